@@ -0,0 +1,766 @@
+pub const NR10_ADDRESS : u16 = 0xFF10;
+pub const NR11_ADDRESS : u16 = 0xFF11;
+pub const NR12_ADDRESS : u16 = 0xFF12;
+pub const NR13_ADDRESS : u16 = 0xFF13;
+pub const NR14_ADDRESS : u16 = 0xFF14;
+pub const NR21_ADDRESS : u16 = 0xFF16;
+pub const NR22_ADDRESS : u16 = 0xFF17;
+pub const NR23_ADDRESS : u16 = 0xFF18;
+pub const NR24_ADDRESS : u16 = 0xFF19;
+pub const NR30_ADDRESS : u16 = 0xFF1A;
+pub const NR31_ADDRESS : u16 = 0xFF1B;
+pub const NR32_ADDRESS : u16 = 0xFF1C;
+pub const NR33_ADDRESS : u16 = 0xFF1D;
+pub const NR34_ADDRESS : u16 = 0xFF1E;
+pub const NR41_ADDRESS : u16 = 0xFF20;
+pub const NR42_ADDRESS : u16 = 0xFF21;
+pub const NR43_ADDRESS : u16 = 0xFF22;
+pub const NR44_ADDRESS : u16 = 0xFF23;
+pub const NR50_ADDRESS : u16 = 0xFF24;
+pub const NR51_ADDRESS : u16 = 0xFF25;
+pub const NR52_ADDRESS : u16 = 0xFF26;
+pub const WAVE_RAM_START : u16 = 0xFF30;
+pub const WAVE_RAM_END : u16 = 0xFF3F;
+
+/// Clock the APU runs at, which stays the same in CGB double speed
+const CLOCK_RATE : u32 = 4_194_304;
+/// Clocks between steps of the 512Hz frame sequencer
+const FRAME_SEQUENCER_PERIOD : u32 = 8192;
+pub const DEFAULT_SAMPLE_RATE : u32 = 48_000;
+/// Names of the channels, in the order their stems are produced
+pub const CHANNEL_NAMES : [&str; 4] = ["square1", "square2", "wave", "noise"];
+
+/// Bits which always read as set in each register from NR10 to 0xFF2F, as most are partly write only
+const READ_MASKS : [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,
+    0x00, 0x00, 0x70,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+const POWER : u8 = 0x80;
+const TRIGGER : u8 = 0x80;
+const LENGTH_ENABLE : u8 = 0x40;
+
+/// Waveforms for each duty setting, played from the top bit down
+const DUTY_PATTERNS : [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+const NOISE_DIVISORS : [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Length counter, which silences a channel when it runs out
+#[derive(Default)]
+struct Length {
+    max : u16,
+    counter : u16,
+    enabled : bool,
+}
+
+impl Length {
+    fn new(max : u16) -> Length {
+        Length { max, ..Length::default() }
+    }
+    fn load(&mut self, value : u8) {
+        self.counter = self.max - value as u16;
+    }
+    /// Returns true when the counter expires, disabling the channel
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+    /// Handle the length bits of an NRx4 write, given the next frame sequencer step.
+    /// Returns true if the channel should be disabled.
+    fn write_control(&mut self, data : u8, frame_step : u8) -> bool {
+        //When the next step doesn't clock lengths, enabling the counter clocks it once straight away
+        let extra_clock = frame_step & 1 == 1;
+        let was_enabled = self.enabled;
+        self.enabled = data & LENGTH_ENABLE != 0;
+
+        let mut expired = false;
+        if extra_clock && !was_enabled && self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            expired = self.counter == 0 && data & TRIGGER == 0;
+        }
+        if data & TRIGGER != 0 && self.counter == 0 {
+            self.counter = if extra_clock && self.enabled { self.max - 1 } else { self.max };
+        }
+        expired
+    }
+}
+
+/// Volume envelope of the square and noise channels
+#[derive(Default)]
+struct Envelope {
+    initial : u8,
+    increase : bool,
+    period : u8,
+    volume : u8,
+    timer : u8,
+}
+
+impl Envelope {
+    fn write(&mut self, data : u8) {
+        self.initial = data >> 4;
+        self.increase = data & 0x08 != 0;
+        self.period = data & 0x07;
+    }
+    /// The DAC is off when the top five bits of NRx2 are all clear
+    fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+/// Frequency sweep of channel 1
+#[derive(Default)]
+struct Sweep {
+    period : u8,
+    negate : bool,
+    shift : u8,
+    timer : u8,
+    shadow : u16,
+    enabled : bool,
+    ///A subtraction has been calculated since the last trigger
+    negated : bool,
+}
+
+impl Sweep {
+    /// Returns true if the write disables the channel, from clearing negate after it's been used
+    fn write(&mut self, data : u8) -> bool {
+        self.period = (data >> 4) & 0x07;
+        self.negate = data & 0x08 != 0;
+        self.shift = data & 0x07;
+        !self.negate && self.negated
+    }
+    /// The next frequency, or None when it overflows
+    fn calculate(&mut self) -> Option<u16> {
+        let delta = self.shadow >> self.shift;
+        let frequency = if self.negate {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        };
+        (frequency <= 0x7FF).then_some(frequency)
+    }
+    /// Returns false if the channel is disabled by the overflow check
+    fn trigger(&mut self, frequency : u16) -> bool {
+        self.shadow = frequency;
+        self.timer = if self.period == 0 { 8 } else { self.period };
+        self.enabled = self.period != 0 || self.shift != 0;
+        self.negated = false;
+        self.shift == 0 || self.calculate().is_some()
+    }
+}
+
+/// Channels 1 and 2
+struct Square {
+    enabled : bool,
+    duty : u8,
+    position : u8,
+    frequency : u16,
+    timer : i32,
+    length : Length,
+    envelope : Envelope,
+    ///Only channel 1 has a sweep unit
+    sweep : Option<Sweep>,
+}
+
+impl Square {
+    fn new(sweep : bool) -> Square {
+        Square {
+            enabled : false,
+            duty : 0,
+            position : 0,
+            frequency : 0,
+            timer : 0,
+            length : Length::new(64),
+            envelope : Envelope::default(),
+            sweep : sweep.then(Sweep::default),
+        }
+    }
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
+    fn tick(&mut self, cycles : u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) & 7;
+        }
+    }
+    fn output(&self) -> u8 {
+        let high = (DUTY_PATTERNS[self.duty as usize] >> (7 - self.position)) & 1 != 0;
+        if self.enabled && high { self.envelope.volume } else { 0 }
+    }
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.envelope.trigger();
+        if let Some(sweep) = &mut self.sweep {
+            if !sweep.trigger(self.frequency) {
+                self.enabled = false;
+            }
+        }
+    }
+    fn clock_sweep(&mut self) {
+        let Some(sweep) = &mut self.sweep else {
+            return;
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        match sweep.calculate() {
+            Some(frequency) if sweep.shift != 0 => {
+                sweep.shadow = frequency;
+                self.frequency = frequency;
+                //The new frequency is checked for overflow again, but not used
+                if sweep.calculate().is_none() {
+                    self.enabled = false;
+                }
+            },
+            Some(_) => (),
+            None => self.enabled = false,
+        }
+    }
+}
+
+/// Channel 3, playing back 32 4-bit samples from wave RAM
+struct Wave {
+    enabled : bool,
+    dac : bool,
+    length : Length,
+    volume_code : u8,
+    frequency : u16,
+    timer : i32,
+    position : u8,
+    sample : u8,
+    ram : [u8; 16],
+}
+
+impl Wave {
+    fn new() -> Wave {
+        Wave {
+            enabled : false,
+            dac : false,
+            length : Length::new(256),
+            volume_code : 0,
+            frequency : 0,
+            timer : 0,
+            position : 0,
+            sample : 0,
+            ram : [0; 16],
+        }
+    }
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
+    fn tick(&mut self, cycles : u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) & 31;
+            let byte = self.ram[self.position as usize / 2];
+            self.sample = if self.position & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+    fn output(&self) -> u8 {
+        //Volume codes are mute, 100%, 50% and 25%
+        let shift = [4, 0, 1, 2][self.volume_code as usize];
+        if self.enabled { self.sample >> shift } else { 0 }
+    }
+    fn trigger(&mut self) {
+        self.enabled = self.dac;
+        self.position = 0;
+        self.timer = self.period();
+    }
+}
+
+/// Channel 4, pseudo-random noise from a linear feedback shift register
+struct Noise {
+    enabled : bool,
+    length : Length,
+    envelope : Envelope,
+    shift : u8,
+    ///7 bit mode, which gives a more metallic tone
+    narrow : bool,
+    divisor_code : u8,
+    lfsr : u16,
+    timer : i32,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            enabled : false,
+            length : Length::new(64),
+            envelope : Envelope::default(),
+            shift : 0,
+            narrow : false,
+            divisor_code : 0,
+            lfsr : 0x7FFF,
+            timer : 0,
+        }
+    }
+    fn period(&self) -> i32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.shift
+    }
+    fn tick(&mut self, cycles : u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | feedback << 14;
+            if self.narrow {
+                self.lfsr = (self.lfsr & !0x40) | feedback << 6;
+            }
+        }
+    }
+    fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 { self.envelope.volume } else { 0 }
+    }
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.lfsr = 0x7FFF;
+        self.timer = self.period();
+        self.envelope.trigger();
+    }
+}
+
+/// Panning and volume applied when mixing channels together
+#[derive(Clone, Copy)]
+struct Mixer {
+    panning : u8,
+    volume : u8,
+    capacitor_charge : f32,
+}
+
+impl Mixer {
+    /// Mix the channels set in `channels` through the high pass filter `capacitors`
+    fn mix(self, outputs : &[Option<f32>; 4], channels : u8, capacitors : &mut [f32; 2]) -> [i16; 2] {
+        let mut mixed = [0_i16; 2];
+        //Left is the upper nibble of NR50 and NR51, right the lower
+        for (side, shift) in [4, 0].into_iter().enumerate() {
+            let sum : f32 = outputs.iter().enumerate()
+                .filter(|(channel, _)| (self.panning >> shift) & channels & (1 << channel) != 0)
+                .filter_map(|(_, output)| *output)
+                .sum();
+            let master = ((self.volume >> shift) & 0x07) as f32 + 1.0;
+            let input = sum / 4.0 * master / 8.0;
+
+            let output = input - capacitors[side];
+            capacitors[side] = input - output * self.capacitor_charge;
+            mixed[side] = (output * i16::MAX as f32).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+        mixed
+    }
+}
+
+/// Each channel on its own, as it would sound with the others muted
+#[derive(Default)]
+struct Stems {
+    samples : [Vec<i16>; 4],
+    capacitors : [[f32; 2]; 4],
+}
+
+/// Audio processing unit, producing interleaved stereo samples at a host sample rate
+pub struct Apu {
+    ///Last value written to each register, for reading back
+    registers : [u8; 0x20],
+    powered : bool,
+
+    square1 : Square,
+    square2 : Square,
+    wave : Wave,
+    noise : Noise,
+
+    frame_timer : u32,
+    ///Next step of the frame sequencer to run
+    frame_step : u8,
+
+    sample_rate : u32,
+    ///Clocks towards the next sample, scaled by the sample rate
+    sample_clock : u32,
+    ///High pass filter state, removing the DC offset of the DACs as the console's capacitors do
+    capacitors : [f32; 2],
+    capacitor_charge : f32,
+    samples : Vec<i16>,
+    stems : Option<Stems>,
+}
+
+impl Apu {
+    pub fn new(sample_rate : u32) -> Apu {
+        let mut apu = Apu {
+            registers : [0; 0x20],
+            powered : false,
+            square1 : Square::new(true),
+            square2 : Square::new(false),
+            wave : Wave::new(),
+            noise : Noise::new(),
+            frame_timer : 0,
+            frame_step : 0,
+            sample_rate,
+            sample_clock : 0,
+            capacitors : [0.0; 2],
+            capacitor_charge : 0.0,
+            samples : Vec::new(),
+            stems : None,
+        };
+        apu.set_sample_rate(sample_rate);
+        apu
+    }
+    pub fn set_sample_rate(&mut self, sample_rate : u32) {
+        self.sample_rate = sample_rate;
+        self.capacitor_charge = 0.999958_f32.powf(CLOCK_RATE as f32 / sample_rate as f32);
+    }
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    /// Take the interleaved left/right samples produced so far
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.samples)
+    }
+    /// Also produce samples of each channel on its own
+    pub fn capture_stems(&mut self, enabled : bool) {
+        self.stems = enabled.then(Stems::default);
+    }
+    /// Take the samples of each channel produced so far, in the order of `CHANNEL_NAMES`
+    pub fn take_stems(&mut self) -> Option<[Vec<i16>; 4]> {
+        self.stems.as_mut().map(|stems| std::mem::take(&mut stems.samples))
+    }
+
+    pub fn read(&self, addr : u16) -> u8 {
+        match addr {
+            WAVE_RAM_START..=WAVE_RAM_END => self.wave.ram[(addr - WAVE_RAM_START) as usize],
+            NR52_ADDRESS => {
+                let channels = [self.square1.enabled, self.square2.enabled, self.wave.enabled, self.noise.enabled];
+                let status = channels.iter().enumerate()
+                    .fold(0, |status, (index, &enabled)| status | (enabled as u8) << index);
+                READ_MASKS[(NR52_ADDRESS - NR10_ADDRESS) as usize] | if self.powered { POWER } else { 0 } | status
+            },
+            NR10_ADDRESS..=0xFF2F => {
+                let index = (addr - NR10_ADDRESS) as usize;
+                self.registers[index] | READ_MASKS[index]
+            },
+            _ => 0xFF,
+        }
+    }
+    pub fn write(&mut self, addr : u16, data : u8) {
+        if let WAVE_RAM_START..=WAVE_RAM_END = addr {
+            self.wave.ram[(addr - WAVE_RAM_START) as usize] = data;
+            return;
+        }
+        if addr == NR52_ADDRESS {
+            match (self.powered, data & POWER != 0) {
+                (true, false) => self.power_off(),
+                (false, true) => self.power_on(),
+                _ => (),
+            }
+            return;
+        }
+        if !self.powered {
+            //While powered off only the DMG's length counters can be written
+            match addr {
+                NR11_ADDRESS => self.square1.length.load(data & 0x3F),
+                NR21_ADDRESS => self.square2.length.load(data & 0x3F),
+                NR31_ADDRESS => self.wave.length.load(data),
+                NR41_ADDRESS => self.noise.length.load(data & 0x3F),
+                _ => (),
+            }
+            return;
+        }
+        self.write_register(addr, data);
+    }
+    fn write_register(&mut self, addr : u16, data : u8) {
+        if let NR10_ADDRESS..=0xFF2F = addr {
+            self.registers[(addr - NR10_ADDRESS) as usize] = data;
+        }
+        let frame_step = self.frame_step;
+        match addr {
+            NR10_ADDRESS => if let Some(sweep) = &mut self.square1.sweep {
+                if sweep.write(data) {
+                    self.square1.enabled = false;
+                }
+            },
+            NR11_ADDRESS => {
+                self.square1.duty = data >> 6;
+                self.square1.length.load(data & 0x3F);
+            },
+            NR12_ADDRESS => {
+                self.square1.envelope.write(data);
+                self.square1.enabled &= self.square1.envelope.dac_enabled();
+            },
+            NR13_ADDRESS => self.square1.frequency = (self.square1.frequency & 0x700) | data as u16,
+            NR14_ADDRESS => {
+                self.square1.frequency = (self.square1.frequency & 0xFF) | ((data & 0x07) as u16) << 8;
+                if self.square1.length.write_control(data, frame_step) {
+                    self.square1.enabled = false;
+                }
+                if data & TRIGGER != 0 {
+                    self.square1.trigger();
+                }
+            },
+            NR21_ADDRESS => {
+                self.square2.duty = data >> 6;
+                self.square2.length.load(data & 0x3F);
+            },
+            NR22_ADDRESS => {
+                self.square2.envelope.write(data);
+                self.square2.enabled &= self.square2.envelope.dac_enabled();
+            },
+            NR23_ADDRESS => self.square2.frequency = (self.square2.frequency & 0x700) | data as u16,
+            NR24_ADDRESS => {
+                self.square2.frequency = (self.square2.frequency & 0xFF) | ((data & 0x07) as u16) << 8;
+                if self.square2.length.write_control(data, frame_step) {
+                    self.square2.enabled = false;
+                }
+                if data & TRIGGER != 0 {
+                    self.square2.trigger();
+                }
+            },
+            NR30_ADDRESS => {
+                self.wave.dac = data & 0x80 != 0;
+                self.wave.enabled &= self.wave.dac;
+            },
+            NR31_ADDRESS => self.wave.length.load(data),
+            NR32_ADDRESS => self.wave.volume_code = (data >> 5) & 0x03,
+            NR33_ADDRESS => self.wave.frequency = (self.wave.frequency & 0x700) | data as u16,
+            NR34_ADDRESS => {
+                self.wave.frequency = (self.wave.frequency & 0xFF) | ((data & 0x07) as u16) << 8;
+                if self.wave.length.write_control(data, frame_step) {
+                    self.wave.enabled = false;
+                }
+                if data & TRIGGER != 0 {
+                    self.wave.trigger();
+                }
+            },
+            NR41_ADDRESS => self.noise.length.load(data & 0x3F),
+            NR42_ADDRESS => {
+                self.noise.envelope.write(data);
+                self.noise.enabled &= self.noise.envelope.dac_enabled();
+            },
+            NR43_ADDRESS => {
+                self.noise.shift = data >> 4;
+                self.noise.narrow = data & 0x08 != 0;
+                self.noise.divisor_code = data & 0x07;
+            },
+            NR44_ADDRESS => {
+                if self.noise.length.write_control(data, frame_step) {
+                    self.noise.enabled = false;
+                }
+                if data & TRIGGER != 0 {
+                    self.noise.trigger();
+                }
+            },
+            _ => (),
+        }
+    }
+    fn power_off(&mut self) {
+        //Every register is cleared, except the DMG's length counters and wave RAM
+        for addr in NR10_ADDRESS..NR52_ADDRESS {
+            if !matches!(addr, NR11_ADDRESS | NR21_ADDRESS | NR31_ADDRESS | NR41_ADDRESS) {
+                self.write_register(addr, 0);
+            }
+        }
+        self.registers.fill(0);
+        self.square1.duty = 0;
+        self.square2.duty = 0;
+        self.square1.enabled = false;
+        self.square2.enabled = false;
+        self.wave.enabled = false;
+        self.noise.enabled = false;
+        self.powered = false;
+    }
+    fn power_on(&mut self) {
+        self.powered = true;
+        self.frame_step = 0;
+        self.frame_timer = 0;
+        self.square1.position = 0;
+        self.square2.position = 0;
+        self.wave.sample = 0;
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        let clock_length = |apu : &mut Apu| {
+            if apu.square1.length.clock() {
+                apu.square1.enabled = false;
+            }
+            if apu.square2.length.clock() {
+                apu.square2.enabled = false;
+            }
+            if apu.wave.length.clock() {
+                apu.wave.enabled = false;
+            }
+            if apu.noise.length.clock() {
+                apu.noise.enabled = false;
+            }
+        };
+        match self.frame_step {
+            0 | 4 => clock_length(self),
+            2 | 6 => {
+                clock_length(self);
+                self.square1.clock_sweep();
+            },
+            7 => {
+                self.square1.envelope.clock();
+                self.square2.envelope.clock();
+                self.noise.envelope.clock();
+            },
+            _ => (),
+        }
+        self.frame_step = (self.frame_step + 1) & 7;
+    }
+
+    /// Advance by `cycles` clocks at the normal speed clock rate
+    pub fn tick(&mut self, cycles : u8) {
+        let cycles = cycles as u32;
+        if self.powered {
+            self.square1.tick(cycles);
+            self.square2.tick(cycles);
+            self.wave.tick(cycles);
+            self.noise.tick(cycles);
+
+            self.frame_timer += cycles;
+            while self.frame_timer >= FRAME_SEQUENCER_PERIOD {
+                self.frame_timer -= FRAME_SEQUENCER_PERIOD;
+                self.step_frame_sequencer();
+            }
+        }
+
+        self.sample_clock += cycles * self.sample_rate;
+        while self.sample_clock >= CLOCK_RATE {
+            self.sample_clock -= CLOCK_RATE;
+            self.output_sample();
+        }
+    }
+    fn output_sample(&mut self) {
+        //Powered off, every DAC is disconnected
+        let outputs = if self.powered { self.dac_outputs() } else { [None; 4] };
+        let mixer = Mixer {
+            panning : self.registers[(NR51_ADDRESS - NR10_ADDRESS) as usize],
+            volume : self.registers[(NR50_ADDRESS - NR10_ADDRESS) as usize],
+            capacitor_charge : self.capacitor_charge,
+        };
+
+        self.samples.extend(mixer.mix(&outputs, 0b1111, &mut self.capacitors));
+        if let Some(stems) = &mut self.stems {
+            for (channel, (samples, capacitors)) in stems.samples.iter_mut().zip(&mut stems.capacitors).enumerate() {
+                samples.extend(mixer.mix(&outputs, 1 << channel, capacitors));
+            }
+        }
+    }
+    /// Each channel's DAC output from -1.0 to 1.0, or None if its DAC is off
+    fn dac_outputs(&self) -> [Option<f32>; 4] {
+        let dac = |enabled : bool, output : u8| enabled.then(|| output as f32 / 7.5 - 1.0);
+        [
+            dac(self.square1.envelope.dac_enabled(), self.square1.output()),
+            dac(self.square2.envelope.dac_enabled(), self.square2.output()),
+            dac(self.wave.dac, self.wave.output()),
+            dac(self.noise.envelope.dac_enabled(), self.noise.output()),
+        ]
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new(DEFAULT_SAMPLE_RATE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_counter_silences_channel() {
+        let mut apu = Apu::default();
+        apu.write(NR52_ADDRESS, POWER);
+        apu.write(NR22_ADDRESS, 0xF0);
+        //Two ticks of length left
+        apu.write(NR21_ADDRESS, 62);
+        apu.write(NR24_ADDRESS, TRIGGER | LENGTH_ENABLE);
+        assert_eq!(apu.read(NR52_ADDRESS), 0xF2);
+
+        //Length clocks on steps 0 and 2
+        for _ in 0..(3 * FRAME_SEQUENCER_PERIOD / 16) {
+            apu.tick(16);
+        }
+        assert_eq!(apu.read(NR52_ADDRESS), 0xF0);
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = Apu::default();
+        apu.write(NR52_ADDRESS, POWER);
+        apu.write(NR50_ADDRESS, 0x77);
+        apu.write(WAVE_RAM_START, 0x12);
+        apu.write(NR52_ADDRESS, 0x00);
+
+        assert_eq!(apu.read(NR50_ADDRESS), 0x00);
+        assert_eq!(apu.read(NR52_ADDRESS), 0x70);
+        assert_eq!(apu.read(WAVE_RAM_START), 0x12);
+        //Writes are ignored until it's powered back on
+        apu.write(NR50_ADDRESS, 0x77);
+        assert_eq!(apu.read(NR50_ADDRESS), 0x00);
+    }
+
+    #[test]
+    fn produces_samples_at_host_rate() {
+        let mut apu = Apu::new(44_100);
+        for _ in 0..CLOCK_RATE / 16 {
+            apu.tick(16);
+        }
+        assert_eq!(apu.take_samples().len(), 2 * 44_100);
+    }
+
+    #[test]
+    fn stems_split_the_mix_by_channel() {
+        let mut apu = Apu::new(44_100);
+        assert!(apu.take_stems().is_none());
+        apu.capture_stems(true);
+        apu.write(NR52_ADDRESS, POWER);
+        apu.write(NR50_ADDRESS, 0x77);
+        apu.write(NR51_ADDRESS, 0xFF);
+        //Only square 2 has its DAC on
+        apu.write(NR22_ADDRESS, 0xF0);
+        apu.write(NR24_ADDRESS, TRIGGER);
+        for _ in 0..CLOCK_RATE / 16 / 10 {
+            apu.tick(16);
+        }
+
+        let samples = apu.take_samples();
+        let stems = apu.take_stems().expect("stems are being captured");
+        assert!(samples.iter().any(|sample| *sample != 0));
+        assert_eq!(stems[1], samples);
+        for channel in [0, 2, 3] {
+            assert_eq!(stems[channel].len(), samples.len());
+            assert!(stems[channel].iter().all(|sample| *sample == 0), "{} is silent", CHANNEL_NAMES[channel]);
+        }
+        assert!(apu.take_stems().expect("still capturing").iter().all(Vec::is_empty));
+    }
+}
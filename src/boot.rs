@@ -0,0 +1,189 @@
+use crate::cpu;
+use crate::memory::Bus;
+use crate::ppu;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Writing to this register unmaps the boot ROM until reset
+pub const BOOT_OFF_ADDRESS : u16 = 0xFF50;
+/// CGB mode select, written by the CGB boot ROM from the header's CGB flag before it unmaps itself
+pub const KEY0_ADDRESS : u16 = 0xFF4C;
+/// KEY0 bit putting the CGB into DMG compatibility mode
+pub const KEY0_DMG_MODE : u8 = 0x04;
+
+/// Size of the DMG, MGB and SGB boot ROMs, which overlay 0x0000..=0x00FF
+pub const DMG_BOOT_SIZE : usize = 0x100;
+/// Size of the CGB boot ROM, which also overlays 0x0200..=0x08FF, leaving the cartridge header visible
+pub const CGB_BOOT_SIZE : usize = 0x900;
+
+const HEADER_START : u16 = 0x0100;
+const HEADER_END : u16 = 0x01FF;
+
+/// Console models, which differ in the state their boot ROM leaves behind
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+    Dmg, Mgb, Sgb, Cgb,
+}
+
+impl Model {
+    pub fn from_name(name : &str) -> Option<Model> {
+        match name.to_ascii_lowercase().as_str() {
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "sgb" => Some(Model::Sgb),
+            "cgb" => Some(Model::Cgb),
+            _ => None,
+        }
+    }
+}
+
+/// Boot ROM overlaying the bottom of the cartridge ROM until it is unmapped
+pub struct BootRom {
+    data : Vec<u8>,
+}
+
+impl BootRom {
+    pub fn from_bytes(data : Vec<u8>) -> io::Result<BootRom> {
+        match data.len() {
+            DMG_BOOT_SIZE | CGB_BOOT_SIZE => Ok(BootRom { data }),
+            length => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("boot ROM is {} bytes, expected {} or {}", length, DMG_BOOT_SIZE, CGB_BOOT_SIZE),
+            )),
+        }
+    }
+    pub fn load(path : &Path) -> io::Result<BootRom> {
+        BootRom::from_bytes(fs::read(path)?)
+    }
+    /// The boot ROM's byte at `addr`, or None where the cartridge shows through
+    pub fn read(&self, addr : u16) -> Option<u8> {
+        match addr {
+            HEADER_START..=HEADER_END => None,
+            _ => self.data.get(addr as usize).copied(),
+        }
+    }
+}
+
+/// I/O register values left by the DMG boot ROM, in the order they're written
+const DMG_IO : [(u16, u8); 35] = [
+    //Sound first, so the rest of its registers aren't ignored while it's powered off
+    (0xFF26, 0xF1),
+    (0xFF00, 0xCF), (0xFF01, 0x00), (0xFF02, 0x7E),
+    (0xFF05, 0x00), (0xFF06, 0x00), (0xFF07, 0xF8), (0xFF0F, 0xE1),
+    (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF13, 0xFF), (0xFF14, 0xBF),
+    (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF18, 0xFF), (0xFF19, 0xBF),
+    (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1D, 0xFF), (0xFF1E, 0xBF),
+    (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00), (0xFF23, 0xBF),
+    (0xFF24, 0x77), (0xFF25, 0xF3),
+    (0xFF40, 0x91), (0xFF42, 0x00), (0xFF43, 0x00), (0xFF45, 0x00),
+    (0xFF47, 0xFC), (0xFF4A, 0x00), (0xFF4B, 0x00),
+];
+
+/// Colours the CGB boot ROM gives DMG cartridges it doesn't recognise, for BGP and for both OBPs
+const COMPATIBILITY_BG : [u16; 4] = [0x7FFF, 0x1BEF, 0x6180, 0x0000];
+const COMPATIBILITY_OBJ : [u16; 4] = [0x7FFF, 0x421F, 0x1CF2, 0x0000];
+
+/// Fill palette RAM through an index and data register pair, from palette 0 up
+fn write_palettes<B : Bus>(memory : &mut B, index_address : u16, palettes : &[[u16; 4]]) {
+    memory.write(index_address, 0x80);
+    for color in palettes.iter().flatten() {
+        for byte in color.to_le_bytes() {
+            memory.write(index_address + 1, byte);
+        }
+    }
+}
+
+/// Put the CPU and I/O registers in the state `model`'s boot ROM hands over to the cartridge with
+pub fn skip_boot<B : Bus>(model : Model, state : &mut cpu::Registers, memory : &mut B) {
+    //The DMG and MGB boot ROMs leave H and C set unless the header checksum is zero
+    let checksum_flags = if memory.read(0x014D) == 0 { 0x80 } else { 0xB0 };
+    let (af, bc, de, hl) = match model {
+        Model::Dmg => (0x0100 | checksum_flags, 0x0013, 0x00D8, 0x014D),
+        Model::Mgb => (0xFF00 | checksum_flags, 0x0013, 0x00D8, 0x014D),
+        Model::Sgb => (0x0100, 0x0014, 0x0000, 0xC060),
+        Model::Cgb => (0x1180, 0x0000, 0xFF56, 0x000D),
+    };
+    state.set_af(af);
+    state.set_bc(bc);
+    state.set_de(de);
+    state.set_hl(hl);
+    state.set_sp(0xFFFE);
+    state.set_pc(0x0100);
+
+    for (addr, value) in DMG_IO {
+        memory.write(addr, value);
+    }
+    match model {
+        Model::Sgb => memory.write(0xFF26, 0xF0),
+        Model::Cgb => {
+            memory.write(0xFF02, 0x7F);
+            //The header's CGB flag picks between CGB mode and DMG compatibility mode
+            let cgb_flag = memory.read(0x0143);
+            if cgb_flag & 0x80 != 0 {
+                write_palettes(memory, ppu::BCPS_ADDRESS, &[[0x7FFF; 4]; 8]);
+                memory.write(KEY0_ADDRESS, cgb_flag);
+            } else {
+                write_palettes(memory, ppu::BCPS_ADDRESS, &[COMPATIBILITY_BG]);
+                write_palettes(memory, ppu::OCPS_ADDRESS, &[COMPATIBILITY_OBJ; 2]);
+                memory.write(KEY0_ADDRESS, KEY0_DMG_MODE);
+            }
+        },
+        Model::Dmg | Model::Mgb => (),
+    }
+    memory.write(BOOT_OFF_ADDRESS, 0x01);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{Memory, SVBK_ADDRESS};
+
+    #[test]
+    fn overlay_unmaps_on_write() {
+        let mut memory = Memory::with_rom(vec![0x11; 0x8000]);
+        memory.boot_rom = Some(BootRom::from_bytes(vec![0x22; CGB_BOOT_SIZE]).unwrap());
+
+        assert_eq!(memory.read(0x0000), 0x22);
+        //The header always comes from the cartridge
+        assert_eq!(memory.read(0x0100), 0x11);
+        assert_eq!(memory.read(0x08FF), 0x22);
+        assert_eq!(memory.read(0x0900), 0x11);
+
+        memory.write(BOOT_OFF_ADDRESS, 0x01);
+        assert_eq!(memory.read(0x0000), 0x11);
+    }
+
+    #[test]
+    fn header_selects_cgb_mode() {
+        let boot = |cgb_flag : u8| {
+            let mut rom = vec![0; 0x8000];
+            rom[0x0143] = cgb_flag;
+            let mut memory = Memory::with_rom(rom);
+            memory.set_cgb(true);
+            skip_boot(Model::Cgb, &mut cpu::Registers::default(), &mut memory);
+            memory
+        };
+
+        let mut memory = boot(0x80);
+        assert!(memory.cgb_mode());
+        memory.write(0xD000, 0x01);
+        memory.write(SVBK_ADDRESS, 2);
+        assert_eq!(memory.read(SVBK_ADDRESS), 0xFA);
+        assert_eq!(memory.read(0xD000), 0x00);
+        memory.write(0xD000, 0x02);
+        //Bank 0 selects bank 1
+        memory.write(SVBK_ADDRESS, 0);
+        assert_eq!(memory.read(0xD000), 0x01);
+        assert_eq!(memory.read(0xF000), 0x01);
+
+        let mut memory = boot(0x00);
+        assert!(!memory.cgb_mode());
+        assert_eq!(memory.read(SVBK_ADDRESS), 0xFF);
+        assert_eq!(memory.read(ppu::VBK_ADDRESS), 0xFF);
+        //KEY0 is locked once the boot ROM is done
+        memory.write(KEY0_ADDRESS, 0x80);
+        assert!(!memory.cgb_mode());
+    }
+}
@@ -0,0 +1,354 @@
+use std::fmt::{self, Display};
+use std::path::Path;
+
+use crate::bitmath::join_u8;
+
+const HEADER_END : usize = 0x0150;
+
+const TITLE_START : usize = 0x0134;
+const NEW_LICENSEE : usize = 0x0144;
+const CGB_FLAG : usize = 0x0143;
+const SGB_FLAG : usize = 0x0146;
+const CARTRIDGE_TYPE : usize = 0x0147;
+const ROM_SIZE : usize = 0x0148;
+const RAM_SIZE : usize = 0x0149;
+const OLD_LICENSEE : usize = 0x014B;
+const VERSION : usize = 0x014C;
+const HEADER_CHECKSUM : usize = 0x014D;
+const GLOBAL_CHECKSUM : usize = 0x014E;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(std::io::Error),
+    ///Image too short to contain a header
+    Truncated{length : usize},
+    UnknownCartridgeType(u8),
+    UnknownRomSize(u8),
+    UnknownRamSize(u8),
+    ///Image length disagrees with the header's ROM size. Only an error when the image is short.
+    SizeMismatch{expected : usize, actual : usize},
+    HeaderChecksum{expected : u8, actual : u8},
+    ///Never checked by hardware, so only ever a warning
+    GlobalChecksum{expected : u16, actual : u16},
+    UnsupportedMapper(MapperKind),
+}
+
+impl Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::Io(err) =>
+                write!(f, "could not read ROM: {}", err),
+            CartridgeError::Truncated{length} =>
+                write!(f, "ROM is {} bytes, too short to hold a header", length),
+            CartridgeError::UnknownCartridgeType(code) =>
+                write!(f, "unknown cartridge type ${:02X}", code),
+            CartridgeError::UnknownRomSize(code) =>
+                write!(f, "unknown ROM size code ${:02X}", code),
+            CartridgeError::UnknownRamSize(code) =>
+                write!(f, "unknown RAM size code ${:02X}", code),
+            CartridgeError::SizeMismatch{expected, actual} =>
+                write!(f, "header declares {} bytes of ROM but the image is {} bytes", expected, actual),
+            CartridgeError::HeaderChecksum{expected, actual} =>
+                write!(f, "header checksum is ${:02X} but the header sums to ${:02X}", expected, actual),
+            CartridgeError::GlobalChecksum{expected, actual} =>
+                write!(f, "global checksum is ${:04X} but the ROM sums to ${:04X}", expected, actual),
+            CartridgeError::UnsupportedMapper(kind) =>
+                write!(f, "{:?} cartridges are not supported", kind),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CartridgeError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CartridgeError {
+    fn from(err : std::io::Error) -> Self {
+        CartridgeError::Io(err)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CgbSupport {
+    ///Plain DMG cartridge
+    None,
+    ///Works on DMG, with CGB enhancements
+    Enhanced,
+    ///Requires a CGB
+    Only,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapperKind {
+    None, Mbc1, Mbc2, Mbc3, Mbc5, Mbc6, Mbc7, Mmm01, PocketCamera, Tama5, HuC1, HuC3,
+}
+
+/// Hardware on the cartridge, as declared by the cartridge type byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CartridgeType {
+    pub code : u8,
+    pub mapper : MapperKind,
+    pub ram : bool,
+    pub battery : bool,
+    pub timer : bool,
+    pub rumble : bool,
+}
+
+impl CartridgeType {
+    pub fn from_code(code : u8) -> Option<CartridgeType> {
+        let (mapper, ram, battery, timer, rumble) = match code {
+            0x00 => (MapperKind::None, false, false, false, false),
+            0x01 => (MapperKind::Mbc1, false, false, false, false),
+            0x02 => (MapperKind::Mbc1, true, false, false, false),
+            0x03 => (MapperKind::Mbc1, true, true, false, false),
+            //MBC2 RAM is built into the mapper
+            0x05 => (MapperKind::Mbc2, true, false, false, false),
+            0x06 => (MapperKind::Mbc2, true, true, false, false),
+            0x08 => (MapperKind::None, true, false, false, false),
+            0x09 => (MapperKind::None, true, true, false, false),
+            0x0B => (MapperKind::Mmm01, false, false, false, false),
+            0x0C => (MapperKind::Mmm01, true, false, false, false),
+            0x0D => (MapperKind::Mmm01, true, true, false, false),
+            0x0F => (MapperKind::Mbc3, false, true, true, false),
+            0x10 => (MapperKind::Mbc3, true, true, true, false),
+            0x11 => (MapperKind::Mbc3, false, false, false, false),
+            0x12 => (MapperKind::Mbc3, true, false, false, false),
+            0x13 => (MapperKind::Mbc3, true, true, false, false),
+            0x19 => (MapperKind::Mbc5, false, false, false, false),
+            0x1A => (MapperKind::Mbc5, true, false, false, false),
+            0x1B => (MapperKind::Mbc5, true, true, false, false),
+            0x1C => (MapperKind::Mbc5, false, false, false, true),
+            0x1D => (MapperKind::Mbc5, true, false, false, true),
+            0x1E => (MapperKind::Mbc5, true, true, false, true),
+            0x20 => (MapperKind::Mbc6, true, true, false, false),
+            0x22 => (MapperKind::Mbc7, true, true, false, true),
+            0xFC => (MapperKind::PocketCamera, true, true, false, false),
+            0xFD => (MapperKind::Tama5, true, true, false, false),
+            0xFE => (MapperKind::HuC3, true, true, true, false),
+            0xFF => (MapperKind::HuC1, true, true, false, false),
+            _ => return None,
+        };
+        Some(CartridgeType { code, mapper, ram, battery, timer, rumble })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Licensee {
+    Old(u8),
+    ///Two ASCII characters, used when the old licensee code is $33
+    New([u8; 2]),
+}
+
+impl Display for Licensee {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Licensee::Old(code) => write!(f, "${:02X}", code),
+            Licensee::New(code) => write!(f, "\"{}{}\"", code[0] as char, code[1] as char),
+        }
+    }
+}
+
+/// The cartridge header at 0x0100..=0x014F
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub title : String,
+    pub cgb : CgbSupport,
+    pub sgb : bool,
+    pub cartridge_type : CartridgeType,
+    ///In bytes
+    pub rom_size : usize,
+    ///In bytes, excluding RAM built into the mapper
+    pub ram_size : usize,
+    pub licensee : Licensee,
+    pub version : u8,
+    pub header_checksum : u8,
+    pub global_checksum : u16,
+}
+
+impl Header {
+    /// Parse the header of `rom` without checking it against the rest of the image
+    pub fn parse(rom : &[u8]) -> Result<Header, CartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::Truncated{ length : rom.len() });
+        }
+
+        let cgb = match rom[CGB_FLAG] {
+            0xC0 => CgbSupport::Only,
+            flag if flag & 0x80 != 0 => CgbSupport::Enhanced,
+            _ => CgbSupport::None,
+        };
+        //CGB cartridges use the last title byte for the CGB flag
+        let title_end = if cgb == CgbSupport::None {0x0144} else {0x0143};
+        let title = rom[TITLE_START..title_end].iter()
+            .take_while(|byte| **byte != 0)
+            .map(|byte| if byte.is_ascii_graphic() || *byte == b' ' {*byte as char} else {'?'})
+            .collect();
+
+        let cartridge_type = CartridgeType::from_code(rom[CARTRIDGE_TYPE])
+            .ok_or(CartridgeError::UnknownCartridgeType(rom[CARTRIDGE_TYPE]))?;
+
+        let rom_size = match rom[ROM_SIZE] {
+            code @ 0x00..=0x08 => 0x8000 << code,
+            code => return Err(CartridgeError::UnknownRomSize(code)),
+        };
+        let ram_size = match rom[RAM_SIZE] {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            code => return Err(CartridgeError::UnknownRamSize(code)),
+        };
+
+        let licensee = match rom[OLD_LICENSEE] {
+            0x33 => Licensee::New([rom[NEW_LICENSEE], rom[NEW_LICENSEE + 1]]),
+            code => Licensee::Old(code),
+        };
+
+        Ok(Header {
+            title,
+            cgb,
+            //The SGB flag only counts alongside the new licensee code
+            sgb : rom[SGB_FLAG] == 0x03 && rom[OLD_LICENSEE] == 0x33,
+            cartridge_type,
+            rom_size,
+            ram_size,
+            licensee,
+            version : rom[VERSION],
+            header_checksum : rom[HEADER_CHECKSUM],
+            global_checksum : join_u8(rom[GLOBAL_CHECKSUM + 1], rom[GLOBAL_CHECKSUM]),
+        })
+    }
+}
+
+impl Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Title: {}", self.title)?;
+        writeln!(f, "Type: ${:02X} {:?}{}{}{}{}",
+            self.cartridge_type.code,
+            self.cartridge_type.mapper,
+            if self.cartridge_type.ram {" +RAM"} else {""},
+            if self.cartridge_type.battery {" +BATTERY"} else {""},
+            if self.cartridge_type.timer {" +TIMER"} else {""},
+            if self.cartridge_type.rumble {" +RUMBLE"} else {""},
+        )?;
+        writeln!(f, "ROM: {}KiB RAM: {}KiB", self.rom_size / 1024, self.ram_size / 1024)?;
+        writeln!(f, "CGB: {:?} SGB: {}", self.cgb, self.sgb)?;
+        write!(f, "Licensee: {} Version: {}", self.licensee, self.version)
+    }
+}
+
+/// Checksum over 0x0134..=0x014C, as verified by the boot ROM
+pub fn header_checksum(rom : &[u8]) -> u8 {
+    rom[TITLE_START..HEADER_CHECKSUM].iter()
+        .fold(0_u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1))
+}
+
+/// Sum of every byte of the image except the global checksum itself
+pub fn global_checksum(rom : &[u8]) -> u16 {
+    rom.iter().enumerate()
+        .filter(|(addr, _)| *addr != GLOBAL_CHECKSUM && *addr != GLOBAL_CHECKSUM + 1)
+        .fold(0_u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16))
+}
+
+pub struct Cartridge {
+    pub header : Header,
+    pub rom : Vec<u8>,
+    ///Problems with the image which hardware doesn't care about
+    pub warnings : Vec<CartridgeError>,
+}
+
+impl Cartridge {
+    pub fn load(path : &Path) -> Result<Cartridge, CartridgeError> {
+        Cartridge::from_bytes(std::fs::read(path)?)
+    }
+    /// Parse and validate a ROM image. Overdumped images are cut down to the header's ROM size.
+    pub fn from_bytes(mut rom : Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        let header = Header::parse(&rom)?;
+        let mut warnings = Vec::new();
+
+        if rom.len() < header.rom_size {
+            return Err(CartridgeError::SizeMismatch{ expected : header.rom_size, actual : rom.len() });
+        }
+        if rom.len() > header.rom_size {
+            warnings.push(CartridgeError::SizeMismatch{ expected : header.rom_size, actual : rom.len() });
+            rom.truncate(header.rom_size);
+        }
+
+        //The boot ROM locks up on a bad header checksum, so nothing would run anyway
+        let actual = header_checksum(&rom);
+        if actual != header.header_checksum {
+            return Err(CartridgeError::HeaderChecksum{ expected : header.header_checksum, actual });
+        }
+        let actual = global_checksum(&rom);
+        if actual != header.global_checksum {
+            warnings.push(CartridgeError::GlobalChecksum{ expected : header.global_checksum, actual });
+        }
+
+        Ok(Cartridge { header, rom, warnings })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 32KiB ROM-only image with valid checksums
+    fn valid_rom() -> Vec<u8> {
+        let mut rom = vec![0_u8; 0x8000];
+        rom[TITLE_START..TITLE_START + 4].copy_from_slice(b"TEST");
+        rom[OLD_LICENSEE] = 0x01;
+        rom[HEADER_CHECKSUM] = header_checksum(&rom);
+        let global = global_checksum(&rom);
+        rom[GLOBAL_CHECKSUM] = (global >> 8) as u8;
+        rom[GLOBAL_CHECKSUM + 1] = global as u8;
+        rom
+    }
+
+    #[test]
+    fn parses_valid_header() {
+        let cartridge = Cartridge::from_bytes(valid_rom()).expect("valid image");
+
+        assert_eq!(cartridge.header.title, "TEST");
+        assert_eq!(cartridge.header.cgb, CgbSupport::None);
+        assert_eq!(cartridge.header.cartridge_type.mapper, MapperKind::None);
+        assert_eq!(cartridge.header.rom_size, 0x8000);
+        assert_eq!(cartridge.header.licensee, Licensee::Old(0x01));
+    }
+
+    #[test]
+    fn rejects_bad_images() {
+        assert!(matches!(Cartridge::from_bytes(vec![0; 0x100]), Err(CartridgeError::Truncated{..})));
+
+        let mut rom = valid_rom();
+        rom.truncate(0x4000);
+        assert!(matches!(Cartridge::from_bytes(rom), Err(CartridgeError::SizeMismatch{..})));
+
+        let mut rom = valid_rom();
+        rom[VERSION] = 1;
+        assert!(matches!(Cartridge::from_bytes(rom), Err(CartridgeError::HeaderChecksum{..})));
+    }
+
+    #[test]
+    fn warns_about_what_hardware_ignores() {
+        let mut rom = valid_rom();
+        rom[0x4000] = 1;
+        let cartridge = Cartridge::from_bytes(rom).expect("bad global checksum still loads");
+        assert!(matches!(cartridge.warnings[..], [CartridgeError::GlobalChecksum{..}]));
+
+        //Overdumps are cut down to the declared size
+        let mut rom = valid_rom();
+        rom.resize(0x10000, 0xFF);
+        let cartridge = Cartridge::from_bytes(rom).expect("overdump still loads");
+        assert_eq!(cartridge.rom.len(), 0x8000);
+        assert!(matches!(cartridge.warnings[..], [CartridgeError::SizeMismatch{expected : 0x8000, actual : 0x10000}]));
+
+        assert!(Cartridge::from_bytes(valid_rom()).expect("valid image").warnings.is_empty());
+    }
+}
@@ -1,6 +1,10 @@
 
 use std::fmt::Display;
 
+use crate::instructions::Instruction;
+use crate::interrupts::{self, Interrupt, InterruptController};
+use crate::memory::Bus;
+
 #[derive(Debug)]
 pub enum Register8 {
     A, B, C, D, E, H, L,
@@ -33,6 +37,7 @@ impl Display for Register16 {
         }
     }
 }
+#[derive(Clone, Copy)]
 pub enum Flag {
     Zero = 7, Negative = 6, HalfCarry = 5, Carry = 4,
     
@@ -53,6 +58,7 @@ impl Display for Flag {
         }
     }
 }
+#[derive(Default)]
 pub struct Registers {
     pub a : u8,
     pub b : u8,
@@ -63,7 +69,154 @@ pub struct Registers {
     pub l : u8,
     pub flags : u8,
     pub sp : u16,
-    pub pc : u16
+    pub pc : u16,
+
+    ///Interrupt master enable
+    pub ime : bool,
+    ///Set by EI, which only enables interrupts after the following instruction
+    pub ime_scheduled : bool,
+
+    pub mode : Mode,
+    ///Set when HALT was executed with IME off and an interrupt already pending.
+    ///The next opcode fetch fails to increment PC, so its first byte is read twice.
+    pub halt_bug : bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Running,
+    ///Idle until any enabled interrupt is requested
+    Halted,
+    ///Idle with the clock stopped until a joypad line goes low
+    Stopped,
+    ///Hung by an illegal opcode, only a reset recovers
+    Locked,
+}
+
+pub const KEY1_ADDRESS : u16 = 0xFF4D;
+
+/// CGB KEY1 register, a speed switch armed by software and performed by STOP
+#[derive(Default)]
+pub struct SpeedSwitch {
+    ///KEY1 only exists on CGB hardware
+    pub cgb : bool,
+    pub armed : bool,
+    pub double_speed : bool,
+}
+
+impl SpeedSwitch {
+    pub fn read(&self) -> u8 {
+        if !self.cgb {
+            return 0xFF;
+        }
+        (self.double_speed as u8) << 7 | 0b0111_1110 | self.armed as u8
+    }
+    pub fn write(&mut self, value : u8) {
+        if self.cgb {
+            self.armed = value & 0x01 != 0;
+        }
+    }
+    /// Called by STOP. Returns whether a speed switch happened, in which case the CPU does not stop.
+    pub fn switch(&mut self) -> bool {
+        if !(self.cgb && self.armed) {
+            return false;
+        }
+        self.armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+}
+
+/// The CPU's side of the bus. Every access and internal delay takes one machine cycle,
+/// which is ticked through the rest of the bus before the access lands.
+pub struct CpuBus<'a, B : Bus> {
+    bus : &'a mut B,
+    ///Clock cycles taken so far
+    cycles : u32,
+}
+
+impl<'a, B : Bus> CpuBus<'a, B> {
+    pub fn new(bus : &'a mut B) -> Self {
+        CpuBus { bus, cycles : 0 }
+    }
+    pub fn read(&mut self, addr : u16) -> u8 {
+        self.idle();
+        self.bus.read(addr)
+    }
+    pub fn write(&mut self, addr : u16, data : u8) {
+        self.idle();
+        self.bus.write(addr, data);
+    }
+    /// A machine cycle spent inside the CPU, with nothing on the bus
+    pub fn idle(&mut self) {
+        self.cycles += 4 + self.bus.tick(4);
+    }
+    pub fn cycles(&self) -> u32 {
+        self.cycles
+    }
+    pub fn interrupts(&self) -> &InterruptController {
+        self.bus.interrupts()
+    }
+    pub fn interrupts_mut(&mut self) -> &mut InterruptController {
+        self.bus.interrupts_mut()
+    }
+    pub fn stop(&mut self) -> bool {
+        self.bus.stop()
+    }
+}
+
+/// Run a single instruction, dispatching a pending interrupt first if IME allows it.
+/// While halted or stopped, idles for one machine cycle instead.
+/// The bus is ticked along with each machine cycle, and the number of cycles taken is returned.
+pub fn step<B : Bus>(state : &mut Registers, memory : &mut B) -> u32 {
+    let mut memory = CpuBus::new(memory);
+    step_cpu(state, &mut memory);
+    memory.cycles()
+}
+
+fn step_cpu<B : Bus>(state : &mut Registers, memory : &mut CpuBus<B>) {
+    match state.mode {
+        Mode::Running => (),
+        Mode::Locked => return memory.idle(),
+        Mode::Halted => {
+            memory.idle();
+            //Any enabled interrupt ends HALT, even if IME keeps it from being serviced
+            if memory.interrupts().pending() == 0 {
+                return;
+            }
+            state.mode = Mode::Running;
+        },
+        Mode::Stopped => {
+            //Pressing a button on a selected matrix requests the joypad interrupt, which is what wakes STOP
+            if memory.interrupts().requested() & Interrupt::Joypad.mask() == 0 {
+                return memory.idle();
+            }
+            state.mode = Mode::Running;
+        },
+    };
+
+    if interrupts::service(state, memory) {
+        return;
+    }
+
+    //The opcode alone decides the length, the operands are then fetched a byte per cycle
+    let pc = state.pc();
+    let mut bytes = [memory.read(pc), 0, 0];
+    let size = Instruction::from_bytes(0, &bytes).map_or(1, |instruction| instruction.size);
+    for (offset, byte) in bytes.iter_mut().enumerate().take(size as usize).skip(1) {
+        //With the HALT bug PC doesn't move past the opcode, so it's read twice
+        let offset = if state.halt_bug { offset - 1 } else { offset };
+        *byte = memory.read(pc.wrapping_add(offset as u16));
+    }
+    if state.halt_bug {
+        state.halt_bug = false;
+        //Back up PC so the instruction ends one byte short of where it normally would
+        state.set_pc(pc.wrapping_sub(1));
+    }
+    let instruction = Instruction::from_bytes(0, &bytes).expect("three bytes always decode to an instruction");
+
+    instruction.execute(state, memory);
 }
 
 impl Registers {
@@ -71,7 +224,8 @@ impl Registers {
         Registers::get_u8s_into_u16(self.a, self.flags)
     }
     pub fn set_af(&mut self, value : u16) {
-        Registers::set_u16_into_u8s(value, &mut self.a, &mut self.flags);
+        //The low nibble of F is not backed by any flag and always reads as zero
+        Registers::set_u16_into_u8s(value & 0xFFF0, &mut self.a, &mut self.flags);
     }
     pub fn bc(&self) -> u16 {
         Registers::get_u8s_into_u16(self.b, self.c)
@@ -107,10 +261,18 @@ impl Registers {
         match f {
             Flag::Zero | Flag::Carry | Flag::HalfCarry | Flag::Negative =>
                 //Test the bit as determined by the flag index
-                self.flags & (f as u8) != 0,
+                self.flags & (1 << f as u8) != 0,
             Flag::NotZero | Flag::NotCarry | Flag::NotHalfCarry | Flag::NotNegative =>
                 //Inverted constants are four less than their positive counterparts
-                self.flags & (f as u8 + 4) == 0
+                self.flags & (1 << (f as u8 + 4)) == 0
+        }
+    }
+    ///Set or reset a flag according to `value`
+    pub fn assign_flag(&mut self, f : Flag, value : bool) {
+        if value {
+            self.set_flag(f);
+        } else {
+            self.reset_flag(f);
         }
     }
     pub fn set_flag(&mut self, f : Flag) {
@@ -175,7 +337,7 @@ impl Registers {
             Register8::L => self.l,
         }
     }
-    pub fn get_u8_register_mut<'a>(&'a mut self, r : &Register8) -> &'a mut u8 {
+    pub fn get_u8_register_mut(&mut self, r : &Register8) -> &mut u8 {
         match r {
             Register8::A => &mut self.a,
             Register8::B => &mut self.b,
@@ -217,20 +379,104 @@ impl Registers {
     }
 }
 
-impl Default for Registers {
-    fn default() -> Self {
-        return Registers { a: 0, b: 0, c: 0, d: 0, e: 0, h: 0, l: 0, flags: 0, sp: 0, pc: 0 }
-    }
-}
-
 impl Display for Registers {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "A: {:02X} F: {:02X}, AF: {:04X}", self.a, self.flags, self.af())?;
         writeln!(f, "B: {:02X} C: {:02X}, BC: {:04X}", self.b, self.c, self.bc())?;
         writeln!(f, "H: {:02X} L: {:02X}, HL: {:04X}", self.h, self.l, self.hl())?;
         writeln!(f, "E: {:02X}", self.e)?;
-        write!(f, "SP: {:04X}  PC : {:04X}", self.sp, self.pc)?;
+        writeln!(f, "SP: {:04X}  PC : {:04X}", self.sp, self.pc)?;
+        write!(f, "IME: {}", self.ime)?;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::{IE_ADDRESS, IF_ADDRESS};
+    use crate::joypad::{Buttons, P1_ADDRESS};
+    use crate::memory::Memory;
+
+    fn setup(program : &[u8]) -> (Registers, Memory) {
+        let mut memory = Memory::default();
+        for (offset, byte) in program.iter().enumerate() {
+            memory.write(0xC000 + offset as u16, *byte);
+        }
+        let state = Registers { pc : 0xC000, sp : 0xD000, ..Default::default() };
+        (state, memory)
+    }
+
+    fn cpu_steps(state : &mut Registers, memory : &mut Memory, count : usize) {
+        for _ in 0..count {
+            step(state, memory);
+        }
+    }
+
+    fn request(memory : &mut Memory, interrupt : Interrupt) {
+        memory.write(IE_ADDRESS, interrupt.mask());
+        memory.write(IF_ADDRESS, interrupt.mask());
+    }
+
+    #[test]
+    fn halt_idles_until_an_interrupt_is_pending() {
+        //HALT, INC A
+        let (mut state, mut memory) = setup(&[0x76, 0x3C]);
+        cpu_steps(&mut state, &mut memory, 1);
+        assert_eq!(state.mode, Mode::Halted);
+        //Requested but not enabled doesn't count
+        memory.write(IF_ADDRESS, Interrupt::Timer.mask());
+        for _ in 0..10 {
+            assert_eq!(step(&mut state, &mut memory), 4);
+        }
+        assert_eq!((state.mode, state.pc()), (Mode::Halted, 0xC001));
+
+        //With IME off, waking carries on from after the HALT
+        memory.write(IE_ADDRESS, Interrupt::Timer.mask());
+        cpu_steps(&mut state, &mut memory, 1);
+        assert_eq!((state.mode, state.pc(), state.a), (Mode::Running, 0xC002, 1));
+    }
+
+    #[test]
+    fn halt_bug_runs_the_next_instruction_twice() {
+        //HALT, INC A with IME off and an interrupt already pending
+        let (mut state, mut memory) = setup(&[0x76, 0x3C]);
+        request(&mut memory, Interrupt::Timer);
+        cpu_steps(&mut state, &mut memory, 1);
+        assert_eq!(state.mode, Mode::Running);
+        cpu_steps(&mut state, &mut memory, 2);
+        assert_eq!((state.a, state.pc()), (2, 0xC002));
+    }
+
+    #[test]
+    fn ei_halt_returns_to_the_halt() {
+        //EI, HALT, INC A with an interrupt already pending
+        let (mut state, mut memory) = setup(&[0xFB, 0x76, 0x3C]);
+        request(&mut memory, Interrupt::VBlank);
+        cpu_steps(&mut state, &mut memory, 2);
+        assert!(state.ime);
+        cpu_steps(&mut state, &mut memory, 1);
+        assert_eq!(state.pc(), Interrupt::VBlank.vector());
+        assert_eq!(memory.read_u16(state.sp()), 0xC001);
+        assert!(!state.halt_bug);
+        assert_eq!(state.a, 0);
+    }
+
+    #[test]
+    fn stop_wakes_on_a_joypad_press() {
+        //STOP, INC A
+        let (mut state, mut memory) = setup(&[0x10, 0x00, 0x3C]);
+        memory.write(P1_ADDRESS, 0x20);
+        cpu_steps(&mut state, &mut memory, 1);
+        assert_eq!(state.mode, Mode::Stopped);
+        //Other interrupts don't wake it
+        request(&mut memory, Interrupt::Timer);
+        cpu_steps(&mut state, &mut memory, 10);
+        assert_eq!((state.mode, state.pc()), (Mode::Stopped, 0xC002));
+
+        memory.joypad.set_buttons(Buttons::RIGHT, &mut memory.interrupts);
+        cpu_steps(&mut state, &mut memory, 1);
+        assert_eq!((state.mode, state.a), (Mode::Running, 1));
+    }
+}
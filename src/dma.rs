@@ -0,0 +1,260 @@
+pub const DMA_ADDRESS : u16 = 0xFF46;
+pub const HDMA1_ADDRESS : u16 = 0xFF51;
+pub const HDMA2_ADDRESS : u16 = 0xFF52;
+pub const HDMA3_ADDRESS : u16 = 0xFF53;
+pub const HDMA4_ADDRESS : u16 = 0xFF54;
+pub const HDMA5_ADDRESS : u16 = 0xFF55;
+
+/// Bytes VRAM DMA copies at a time
+pub const VRAM_DMA_BLOCK : u16 = 0x10;
+
+/// Bytes copied into OAM, one per M-cycle
+const TRANSFER_LENGTH : u8 = 0xA0;
+/// M-cycles between writing 0xFF46 and the first byte being copied
+const START_DELAY : u8 = 1;
+
+/// Copies 160 bytes from `XX00` into OAM after a write of `XX` to 0xFF46
+#[derive(Default)]
+pub struct OamDma {
+    register : u8,
+    ///Source of a transfer that has been requested but not started, with the M-cycles until it does
+    pending : Option<(u16, u8)>,
+    ///Source of the transfer in progress and the next byte to copy
+    active : Option<(u16, u8)>,
+}
+
+impl OamDma {
+    pub fn read(&self) -> u8 {
+        self.register
+    }
+    pub fn write(&mut self, data : u8) {
+        self.register = data;
+        //Sources past WRAM read through echo RAM rather than OAM and I/O
+        let source = if data >= 0xFE { (data as u16 - 0x20) << 8 } else { (data as u16) << 8 };
+        //A running transfer carries on until the new one takes over
+        self.pending = Some((source, START_DELAY));
+    }
+    /// Whether the CPU is locked off the bus by a transfer
+    pub fn active(&self) -> bool {
+        self.active.is_some()
+    }
+    /// Advance by one M-cycle. Returns the address to copy from and the OAM index to copy to, if a byte moves.
+    pub fn step(&mut self) -> Option<(u16, u8)> {
+        let copy = self.active.map(|(source, index)| (source + index as u16, index));
+        self.active = self.active
+            .map(|(source, index)| (source, index + 1))
+            .filter(|&(_, index)| index < TRANSFER_LENGTH);
+
+        if let Some((source, delay)) = self.pending {
+            self.pending = if delay > 1 {
+                Some((source, delay - 1))
+            } else {
+                self.active = Some((source, 0));
+                None
+            };
+        }
+        copy
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum VramDmaMode {
+    #[default]
+    Idle,
+    ///Copies everything at once, with the CPU halted until it's done
+    General,
+    ///Copies a block at the start of each HBlank
+    HBlank,
+}
+
+/// CGB DMA from ROM or RAM into VRAM, set up through HDMA1-5
+#[derive(Default)]
+pub struct VramDma {
+    ///Only CGB mode has VRAM DMA
+    pub cgb : bool,
+    source : u16,
+    ///Offset into VRAM
+    destination : u16,
+    ///Blocks left to copy, which reads back through HDMA5
+    blocks : u8,
+    mode : VramDmaMode,
+    ///An HBlank started which hasn't had its block copied yet
+    hblank_pending : bool,
+}
+
+impl VramDma {
+    pub fn read(&self, addr : u16) -> u8 {
+        match addr {
+            //Bit 7 is clear while an HBlank DMA is running, the rest is the block count less one
+            HDMA5_ADDRESS if self.cgb => {
+                let length = self.blocks.wrapping_sub(1) & 0x7F;
+                if self.mode == VramDmaMode::HBlank { length } else { 0x80 | length }
+            },
+            //The address registers are write only
+            _ => 0xFF,
+        }
+    }
+    pub fn write(&mut self, addr : u16, data : u8) {
+        if !self.cgb {
+            return;
+        }
+        match addr {
+            HDMA1_ADDRESS => self.source = (self.source & 0x00FF) | (data as u16) << 8,
+            HDMA2_ADDRESS => self.source = (self.source & 0xFF00) | (data & 0xF0) as u16,
+            HDMA3_ADDRESS => self.destination = (self.destination & 0x00FF) | ((data & 0x1F) as u16) << 8,
+            HDMA4_ADDRESS => self.destination = (self.destination & 0xFF00) | (data & 0xF0) as u16,
+            HDMA5_ADDRESS => {
+                //Clearing bit 7 during an HBlank DMA stops it rather than starting a general one
+                if self.mode == VramDmaMode::HBlank && data & 0x80 == 0 {
+                    self.mode = VramDmaMode::Idle;
+                    return;
+                }
+                self.blocks = (data & 0x7F) + 1;
+                self.mode = if data & 0x80 != 0 { VramDmaMode::HBlank } else { VramDmaMode::General };
+                self.hblank_pending = false;
+            },
+            _ => (),
+        }
+    }
+    /// Called as the PPU enters HBlank, or when HBlank DMA starts with the PPU already there
+    pub fn hblank(&mut self) {
+        if self.mode == VramDmaMode::HBlank {
+            self.hblank_pending = true;
+        }
+    }
+    /// The source address and VRAM offset of the next block to copy, if one is due now
+    pub fn next_block(&mut self) -> Option<(u16, u16)> {
+        let due = match self.mode {
+            VramDmaMode::Idle => false,
+            VramDmaMode::General => true,
+            VramDmaMode::HBlank => std::mem::take(&mut self.hblank_pending),
+        };
+        if !due {
+            return None;
+        }
+        let block = (self.source, self.destination);
+        self.source = self.source.wrapping_add(VRAM_DMA_BLOCK);
+        self.destination = (self.destination + VRAM_DMA_BLOCK) & 0x1FF0;
+        self.blocks -= 1;
+        if self.blocks == 0 {
+            self.mode = VramDmaMode::Idle;
+        }
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu;
+    use crate::interrupts;
+    use crate::memory::{Bus, Memory};
+    use crate::ppu;
+
+    #[test]
+    fn copies_to_oam_and_blocks_the_bus() {
+        let mut memory = Memory::default();
+        for index in 0..TRANSFER_LENGTH as u16 {
+            memory.write(0xC100 + index, index as u8 ^ 0x5A);
+        }
+        memory.write(0xFF80, 0x12);
+        memory.write(ppu::BGP_ADDRESS, 0xE4);
+
+        memory.write(DMA_ADDRESS, 0xC1);
+        //The delay cycle, then the first byte
+        memory.tick(8);
+        assert_eq!(memory.read(0xC100), 0xFF);
+        assert_eq!(memory.read(0xFF80), 0x12);
+        //I/O and IE are out of reach too
+        assert_eq!(memory.read(ppu::BGP_ADDRESS), 0xFF);
+        memory.write(interrupts::IE_ADDRESS, 0x1F);
+        assert_eq!(memory.interrupts.read_enable(), 0x00);
+
+        for _ in 1..TRANSFER_LENGTH - 1 {
+            memory.tick(4);
+        }
+        assert_eq!(memory.read(0xFE00), 0xFF);
+        memory.tick(4);
+        assert_eq!(memory.read(0xFE00), 0x5A);
+        assert_eq!(memory.read(0xFE9F), 0x9F ^ 0x5A);
+    }
+
+    fn start_vram_dma(memory : &mut Memory, source : u16, destination : u16, control : u8) {
+        memory.write(HDMA1_ADDRESS, (source >> 8) as u8);
+        memory.write(HDMA2_ADDRESS, source as u8);
+        memory.write(HDMA3_ADDRESS, (destination >> 8) as u8);
+        memory.write(HDMA4_ADDRESS, destination as u8);
+        memory.write(HDMA5_ADDRESS, control);
+        memory.tick(4);
+    }
+
+    #[test]
+    fn vram_dma_general_and_hblank() {
+        let mut memory = Memory::default();
+        memory.set_cgb(true);
+        for index in 0..0x40 {
+            memory.write(0xC000 + index, index as u8 + 1);
+        }
+
+        //With the LCD off, a general DMA of two blocks finishes straight away
+        start_vram_dma(&mut memory, 0xC000, 0x8100, 0x01);
+        assert_eq!(memory.read(HDMA5_ADDRESS), 0xFF);
+        assert_eq!(memory.read(0x8100), 0x01);
+        assert_eq!(memory.read(0x811F), 0x20);
+        assert_eq!(memory.read(0x8120), 0x00);
+
+        //An HBlank DMA copies one block per HBlank, and stops when bit 7 is cleared
+        memory.write(ppu::LCDC_ADDRESS, 0x91);
+        start_vram_dma(&mut memory, 0xC020, 0x8200, 0x82);
+        assert_eq!(memory.read(HDMA5_ADDRESS), 0x02);
+        while memory.ppu.mode() != ppu::Mode::HBlank {
+            memory.tick(4);
+        }
+        assert_eq!(memory.read(HDMA5_ADDRESS), 0x01);
+        assert_eq!(memory.read(0x8200), 0x21);
+        assert_eq!(memory.read(0x8210), 0x00);
+        memory.write(HDMA5_ADDRESS, 0x00);
+        assert_eq!(memory.read(HDMA5_ADDRESS), 0x81);
+    }
+
+    #[test]
+    fn general_dma_takes_the_same_time_at_double_speed() {
+        for double_speed in [false, true] {
+            let mut memory = Memory::default();
+            memory.set_cgb(true);
+            memory.speed.double_speed = double_speed;
+            memory.write(ppu::LCDC_ADDRESS, 0x91);
+            //128 blocks at 32 dots each
+            start_vram_dma(&mut memory, 0x0000, 0x8000, 0x7F);
+            assert_eq!(memory.read(ppu::LY_ADDRESS), (128 * 32 / 456) as u8);
+        }
+    }
+
+    /// Cycles `cpu::step` reports for LD A,0x01; LDH (port),A; NOP with a two block VRAM DMA set up
+    fn run_with_dma_setup(port : u8) -> (u32, Memory) {
+        let mut memory = Memory::default();
+        memory.set_cgb(true);
+        for (offset, byte) in [0x3E, 0x01, 0xE0, port, 0x00].into_iter().enumerate() {
+            memory.write(0xC000 + offset as u16, byte);
+        }
+        memory.write(HDMA1_ADDRESS, 0xC1);
+        memory.write(HDMA2_ADDRESS, 0x00);
+        memory.write(HDMA3_ADDRESS, 0x81);
+        memory.write(HDMA4_ADDRESS, 0x00);
+
+        let mut state = cpu::Registers::default();
+        state.set_pc(0xC000);
+        let cycles = (0..3).map(|_| cpu::step(&mut state, &mut memory)).sum();
+        (cycles, memory)
+    }
+
+    #[test]
+    fn general_dma_holds_up_the_cpu() {
+        //Writing HDMA4 instead takes as long to run, without starting anything
+        let (plain, _) = run_with_dma_setup(HDMA4_ADDRESS as u8);
+        let (cycles, memory) = run_with_dma_setup(HDMA5_ADDRESS as u8);
+        //Two blocks of 32 cycles on top of the instructions
+        assert_eq!(cycles, plain + 2 * 32);
+        assert_eq!(memory.read(HDMA5_ADDRESS), 0xFF);
+    }
+}
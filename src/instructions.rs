@@ -1,6 +1,6 @@
-use crate::cpu;
+use crate::cpu::{self, CpuBus};
 use crate::bitmath;
-use crate::memory::Memory;
+use crate::memory::Bus;
 
 use std::fmt;
 
@@ -17,7 +17,7 @@ pub enum MutableData8 {
 }
 
 impl MutableData8 {
-    pub fn get(&self, state : &mut cpu::Registers, memory : &Memory) -> u8 {
+    pub fn get<B : Bus>(&self, state : &mut cpu::Registers, memory : &mut CpuBus<B>) -> u8 {
         match &self {
             Self::Register8(reg)
                 => state.get_u8_register(reg),
@@ -35,7 +35,7 @@ impl MutableData8 {
                 => memory.read(0xFF00 + *addr as u16),
         }
     }
-    pub fn set(&self, value : u8, state : &mut cpu::Registers, memory : &mut Memory) {
+    pub fn set<B : Bus>(&self, value : u8, state : &mut cpu::Registers, memory : &mut CpuBus<B>) {
         match &self {
             Self::Register8(reg)
                 => state.set_u8_register(reg, value),
@@ -81,7 +81,7 @@ pub enum Data8 {
 }
 
 impl Data8 {
-    pub fn get(&self, state : &mut cpu::Registers, memory : &Memory) -> u8 {
+    pub fn get<B : Bus>(&self, state : &mut cpu::Registers, memory : &mut CpuBus<B>) -> u8 {
         match &self {
             Self::Immutable(data) => *data,
             Self::Mutable(mutable) => mutable.get(state, memory),
@@ -106,16 +106,19 @@ pub enum MutableData16 {
 }
 
 impl MutableData16 {
-    pub fn get(&self, state : &cpu::Registers, memory : &Memory) -> u16 {
+    pub fn get<B : Bus>(&self, state : &cpu::Registers, memory : &mut CpuBus<B>) -> u16 {
         match &self {
             Self::Register16(reg) => state.get_u16_register(reg),
-            Self::IndirectValue16(addr) => memory.read_u16(*addr),
+            Self::IndirectValue16(addr) => join_u8(memory.read(*addr), memory.read(addr.wrapping_add(1))),
         }
     }
-    pub fn set(&self, value : u16, state : &mut cpu::Registers, memory : &mut Memory) {
+    pub fn set<B : Bus>(&self, value : u16, state : &mut cpu::Registers, memory : &mut CpuBus<B>) {
         match &self {
             Self::Register16(reg) => state.set_u16_register(reg, value),
-            Self::IndirectValue16(addr) => memory.write_u16(*addr, value),
+            Self::IndirectValue16(addr) => {
+                memory.write(*addr, value as u8);
+                memory.write(addr.wrapping_add(1), (value >> 8) as u8);
+            },
         }
     }
 }
@@ -137,7 +140,12 @@ pub enum Data16 {
 }
 
 impl Data16 {
-
+    pub fn get<B : Bus>(&self, state : &cpu::Registers, memory : &mut CpuBus<B>) -> u16 {
+        match &self {
+            Self::Immutable(data) => *data,
+            Self::Mutable(mutable) => mutable.get(state, memory),
+        }
+    }
 }
 
 impl fmt::Display for Data16 {
@@ -160,10 +168,19 @@ pub enum Op {
     Dec8{into : MutableData8},
     Inc16{into : MutableData16},
     Dec16{into : MutableData16},
-    Ror{into : MutableData8},
-    RorCarry{into : MutableData8},
-    Rol{into : MutableData8},
-    RolCarry{into : MutableData8},
+    ///RR, rotate right through the carry flag
+    RotateRight{into : MutableData8},
+    ///RRC, rotate right, copying bit 0 into the carry flag
+    RotateRightCircular{into : MutableData8},
+    ///RL, rotate left through the carry flag
+    RotateLeft{into : MutableData8},
+    ///RLC, rotate left, copying bit 7 into the carry flag
+    RotateLeftCircular{into : MutableData8},
+    //Accumulator forms of the rotates above, which always clear the zero flag
+    Rlca,
+    Rrca,
+    Rla,
+    Rra,
     Add{into : MutableData8, from : Data8},
     Add16{into : MutableData16, from : Data16},
     AddCarry{into : MutableData8, from : Data8},
@@ -191,6 +208,26 @@ pub enum Op {
     Reset{into : MutableData8, bit : u8},
     Set{into : MutableData8, bit : u8},
 
+    DecimalAdjust,
+    Complement,
+    SetCarryFlag,
+    ComplementCarryFlag,
+
+    Jump{address : Data16},
+    JumpIf{condition : cpu::Flag, address : Data16},
+    Restart{address : u16},
+    ReturnInterrupt,
+    DisableInterrupts,
+    EnableInterrupts,
+
+    ///SP = SP + signed immediate
+    AddStackPointer{amount : i8},
+    ///HL = SP + signed immediate
+    LoadStackPointerOffset{amount : i8},
+
+    ///One of the 11 opcodes with no instruction behind them, which hang the CPU
+    Illegal(u8),
+    ///Not enough bytes were available to decode an instruction
     Unimplemented(u8)
 }
 
@@ -212,14 +249,18 @@ impl fmt::Display for Op {
                 write!(f, "INC {}", into),
             Op::Dec16{into} =>
                 write!(f, "DEC {}", into),
-            Op::Ror{into} =>
-                write!(f, "ROR {}", into),
-            Op::RorCarry{into} =>
+            Op::RotateRight{into} =>
+                write!(f, "RR {}", into),
+            Op::RotateRightCircular{into} =>
                 write!(f, "RRC {}", into),
-            Op::Rol{into} =>
-                write!(f, "ROL {}", into),
-            Op::RolCarry{into} =>
+            Op::RotateLeft{into} =>
+                write!(f, "RL {}", into),
+            Op::RotateLeftCircular{into} =>
                 write!(f, "RLC {}", into),
+            Op::Rlca => write!(f, "RLCA"),
+            Op::Rrca => write!(f, "RRCA"),
+            Op::Rla => write!(f, "RLA"),
+            Op::Rra => write!(f, "RRA"),
             Op::Add{from, into} =>
                 write!(f, "ADD {}, {}", into, from),
             Op::Add16{from, into} =>
@@ -268,6 +309,32 @@ impl fmt::Display for Op {
                 write!(f, "SET {bit}, {}", into),
             Op::Reset{into, bit} =>
                 write!(f, "RES {bit}, {}", into),
+            Op::DecimalAdjust =>
+                write!(f, "DAA"),
+            Op::Complement =>
+                write!(f, "CPL"),
+            Op::SetCarryFlag =>
+                write!(f, "SCF"),
+            Op::ComplementCarryFlag =>
+                write!(f, "CCF"),
+            Op::Jump{address} =>
+                write!(f, "JP  {}", address),
+            Op::JumpIf{condition, address} =>
+                write!(f, "JP {condition} {}", address),
+            Op::Restart{address} =>
+                write!(f, "RST ${:02X}", address),
+            Op::ReturnInterrupt =>
+                write!(f, "RETI"),
+            Op::DisableInterrupts =>
+                write!(f, "DI"),
+            Op::EnableInterrupts =>
+                write!(f, "EI"),
+            Op::AddStackPointer{amount} =>
+                write!(f, "ADD SP, {}", amount),
+            Op::LoadStackPointerOffset{amount} =>
+                write!(f, "LD HL, SP + {}", amount),
+            Op::Illegal(instr) =>
+                write!(f, "ILLEGAL {:02x}", instr),
 
             
             Op::Unimplemented(instr) =>
@@ -278,67 +345,77 @@ impl fmt::Display for Op {
 
 pub struct Instruction {
     pub op : Op,
+    ///Clock cycles taken, including the fetch. Conditional branches take longer when taken.
     pub cycles : u8,
     pub size : u8
 }
 
 impl Instruction {
+    /// Decode the instruction at `addr` in the address space
+    pub fn from_memory<B : Bus>(addr : u16, memory : &B) -> Option<Instruction> {
+        let bytes = [
+            memory.read(addr),
+            memory.read(addr.wrapping_add(1)),
+            memory.read(addr.wrapping_add(2)),
+        ];
+        Instruction::from_bytes(0, &bytes)
+    }
     pub fn from_bytes(addr : usize, data : &[u8]) -> Option<Instruction> {
         Some(match &data[addr..] {
 
             [0x00, ..]
                 => Instruction{ size : 1, cycles : 4, op : Op::Nop },
             [0x10, ..]
-                => Instruction{ size : 2, cycles : 1, op : Op::Stop },
+                => Instruction{ size : 2, cycles : 4, op : Op::Stop },
             [0x20, a, ..]
-                => Instruction{ size : 2, cycles : 1, op : Op::JumpRelativeIf {
+                => Instruction{ size : 2, cycles : 8, op : Op::JumpRelativeIf {
                     amount : *a as i8,
                     condition : cpu::Flag::NotZero 
                 } },
             [0x30, a, ..]
-                => Instruction{ size : 2, cycles : 1, op : Op::JumpRelativeIf {
+                => Instruction{ size : 2, cycles : 8, op : Op::JumpRelativeIf {
                     amount : *a as i8,
                     condition : cpu::Flag::NotCarry 
                 } },
 
             [0x01, a, b, ..]
-                => Instruction{ size : 3, cycles : 3, op : Op::Load16{
+                => Instruction{ size : 3, cycles : 12, op : Op::Load16{
                     into : MutableData16::Register16(cpu::Register16::BC),
                     from : Data16::Immutable(join_u8(*a, *b))
                 } },
             [0x11, a, b, ..]
-                => Instruction{ size : 3, cycles : 3, op : Op::Load16{
+                => Instruction{ size : 3, cycles : 12, op : Op::Load16{
                     into : MutableData16::Register16(cpu::Register16::DE),
                     from : Data16::Immutable(join_u8(*a, *b))
                 } },
             [0x21, a, b, ..]
-                => Instruction{ size : 3, cycles : 3, op : Op::Load16{
+                => Instruction{ size : 3, cycles : 12, op : Op::Load16{
                     into : MutableData16::Register16(cpu::Register16::HL),
                     from : Data16::Immutable(join_u8(*a, *b))
                 } },
             [0x31, a, b, ..]
-                => Instruction{ size : 3, cycles : 3, op : Op::Load16{
+                => Instruction{ size : 3, cycles : 12, op : Op::Load16{
                     into : MutableData16::Register16(cpu::Register16::SP),
                     from : Data16::Immutable(join_u8(*a, *b))
                 } },
 
             [0x02, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 1, cycles : 8, op : Op::Load8{
                     into : MutableData8::IndirectRegister16(cpu::Register16::BC),
                     from : Data8::Mutable(MutableData8::Register8(cpu::Register8::A))
                 } },
             [0x12, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 1, cycles : 8, op : Op::Load8{
                     into : MutableData8::IndirectRegister16(cpu::Register16::DE),
                     from : Data8::Mutable(MutableData8::Register8(cpu::Register8::A))
                 } },
             [0x22, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 1, cycles : 8, op : Op::Load8{
                     into : MutableData8::IndirectRegister16Inc(cpu::Register16::HL),
                     from : Data8::Mutable(MutableData8::Register8(cpu::Register8::A))
                 } },
             [0x32, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 1, cycles : 8, op : Op::Load8{
                     into : MutableData8::IndirectRegister16Dec(cpu::Register16::HL),
                     from : Data8::Mutable(MutableData8::Register8(cpu::Register8::A))
                 } },
@@ -362,337 +439,332 @@ impl Instruction {
 
 
             [0x04, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Inc8{
+                => Instruction{ size : 1, cycles : 4, op : Op::Inc8{
                     into : MutableData8::Register8(cpu::Register8::B)
                 } },
             [0x14, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Inc8{
+                => Instruction{ size : 1, cycles : 4, op : Op::Inc8{
                     into : MutableData8::Register8(cpu::Register8::D)
                 } },
             [0x24, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Inc8{
+                => Instruction{ size : 1, cycles : 4, op : Op::Inc8{
                     into : MutableData8::Register8(cpu::Register8::H)
                 } },
             [0x34, ..]
-                => Instruction{ size : 1, cycles : 3, op : Op::Inc8{
+                => Instruction{ size : 1, cycles : 12, op : Op::Inc8{
                     into : MutableData8::IndirectRegister16(cpu::Register16::HL)
                 } },
 
 
             [0x05, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Dec8{
+                => Instruction{ size : 1, cycles : 4, op : Op::Dec8{
                     into : MutableData8::Register8(cpu::Register8::B)
                 } },
             [0x15, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Dec8{
+                => Instruction{ size : 1, cycles : 4, op : Op::Dec8{
                     into : MutableData8::Register8(cpu::Register8::D)
                 } },
             [0x25, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Dec8{
+                => Instruction{ size : 1, cycles : 4, op : Op::Dec8{
                     into : MutableData8::Register8(cpu::Register8::H)
                 } },
             [0x35, ..]
-                => Instruction{ size : 1, cycles : 3, op : Op::Dec8{
+                => Instruction{ size : 1, cycles : 12, op : Op::Dec8{
                     into : MutableData8::IndirectRegister16(cpu::Register16::HL)
                 } },
 
             
             [0x06, a, ..]
-                => Instruction{ size : 2, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 2, cycles : 8, op : Op::Load8{
                     into : MutableData8::Register8(cpu::Register8::B),
                     from : Data8::Immutable(*a)
                 } },
             [0x16, a, ..]
-                => Instruction{ size : 2, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 2, cycles : 8, op : Op::Load8{
                     into : MutableData8::Register8(cpu::Register8::D),
                     from : Data8::Immutable(*a)
                 } },
             [0x26, a, ..]
-                => Instruction{ size : 2, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 2, cycles : 8, op : Op::Load8{
                     into : MutableData8::Register8(cpu::Register8::H),
                     from : Data8::Immutable(*a)
                 } },
             [0x36, a, ..]
-                => Instruction{ size : 2, cycles : 3, op : Op::Load8{
+                => Instruction{ size : 2, cycles : 12, op : Op::Load8{
                     into : MutableData8::IndirectRegister16(cpu::Register16::HL),
                     from : Data8::Immutable(*a)
                 } },
             
             
             [0x07, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::RolCarry{
-                    into : MutableData8::Register8(cpu::Register8::A),
-                } },
+                => Instruction{ size : 1, cycles : 4, op : Op::Rlca },
             [0x17, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Rol{
-                    into : MutableData8::Register8(cpu::Register8::A),
-                } },
+                => Instruction{ size : 1, cycles : 4, op : Op::Rla },
             [0x27, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Unimplemented(0x27) },
+                => Instruction{ size : 1, cycles : 4, op : Op::DecimalAdjust },
             [0x37, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Unimplemented(0x37) },
+                => Instruction{ size : 1, cycles : 4, op : Op::SetCarryFlag },
             
             [0x08, a, b, ..]
-                => Instruction{ size : 3, cycles : 5, op : Op::Load16{
+                => Instruction{ size : 3, cycles : 20, op : Op::Load16{
                     into : MutableData16::IndirectValue16(join_u8(*a, *b)),
                     from : Data16::Mutable(MutableData16::Register16(cpu::Register16::SP))
                 } },
             [0x18, a, ..]
-                => Instruction{ size : 2, cycles : 3, op : Op::JumpRelative{
+                => Instruction{ size : 2, cycles : 12, op : Op::JumpRelative{
                     amount : *a as i8
                 } },
             [0x28, a, ..]
-                => Instruction{ size : 2, cycles : 2, op : Op::JumpRelativeIf{
+                => Instruction{ size : 2, cycles : 8, op : Op::JumpRelativeIf{
                     condition : cpu::Flag::Zero,
                     amount : *a as i8
                 } },
             [0x38, a, ..]
-                => Instruction{ size : 2, cycles : 2, op : Op::JumpRelativeIf{
+                => Instruction{ size : 2, cycles : 8, op : Op::JumpRelativeIf{
                     condition : cpu::Flag::Carry,
                     amount : *a as i8
                 } },
             
             [0x09, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Add16{
+                => Instruction{ size : 1, cycles : 8, op : Op::Add16{
                     into : MutableData16::Register16(cpu::Register16::HL),
                     from : Data16::Mutable(MutableData16::Register16(cpu::Register16::BC))
                 } },
             [0x19, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Add16{
+                => Instruction{ size : 1, cycles : 8, op : Op::Add16{
                     into : MutableData16::Register16(cpu::Register16::HL),
                     from : Data16::Mutable(MutableData16::Register16(cpu::Register16::DE))
                 } },
             [0x29, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Add16{
+                => Instruction{ size : 1, cycles : 8, op : Op::Add16{
                     into : MutableData16::Register16(cpu::Register16::HL),
                     from : Data16::Mutable(MutableData16::Register16(cpu::Register16::HL))
                 } },
             [0x39, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Add16{
+                => Instruction{ size : 1, cycles : 8, op : Op::Add16{
                     into : MutableData16::Register16(cpu::Register16::HL),
                     from : Data16::Mutable(MutableData16::Register16(cpu::Register16::SP))
                 } },
             
             [0x0A, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 1, cycles : 8, op : Op::Load8{
                     into : MutableData8::Register8(cpu::Register8::A),
                     from : Data8::Mutable(MutableData8::IndirectRegister16(cpu::Register16::BC))
                 } },
             [0x1A, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 1, cycles : 8, op : Op::Load8{
                     into : MutableData8::Register8(cpu::Register8::A),
                     from : Data8::Mutable(MutableData8::IndirectRegister16(cpu::Register16::DE))
                 } },
             [0x2A, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 1, cycles : 8, op : Op::Load8{
                     into : MutableData8::Register8(cpu::Register8::A),
                     from : Data8::Mutable(MutableData8::IndirectRegister16Inc(cpu::Register16::HL))
                 } },
             [0x3A, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 1, cycles : 8, op : Op::Load8{
                     into : MutableData8::Register8(cpu::Register8::A),
                     from : Data8::Mutable(MutableData8::IndirectRegister16Dec(cpu::Register16::HL))
                 } },
 
             
             [0x0B, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Dec16{
+                => Instruction{ size : 1, cycles : 8, op : Op::Dec16{
                     into : MutableData16::Register16(cpu::Register16::BC)
                 } },
             [0x1B, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Dec16{
+                => Instruction{ size : 1, cycles : 8, op : Op::Dec16{
                     into : MutableData16::Register16(cpu::Register16::DE)
                 } },
             [0x2B, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Dec16{
+                => Instruction{ size : 1, cycles : 8, op : Op::Dec16{
                     into : MutableData16::Register16(cpu::Register16::HL)
                 } },
             [0x3B, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Dec16{
+                => Instruction{ size : 1, cycles : 8, op : Op::Dec16{
                     into : MutableData16::Register16(cpu::Register16::SP)
                 } },
 
             [0x0C, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Inc8{
+                => Instruction{ size : 1, cycles : 4, op : Op::Inc8{
                     into : MutableData8::Register8(cpu::Register8::C)
                 } },
             [0x1C, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Inc8{
+                => Instruction{ size : 1, cycles : 4, op : Op::Inc8{
                     into : MutableData8::Register8(cpu::Register8::E)
                 } },
             [0x2C, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Inc8{
+                => Instruction{ size : 1, cycles : 4, op : Op::Inc8{
                     into : MutableData8::Register8(cpu::Register8::L)
                 } },
             [0x3C, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Inc8{
+                => Instruction{ size : 1, cycles : 4, op : Op::Inc8{
                     into : MutableData8::Register8(cpu::Register8::A)
                 } },
 
             [0x0D, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Dec8{
+                => Instruction{ size : 1, cycles : 4, op : Op::Dec8{
                     into : MutableData8::Register8(cpu::Register8::C)
                 } },
             [0x1D, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Dec8{
+                => Instruction{ size : 1, cycles : 4, op : Op::Dec8{
                     into : MutableData8::Register8(cpu::Register8::E)
                 } },
             [0x2D, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Dec8{
+                => Instruction{ size : 1, cycles : 4, op : Op::Dec8{
                     into : MutableData8::Register8(cpu::Register8::L)
                 } },
             [0x3D, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Dec8{
+                => Instruction{ size : 1, cycles : 4, op : Op::Dec8{
                     into : MutableData8::Register8(cpu::Register8::A)
                 } },
             
             
             [0x0E, a, ..]
-                => Instruction{ size : 2, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 2, cycles : 8, op : Op::Load8{
                     into : MutableData8::Register8(cpu::Register8::C),
                     from : Data8::Immutable(*a)
                 } },
             [0x1E, a, ..]
-                => Instruction{ size : 2, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 2, cycles : 8, op : Op::Load8{
                     into : MutableData8::Register8(cpu::Register8::E),
                     from : Data8::Immutable(*a)
                 } },
             [0x2E, a, ..]
-                => Instruction{ size : 2, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 2, cycles : 8, op : Op::Load8{
                     into : MutableData8::Register8(cpu::Register8::L),
                     from : Data8::Immutable(*a)
                 } },
             [0x3E, a, ..]
-                => Instruction{ size : 2, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 2, cycles : 8, op : Op::Load8{
                     into : MutableData8::Register8(cpu::Register8::A),
                     from : Data8::Immutable(*a)
                 } },
 
             [0x0F, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::RorCarry{
-                    into : MutableData8::Register8(cpu::Register8::A),
-                } },
+                => Instruction{ size : 1, cycles : 4, op : Op::Rrca },
             [0x1F, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Ror{
-                    into : MutableData8::Register8(cpu::Register8::A),
-                } },
+                => Instruction{ size : 1, cycles : 4, op : Op::Rra },
             [0x2F, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Unimplemented(0x2F) },
+                => Instruction{ size : 1, cycles : 4, op : Op::Complement },
             [0x3F, ..]
-                => Instruction{ size : 1, cycles : 1, op : Op::Unimplemented(0x3F) },
-
-            [0xAF, ..]
-                => Instruction{ size : 1, cycles : 4, op : Op::Xor{
-                    into : MutableData8::Register8(cpu::Register8::A),
-                    from : Data8::Mutable(MutableData8::Register8(cpu::Register8::A))
-                } },
+                => Instruction{ size : 1, cycles : 4, op : Op::ComplementCarryFlag },
 
             [0xCC, a, b, ..]
-                => Instruction{ size : 3, cycles : 6, op : Op::CallIf {
+                => Instruction{ size : 3, cycles : 12, op : Op::CallIf {
                     condition : cpu::Flag::Zero,
                     address : Data16::Immutable(join_u8(*a, *b))
                 } },
             [0xDC, a, b, ..]
-                => Instruction{ size : 3, cycles : 6, op : Op::CallIf {
+                => Instruction{ size : 3, cycles : 12, op : Op::CallIf {
                     condition : cpu::Flag::Carry,
                     address : Data16::Immutable(join_u8(*a, *b))
                 } },
             [0xCD, a, b, ..]
-                => Instruction{ size : 3, cycles : 6, op : Op::Call {
+                => Instruction{ size : 3, cycles : 24, op : Op::Call {
+                    address : Data16::Immutable(join_u8(*a, *b))
+                } },
+            [0xC4, a, b, ..]
+                => Instruction{ size : 3, cycles : 12, op : Op::CallIf {
+                    condition : cpu::Flag::NotZero,
                     address : Data16::Immutable(join_u8(*a, *b))
                 } },
+            [0xD4, a, b, ..]
+                => Instruction{ size : 3, cycles : 12, op : Op::CallIf {
+                    condition : cpu::Flag::NotCarry,
+                    address : Data16::Immutable(join_u8(*a, *b))
+                } },
+
+            [0xC3, a, b, ..]
+                => Instruction{ size : 3, cycles : 16, op : Op::Jump {
+                    address : Data16::Immutable(join_u8(*a, *b))
+                } },
+            [0xC2, a, b, ..]
+                => Instruction{ size : 3, cycles : 12, op : Op::JumpIf {
+                    condition : cpu::Flag::NotZero,
+                    address : Data16::Immutable(join_u8(*a, *b))
+                } },
+            [0xCA, a, b, ..]
+                => Instruction{ size : 3, cycles : 12, op : Op::JumpIf {
+                    condition : cpu::Flag::Zero,
+                    address : Data16::Immutable(join_u8(*a, *b))
+                } },
+            [0xD2, a, b, ..]
+                => Instruction{ size : 3, cycles : 12, op : Op::JumpIf {
+                    condition : cpu::Flag::NotCarry,
+                    address : Data16::Immutable(join_u8(*a, *b))
+                } },
+            [0xDA, a, b, ..]
+                => Instruction{ size : 3, cycles : 12, op : Op::JumpIf {
+                    condition : cpu::Flag::Carry,
+                    address : Data16::Immutable(join_u8(*a, *b))
+                } },
+            [0xE9, ..]
+                => Instruction{ size : 1, cycles : 4, op : Op::Jump {
+                    address : Data16::Mutable(MutableData16::Register16(cpu::Register16::HL))
+                } },
+
+            //RST xx, vector encoded in bits 3..=5 of the opcode
+            [opcode @ (0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF), ..]
+                => Instruction{ size : 1, cycles : 16, op : Op::Restart {
+                    address : (*opcode & 0b0011_1000) as u16
+                } },
+
+            [0xD9, ..]
+                => Instruction{ size : 1, cycles : 16, op : Op::ReturnInterrupt },
+            [0xF3, ..]
+                => Instruction{ size : 1, cycles : 4, op : Op::DisableInterrupts },
+            [0xFB, ..]
+                => Instruction{ size : 1, cycles : 4, op : Op::EnableInterrupts },
+
+            [0xE8, a, ..]
+                => Instruction{ size : 2, cycles : 16, op : Op::AddStackPointer {
+                    amount : *a as i8
+                } },
+            [0xF8, a, ..]
+                => Instruction{ size : 2, cycles : 12, op : Op::LoadStackPointerOffset {
+                    amount : *a as i8
+                } },
+            [0xF9, ..]
+                => Instruction{ size : 1, cycles : 8, op : Op::Load16 {
+                    into : MutableData16::Register16(cpu::Register16::SP),
+                    from : Data16::Mutable(MutableData16::Register16(cpu::Register16::HL))
+                } },
+
+            //ALU A, n. Same operation ordering as the 0x80..=0xBF block
+            [opcode @ (0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE), a, ..]
+                => Instruction{ size : 2, cycles : 8,
+                    op : Instruction::alu_op((*opcode >> 3) & 0b111, Data8::Immutable(*a))
+                },
+
+            [opcode @ (0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD), ..]
+                => Instruction{ size : 1, cycles : 4, op : Op::Illegal(*opcode) },
             
-            [0x40..=0x7f, ..]
+            [0x76, ..]
+                => Instruction{ size : 1, cycles : 4, op : Op::Halt },
+            [opcode @ 0x40..=0x7F, ..]
                 => {
-                    let opcode = data[0];
-
-                    if opcode == 0x76 {
-                        Instruction{ size : 1, cycles : 4, op : Op::Halt }
-                    } else {
-                        let data_source = match opcode & 0b0111 {
-                            0x0 => Data8::Mutable(MutableData8::Register8(cpu::Register8::B)),
-                            0x1 => Data8::Mutable(MutableData8::Register8(cpu::Register8::C)),
-                            0x2 => Data8::Mutable(MutableData8::Register8(cpu::Register8::D)),
-                            0x3 => Data8::Mutable(MutableData8::Register8(cpu::Register8::E)),
-                            0x4 => Data8::Mutable(MutableData8::Register8(cpu::Register8::H)),
-                            0x5 => Data8::Mutable(MutableData8::Register8(cpu::Register8::L)),
-                            0x6 => Data8::Mutable(MutableData8::IndirectRegister16(cpu::Register16::HL)),
-                            0x7 => Data8::Mutable(MutableData8::Register8(cpu::Register8::A)),
-                
-                            //We masked the lower three bits, it will only ever be 0..=7
-                            _ => unreachable!()
-                        };
-                        //Operation takes 8 cycles if it's indirected, 4 otherwise
-                        let mut cycles = if let Data8::Mutable(MutableData8::IndirectRegister16(_)) = data_source {8} else {4};
-                
-                        //Top5 bits indicate operation
-                        let operation = opcode >> 3;
-                        let op = {
-                            let data_dest = match operation & 0b111 {
-                                0x0 => MutableData8::Register8(cpu::Register8::B),
-                                0x1 => MutableData8::Register8(cpu::Register8::C),
-                                0x2 => MutableData8::Register8(cpu::Register8::D),
-                                0x3 => MutableData8::Register8(cpu::Register8::E),
-                                0x4 => MutableData8::Register8(cpu::Register8::H),
-                                0x5 => MutableData8::Register8(cpu::Register8::L),
-                                0x6 => {
-                                    cycles = 8;
-                                    MutableData8::IndirectRegister16(cpu::Register16::HL)
-                                },
-                                0x7 => MutableData8::Register8(cpu::Register8::A),
-                    
-                                //We masked the lower three bits, it will only ever be 0..=7
-                                _ => unreachable!()
-                            };
-                            Op::Load8{ into : data_dest, from : data_source }
-                        };
-                        Instruction { size : 1, cycles, op }
-                    }
+                    //Bottom 3 bits determine the source, the next 3 the destination
+                    let data_source = Instruction::operand_from_index(opcode & 0b0111);
+                    let data_dest = Instruction::operand_from_index((opcode >> 3) & 0b0111);
+
+                    //Operation takes 8 cycles if it's indirected, 4 otherwise
+                    let indirect = matches!(data_source, MutableData8::IndirectRegister16(_))
+                        || matches!(data_dest, MutableData8::IndirectRegister16(_));
+                    let cycles = if indirect {8} else {4};
+
+                    Instruction { size : 1, cycles, op : Op::Load8{ into : data_dest, from : Data8::Mutable(data_source) } }
                 },
 
-            [0x80..=0xBF, ..]
+            [opcode @ 0x80..=0xBF, ..]
                 => {
-                    let opcode = data[addr];
-
-                    let data_source = match opcode & 0b0111 {
-                        0x0 => Data8::Mutable(MutableData8::Register8(cpu::Register8::B)),
-                        0x1 => Data8::Mutable(MutableData8::Register8(cpu::Register8::C)),
-                        0x2 => Data8::Mutable(MutableData8::Register8(cpu::Register8::D)),
-                        0x3 => Data8::Mutable(MutableData8::Register8(cpu::Register8::E)),
-                        0x4 => Data8::Mutable(MutableData8::Register8(cpu::Register8::H)),
-                        0x5 => Data8::Mutable(MutableData8::Register8(cpu::Register8::L)),
-                        0x6 => Data8::Mutable(MutableData8::IndirectRegister16(cpu::Register16::HL)),
-                        0x7 => Data8::Mutable(MutableData8::Register8(cpu::Register8::A)),
-            
-                        //We masked the lower three bits, it will only ever be 0..=7
-                        _ => unreachable!()
-                    };
+                    let data_source = Instruction::operand_from_index(opcode & 0b0111);
+
                     //Operation takes 8 cycles if it's indirected, 4 otherwise
-                    let cycles = if let Data8::Mutable(MutableData8::IndirectRegister16(_)) = data_source {8} else {4};
-            
-                    //Top5 bits indicate operation
-                    let operation = (opcode - 0x80) >> 3;
-                    let op = match operation {
-                        //ADD A, _
-                        0x0 => Op::Add{ into : MutableData8::Register8(cpu::Register8::A), from : data_source},
-                        //ADC A, _
-                        0x1 => Op::AddCarry{ into : MutableData8::Register8(cpu::Register8::A), from : data_source},
-                        //SUB A, _
-                        0x2 => Op::Sub{ into : MutableData8::Register8(cpu::Register8::A), from : data_source},
-                        //SBC A, _
-                        0x3 => Op::SubCarry{ into : MutableData8::Register8(cpu::Register8::A), from : data_source},
-                        //AND A, _
-                        0x4 => Op::And{ into : MutableData8::Register8(cpu::Register8::A), from : data_source},
-                        //XOR A, _
-                        0x5 => Op::Xor{ into : MutableData8::Register8(cpu::Register8::A), from : data_source},
-                        //OR A, _
-                        0x6 => Op::Or{ into : MutableData8::Register8(cpu::Register8::A), from : data_source},
-                        //CP A, _
-                        0x7 => Op::Compare{ into : Data8::Mutable(MutableData8::Register8(cpu::Register8::A)), from : data_source},
-                        
-                        //If opcode is in the range [0x80..=0xbf] minus 80, the top 5 bits will range from 0x00 to 0x07
-                        _ => unreachable!()
-                    };
+                    let cycles = if let MutableData8::IndirectRegister16(_) = data_source {8} else {4};
+
+                    //Bits 3..=5 indicate operation
+                    let op = Instruction::alu_op((opcode >> 3) & 0b111, Data8::Mutable(data_source));
                     Instruction { size : 1, cycles, op }
                 }
 
@@ -700,22 +772,22 @@ impl Instruction {
                 => Instruction::extended_instruction_from_opcode(*opcode),
 
             [0xE0, a, ..]
-                => Instruction{ size : 2, cycles : 3, op : Op::Load8{
+                => Instruction{ size : 2, cycles : 12, op : Op::Load8{
                     into : MutableData8::IndirectValue8(*a),
                     from : Data8::Mutable(MutableData8::Register8(cpu::Register8::A))
                 } },
             [0xF0, a, ..]
-                => Instruction{ size : 2, cycles : 3, op : Op::Load8{
+                => Instruction{ size : 2, cycles : 12, op : Op::Load8{
                     into : MutableData8::Register8(cpu::Register8::A),
                     from : Data8::Mutable(MutableData8::IndirectValue8(*a))
                 } },
             [0xE2, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 1, cycles : 8, op : Op::Load8{
                     into : MutableData8::IndirectRegister8(cpu::Register8::C),
                     from : Data8::Mutable(MutableData8::Register8(cpu::Register8::A))
                 } },
             [0xF2, ..]
-                => Instruction{ size : 1, cycles : 2, op : Op::Load8{
+                => Instruction{ size : 1, cycles : 8, op : Op::Load8{
                     into : MutableData8::Register8(cpu::Register8::A),
                     from : Data8::Mutable(MutableData8::IndirectRegister8(cpu::Register8::C))
                 } },
@@ -723,67 +795,69 @@ impl Instruction {
             
             
             [0xC1, ..]
-                => Instruction{ size : 1, cycles : 3, op : Op::Pop{
+                => Instruction{ size : 1, cycles : 12, op : Op::Pop{
                     into : MutableData16::Register16(cpu::Register16::BC)
                 } },
             [0xD1, ..]
-                => Instruction{ size : 1, cycles : 3, op : Op::Pop{
+                => Instruction{ size : 1, cycles : 12, op : Op::Pop{
                     into : MutableData16::Register16(cpu::Register16::DE)
                 } },
             [0xE1, ..]
-                => Instruction{ size : 1, cycles : 3, op : Op::Pop{
+                => Instruction{ size : 1, cycles : 12, op : Op::Pop{
                     into : MutableData16::Register16(cpu::Register16::HL)
                 } },
             [0xF1, ..]
-                => Instruction{ size : 1, cycles : 43, op : Op::Pop{
+                => Instruction{ size : 1, cycles : 12, op : Op::Pop{
                     into : MutableData16::Register16(cpu::Register16::AF)
                 } },
             
             [0xC5, ..]
-                => Instruction{ size : 1, cycles : 4, op : Op::Push{
+                => Instruction{ size : 1, cycles : 16, op : Op::Push{
                     from : Data16::Mutable(MutableData16::Register16(cpu::Register16::BC))
                 } },
             [0xD5, ..]
-                => Instruction{ size : 1, cycles : 4, op : Op::Push{
+                => Instruction{ size : 1, cycles : 16, op : Op::Push{
                     from : Data16::Mutable(MutableData16::Register16(cpu::Register16::DE))
                 } },
             [0xE5, ..]
-                => Instruction{ size : 1, cycles : 4, op : Op::Push{
+                => Instruction{ size : 1, cycles : 16, op : Op::Push{
                     from : Data16::Mutable(MutableData16::Register16(cpu::Register16::HL))
                 } },
             [0xF5, ..]
-                => Instruction{ size : 1, cycles : 4, op : Op::Push{
+                => Instruction{ size : 1, cycles : 16, op : Op::Push{
                     from : Data16::Mutable(MutableData16::Register16(cpu::Register16::AF))
                 } },
 
             [0xC9, ..]
-                => Instruction{ size : 1, cycles : 4, op : Op::Return },
+                => Instruction{ size : 1, cycles : 16, op : Op::Return },
+            [0xC0, ..]
+                => Instruction{ size : 1, cycles : 8, op : Op::ReturnIf{ condition : cpu::Flag::NotZero } },
+            [0xC8, ..]
+                => Instruction{ size : 1, cycles : 8, op : Op::ReturnIf{ condition : cpu::Flag::Zero } },
+            [0xD0, ..]
+                => Instruction{ size : 1, cycles : 8, op : Op::ReturnIf{ condition : cpu::Flag::NotCarry } },
+            [0xD8, ..]
+                => Instruction{ size : 1, cycles : 8, op : Op::ReturnIf{ condition : cpu::Flag::Carry } },
 
             [0xEA, a, b, ..]
-                => Instruction{ size : 3, cycles : 4, op : Op::Load8{
+                => Instruction{ size : 3, cycles : 16, op : Op::Load8{
                     into : MutableData8::IndirectValue16(join_u8(*a, *b)),
                     from : Data8::Mutable(MutableData8::Register8(cpu::Register8::A))
                 } },
             [0xFA, a, b, ..]
-                => Instruction{ size : 3, cycles : 4, op : Op::Load8{
+                => Instruction{ size : 3, cycles : 16, op : Op::Load8{
                     into : MutableData8::Register8(cpu::Register8::A),
                     from : Data8::Mutable(MutableData8::IndirectValue16(join_u8(*a, *b)))
                 } },
 
-            [0xFE, a, ..]
-                => Instruction{ size : 2, cycles : 2, op : Op::Compare {
-                    from : Data8::Mutable(MutableData8::Register8(cpu::Register8::A)),
-                    into : Data8::Immutable(*a)
-                }},
-
             [a, ..] => Instruction{ size : 0, cycles : 0, op : Op::Unimplemented(*a) },
 
             _ => Instruction{ size : 0, cycles : 0, op : Op::Unimplemented(0) }
         })
     }
-    fn extended_instruction_from_opcode(opcode : u8) -> Instruction {
-        //Bottom 3 bits determines which register to operate on
-        let data_dest = match opcode & 0b0111 {
+    /// Register or (HL) operand as encoded in three bits of an opcode
+    fn operand_from_index(index : u8) -> MutableData8 {
+        match index {
             0x0 => MutableData8::Register8(cpu::Register8::B),
             0x1 => MutableData8::Register8(cpu::Register8::C),
             0x2 => MutableData8::Register8(cpu::Register8::D),
@@ -793,51 +867,1397 @@ impl Instruction {
             0x6 => MutableData8::IndirectRegister16(cpu::Register16::HL),
             0x7 => MutableData8::Register8(cpu::Register8::A),
 
-            //We masked the lower three bits, it will only ever be 0..=7
+            //Callers mask to three bits, it will only ever be 0..=7
             _ => unreachable!()
-        };
-        //Operation takes 16 cycles if it's indirected, 8 otherwise
-        let cycles = if let MutableData8::IndirectRegister16(_) = data_dest {16} else {8};
-
-        //Top5 bits indicate operation
-        let operation = opcode >> 3;
-        let op = match operation {
-            //RLC
-            0x00 => Op::RolCarry{ into : data_dest },
-            //RRC
-            0x01 => Op::RorCarry{ into : data_dest },
-            //RL
-            0x02 => Op::Rol{ into : data_dest },
-            //RR
-            0x03 => Op::Ror{ into : data_dest },
-            //SLA
-            0x04 => Op::ShiftLeftAccumulator{ into : data_dest },
-            //SRA
-            0x05 => Op::ShiftRightAccumulator{ into : data_dest },
-            //SWAP
-            0x06 => Op::Swap{ into : data_dest },
-            //SRL
-            0x07 => Op::ShiftRightLogical{ into : data_dest },
-            //BIT[0..7]
-            0x08..=0x0F => Op::Bit{ into : Data8::Mutable(data_dest), bit : operation - 0x08 },
-            //RES[0..7]
-            0x10..=0x17 =>  Op::Reset{ into : data_dest, bit : operation - 0x16 },
-            //SET[0..7]
-            0x18..=0x1F => Op::Set{ into : data_dest, bit : operation - 0x24 },
-
-            //We masked to the top 5 bits, will always range 0..=31
+        }
+    }
+    /// One of the eight accumulator ALU operations, in opcode order
+    fn alu_op(operation : u8, from : Data8) -> Op {
+        let into = MutableData8::Register8(cpu::Register8::A);
+        match operation {
+            //ADD A, _
+            0x0 => Op::Add{ into, from },
+            //ADC A, _
+            0x1 => Op::AddCarry{ into, from },
+            //SUB A, _
+            0x2 => Op::Sub{ into, from },
+            //SBC A, _
+            0x3 => Op::SubCarry{ into, from },
+            //AND A, _
+            0x4 => Op::And{ into, from },
+            //XOR A, _
+            0x5 => Op::Xor{ into, from },
+            //OR A, _
+            0x6 => Op::Or{ into, from },
+            //CP A, _
+            0x7 => Op::Compare{ into : Data8::Mutable(into), from },
+
+            //Callers mask to three bits, it will only ever be 0..=7
+            _ => unreachable!()
+        }
+    }
+    fn extended_instruction_from_opcode(opcode : u8) -> Instruction {
+        //Bottom 3 bits determines which register to operate on
+        let data_dest = Instruction::operand_from_index(opcode & 0b0111);
+        let indirect = matches!(data_dest, MutableData8::IndirectRegister16(_));
+
+        //Top 2 bits select the group, the middle 3 bits the operation or bit index
+        let index = (opcode >> 3) & 0b0111;
+        let (op, cycles) = match opcode >> 6 {
+            0x0 => {
+                let op = match index {
+                    0x0 => Op::RotateLeftCircular{ into : data_dest },
+                    0x1 => Op::RotateRightCircular{ into : data_dest },
+                    0x2 => Op::RotateLeft{ into : data_dest },
+                    0x3 => Op::RotateRight{ into : data_dest },
+                    0x4 => Op::ShiftLeftAccumulator{ into : data_dest },
+                    0x5 => Op::ShiftRightAccumulator{ into : data_dest },
+                    0x6 => Op::Swap{ into : data_dest },
+                    0x7 => Op::ShiftRightLogical{ into : data_dest },
+
+                    //We masked three bits, it will only ever be 0..=7
+                    _ => unreachable!(),
+                };
+                (op, if indirect {16} else {8})
+            },
+            //BIT only reads its operand, so skips the write back to (HL)
+            0x1 => (Op::Bit{ into : Data8::Mutable(data_dest), bit : index }, if indirect {12} else {8}),
+            0x2 => (Op::Reset{ into : data_dest, bit : index }, if indirect {16} else {8}),
+            0x3 => (Op::Set{ into : data_dest, bit : index }, if indirect {16} else {8}),
+
+            //Top two bits of a u8, will always range 0..=3
             _ => unreachable!(),
         };
         Instruction { size : 2, cycles, op }
     }
 
-    pub fn execute(&self, state : &mut cpu::Registers, memory : &mut Memory) {
-        let (default_addr, default_cycles) = (state.pc() + self.size as u16, self.cycles);
-        let (new_addr, cycles) : (u16, u8) = match &self.op {
-            
-            _ => (default_addr, default_cycles)
-        };
+    /// Carry out the instruction once it's been fetched, spending the rest of its cycles on the bus
+    pub fn execute<B : Bus>(&self, state : &mut cpu::Registers, memory : &mut CpuBus<B>) {
+        let default_addr = state.pc().wrapping_add(self.size as u16);
+
+        //PC already points past this instruction while it executes, so relative jumps
+        //and pushed return addresses are relative to the next instruction
+        state.set_pc(default_addr);
+
+        //An EI before this instruction takes effect once it completes
+        let enable_interrupts = state.ime_scheduled;
+
+        match &self.op {
+            Op::Nop | Op::Unimplemented(_) => (),
+            //Hardware hangs on these, leave PC on the opcode and stop fetching
+            Op::Illegal(_) => {
+                state.set_pc(default_addr.wrapping_sub(self.size as u16));
+                state.mode = cpu::Mode::Locked;
+            },
+            Op::Halt => {
+                if !state.ime && memory.interrupts().pending() != 0 {
+                    //HALT bug, the CPU doesn't halt and the next fetch doesn't increment PC
+                    state.halt_bug = true;
+                } else {
+                    state.mode = cpu::Mode::Halted;
+                }
+            },
+            Op::Stop => {
+                //An armed CGB speed switch is performed instead of stopping
+                if !memory.stop() {
+                    state.mode = cpu::Mode::Stopped;
+                }
+            },
+            Op::DisableInterrupts => {
+                state.ime = false;
+                state.ime_scheduled = false;
+            },
+            Op::EnableInterrupts => {
+                state.ime_scheduled = true;
+            },
+
+            Op::Load8{into, from} => {
+                let value = from.get(state, memory);
+                into.set(value, state, memory);
+            },
+            Op::Load16{into, from} => {
+                let value = from.get(state, memory);
+                //LD SP, HL spends a cycle moving the value over
+                if let (MutableData16::Register16(_), Data16::Mutable(MutableData16::Register16(_))) = (into, from) {
+                    memory.idle();
+                }
+                into.set(value, state, memory);
+            },
+
+            Op::Inc8{into} => {
+                let value = into.get(state, memory);
+                let result = value.wrapping_add(1);
+                into.set(result, state, memory);
+
+                state.assign_flag(cpu::Flag::Zero, result == 0);
+                state.reset_flag(cpu::Flag::Negative);
+                state.assign_flag(cpu::Flag::HalfCarry, value & 0xF == 0xF);
+            },
+            Op::Dec8{into} => {
+                let value = into.get(state, memory);
+                let result = value.wrapping_sub(1);
+                into.set(result, state, memory);
+
+                state.assign_flag(cpu::Flag::Zero, result == 0);
+                state.set_flag(cpu::Flag::Negative);
+                state.assign_flag(cpu::Flag::HalfCarry, value & 0xF == 0x0);
+            },
+            //16 bit arithmetic goes through the 8 bit ALU, taking an extra cycle
+            Op::Inc16{into} => {
+                let value = into.get(state, memory);
+                into.set(value.wrapping_add(1), state, memory);
+                memory.idle();
+            },
+            Op::Dec16{into} => {
+                let value = into.get(state, memory);
+                into.set(value.wrapping_sub(1), state, memory);
+                memory.idle();
+            },
+
+            Op::RotateRight{into} => {
+                let value = into.get(state, memory);
+                let result = (value >> 1) | ((state.flag(cpu::Flag::Carry) as u8) << 7);
+                into.set(result, state, memory);
+                Instruction::set_shift_flags(state, result, value & 0x01 != 0);
+            },
+            Op::RotateRightCircular{into} => {
+                let value = into.get(state, memory);
+                let result = value.rotate_right(1);
+                into.set(result, state, memory);
+                Instruction::set_shift_flags(state, result, value & 0x01 != 0);
+            },
+            Op::RotateLeft{into} => {
+                let value = into.get(state, memory);
+                let result = (value << 1) | (state.flag(cpu::Flag::Carry) as u8);
+                into.set(result, state, memory);
+                Instruction::set_shift_flags(state, result, value & 0x80 != 0);
+            },
+            Op::RotateLeftCircular{into} => {
+                let value = into.get(state, memory);
+                let result = value.rotate_left(1);
+                into.set(result, state, memory);
+                Instruction::set_shift_flags(state, result, value & 0x80 != 0);
+            },
+            Op::Rlca => {
+                let value = state.a;
+                state.a = value.rotate_left(1);
+                Instruction::set_shift_flags(state, state.a, value & 0x80 != 0);
+                state.reset_flag(cpu::Flag::Zero);
+            },
+            Op::Rrca => {
+                let value = state.a;
+                state.a = value.rotate_right(1);
+                Instruction::set_shift_flags(state, state.a, value & 0x01 != 0);
+                state.reset_flag(cpu::Flag::Zero);
+            },
+            Op::Rla => {
+                let value = state.a;
+                state.a = (value << 1) | (state.flag(cpu::Flag::Carry) as u8);
+                Instruction::set_shift_flags(state, state.a, value & 0x80 != 0);
+                state.reset_flag(cpu::Flag::Zero);
+            },
+            Op::Rra => {
+                let value = state.a;
+                state.a = (value >> 1) | ((state.flag(cpu::Flag::Carry) as u8) << 7);
+                Instruction::set_shift_flags(state, state.a, value & 0x01 != 0);
+                state.reset_flag(cpu::Flag::Zero);
+            },
+
+            Op::Add{into, from} => {
+                let (lhs, rhs) = (into.get(state, memory), from.get(state, memory));
+                let result = Instruction::add_with_flags(state, lhs, rhs, false);
+                into.set(result, state, memory);
+            },
+            Op::AddCarry{into, from} => {
+                let (lhs, rhs) = (into.get(state, memory), from.get(state, memory));
+                let carry = state.flag(cpu::Flag::Carry);
+                let result = Instruction::add_with_flags(state, lhs, rhs, carry);
+                into.set(result, state, memory);
+            },
+            Op::Sub{into, from} => {
+                let (lhs, rhs) = (into.get(state, memory), from.get(state, memory));
+                let result = Instruction::sub_with_flags(state, lhs, rhs, false);
+                into.set(result, state, memory);
+            },
+            Op::SubCarry{into, from} => {
+                let (lhs, rhs) = (into.get(state, memory), from.get(state, memory));
+                let carry = state.flag(cpu::Flag::Carry);
+                let result = Instruction::sub_with_flags(state, lhs, rhs, carry);
+                into.set(result, state, memory);
+            },
+            Op::Compare{into, from} => {
+                let (lhs, rhs) = (into.get(state, memory), from.get(state, memory));
+                Instruction::sub_with_flags(state, lhs, rhs, false);
+            },
+            Op::And{into, from} => {
+                let result = into.get(state, memory) & from.get(state, memory);
+                into.set(result, state, memory);
+                Instruction::set_logic_flags(state, result, true);
+            },
+            Op::Or{into, from} => {
+                let result = into.get(state, memory) | from.get(state, memory);
+                into.set(result, state, memory);
+                Instruction::set_logic_flags(state, result, false);
+            },
+            Op::Xor{into, from} => {
+                let result = into.get(state, memory) ^ from.get(state, memory);
+                into.set(result, state, memory);
+                Instruction::set_logic_flags(state, result, false);
+            },
+            Op::Add16{into, from} => {
+                let (lhs, rhs) = (into.get(state, memory), from.get(state, memory));
+                let (result, carry) = lhs.overflowing_add(rhs);
+                into.set(result, state, memory);
+
+                //Zero flag is left untouched by 16 bit adds
+                state.reset_flag(cpu::Flag::Negative);
+                state.assign_flag(cpu::Flag::HalfCarry, (lhs & 0x0FFF) + (rhs & 0x0FFF) > 0x0FFF);
+                state.assign_flag(cpu::Flag::Carry, carry);
+                memory.idle();
+            },
+
+            Op::ShiftLeftAccumulator{into} => {
+                let value = into.get(state, memory);
+                let result = value << 1;
+                into.set(result, state, memory);
+                Instruction::set_shift_flags(state, result, value & 0x80 != 0);
+            },
+            Op::ShiftRightAccumulator{into} => {
+                let value = into.get(state, memory);
+                //Arithmetic shift keeps the sign bit
+                let result = (value >> 1) | (value & 0x80);
+                into.set(result, state, memory);
+                Instruction::set_shift_flags(state, result, value & 0x01 != 0);
+            },
+            Op::ShiftRightLogical{into} => {
+                let value = into.get(state, memory);
+                let result = value >> 1;
+                into.set(result, state, memory);
+                Instruction::set_shift_flags(state, result, value & 0x01 != 0);
+            },
+            Op::Swap{into} => {
+                let result = into.get(state, memory).rotate_left(4);
+                into.set(result, state, memory);
+                Instruction::set_logic_flags(state, result, false);
+            },
+
+            Op::Bit{into, bit} => {
+                let value = into.get(state, memory);
+                //Carry flag is left untouched
+                state.assign_flag(cpu::Flag::Zero, value & (1 << bit) == 0);
+                state.reset_flag(cpu::Flag::Negative);
+                state.set_flag(cpu::Flag::HalfCarry);
+            },
+            Op::Reset{into, bit} => {
+                let value = into.get(state, memory);
+                into.set(value & !(1 << bit), state, memory);
+            },
+            Op::Set{into, bit} => {
+                let value = into.get(state, memory);
+                into.set(value | (1 << bit), state, memory);
+            },
+
+            Op::Push{from} => {
+                let value = from.get(state, memory);
+                memory.idle();
+                Instruction::push(state, memory, value);
+            },
+            Op::Pop{into} => {
+                let value = Instruction::pop(state, memory);
+                into.set(value, state, memory);
+            },
 
-        state.set_pc(new_addr);
+            //Taken jumps spend a cycle loading the new PC
+            Op::JumpRelative{amount} => {
+                memory.idle();
+                state.set_pc(default_addr.wrapping_add(*amount as u16));
+            },
+            Op::JumpRelativeIf{condition, amount} => {
+                if state.flag(*condition) {
+                    memory.idle();
+                    state.set_pc(default_addr.wrapping_add(*amount as u16));
+                }
+            },
+            Op::Call{address} => {
+                let address = address.get(state, memory);
+                memory.idle();
+                Instruction::push(state, memory, default_addr);
+                state.set_pc(address);
+            },
+            Op::CallIf{condition, address} => {
+                if state.flag(*condition) {
+                    let address = address.get(state, memory);
+                    memory.idle();
+                    Instruction::push(state, memory, default_addr);
+                    state.set_pc(address);
+                }
+            },
+            Op::Return => {
+                let address = Instruction::pop(state, memory);
+                memory.idle();
+                state.set_pc(address);
+            },
+            //Unlike EI, RETI enables interrupts immediately
+            Op::ReturnInterrupt => {
+                let address = Instruction::pop(state, memory);
+                memory.idle();
+                state.set_pc(address);
+                state.ime = true;
+            },
+            Op::Jump{address} => {
+                //JP HL already has the address in hand
+                if let Data16::Immutable(_) = address {
+                    memory.idle();
+                }
+                let address = address.get(state, memory);
+                state.set_pc(address);
+            },
+            Op::JumpIf{condition, address} => {
+                if state.flag(*condition) {
+                    let address = address.get(state, memory);
+                    memory.idle();
+                    state.set_pc(address);
+                }
+            },
+            Op::Restart{address} => {
+                memory.idle();
+                Instruction::push(state, memory, default_addr);
+                state.set_pc(*address);
+            },
+
+            Op::DecimalAdjust => {
+                let mut value = state.a;
+                let mut carry = state.flag(cpu::Flag::Carry);
+                if state.flag(cpu::Flag::Negative) {
+                    //After a subtraction, only undo the adjustments flagged by the borrows
+                    if carry {
+                        value = value.wrapping_sub(0x60);
+                    }
+                    if state.flag(cpu::Flag::HalfCarry) {
+                        value = value.wrapping_sub(0x06);
+                    }
+                } else {
+                    if carry || value > 0x99 {
+                        value = value.wrapping_add(0x60);
+                        carry = true;
+                    }
+                    if state.flag(cpu::Flag::HalfCarry) || value & 0x0F > 0x09 {
+                        value = value.wrapping_add(0x06);
+                    }
+                }
+                state.a = value;
+
+                state.assign_flag(cpu::Flag::Zero, value == 0);
+                state.reset_flag(cpu::Flag::HalfCarry);
+                state.assign_flag(cpu::Flag::Carry, carry);
+            },
+            Op::Complement => {
+                state.a = !state.a;
+                state.set_flag(cpu::Flag::Negative);
+                state.set_flag(cpu::Flag::HalfCarry);
+            },
+            Op::SetCarryFlag => {
+                state.reset_flag(cpu::Flag::Negative);
+                state.reset_flag(cpu::Flag::HalfCarry);
+                state.set_flag(cpu::Flag::Carry);
+            },
+            Op::ComplementCarryFlag => {
+                let carry = state.flag(cpu::Flag::Carry);
+                state.reset_flag(cpu::Flag::Negative);
+                state.reset_flag(cpu::Flag::HalfCarry);
+                state.assign_flag(cpu::Flag::Carry, !carry);
+            },
+
+            Op::AddStackPointer{amount} => {
+                let result = Instruction::offset_stack_pointer(state, *amount);
+                memory.idle();
+                memory.idle();
+                state.set_sp(result);
+            },
+            Op::LoadStackPointerOffset{amount} => {
+                let result = Instruction::offset_stack_pointer(state, *amount);
+                memory.idle();
+                state.set_hl(result);
+            },
+            Op::ReturnIf{condition} => {
+                //Checking the condition takes a cycle of its own
+                memory.idle();
+                if state.flag(*condition) {
+                    let address = Instruction::pop(state, memory);
+                    memory.idle();
+                    state.set_pc(address);
+                }
+            },
+        }
+
+        //DI in the delay slot cancels the pending enable
+        if enable_interrupts && state.ime_scheduled {
+            state.ime = true;
+            state.ime_scheduled = false;
+        }
+    }
+
+    /// Push the high byte then the low byte, a cycle each
+    fn push<B : Bus>(state : &mut cpu::Registers, memory : &mut CpuBus<B>, value : u16) {
+        let [low, high] = value.to_le_bytes();
+        let sp = state.sp().wrapping_sub(1);
+        memory.write(sp, high);
+        let sp = sp.wrapping_sub(1);
+        memory.write(sp, low);
+        state.set_sp(sp);
+    }
+    /// Pop the low byte then the high byte, a cycle each
+    fn pop<B : Bus>(state : &mut cpu::Registers, memory : &mut CpuBus<B>) -> u16 {
+        let sp = state.sp();
+        let low = memory.read(sp);
+        let high = memory.read(sp.wrapping_add(1));
+        state.set_sp(sp.wrapping_add(2));
+        join_u8(low, high)
+    }
+
+    /// SP plus a signed offset. Flags come from the unsigned add of the low byte.
+    fn offset_stack_pointer(state : &mut cpu::Registers, amount : i8) -> u16 {
+        let sp = state.sp();
+        let offset = amount as u8 as u16;
+
+        state.reset_flag(cpu::Flag::Zero);
+        state.reset_flag(cpu::Flag::Negative);
+        state.assign_flag(cpu::Flag::HalfCarry, (sp & 0x0F) + (offset & 0x0F) > 0x0F);
+        state.assign_flag(cpu::Flag::Carry, (sp & 0xFF) + offset > 0xFF);
+
+        sp.wrapping_add(amount as u16)
+    }
+    /// 8 bit addition, optionally with an incoming carry, updating all four flags
+    fn add_with_flags(state : &mut cpu::Registers, lhs : u8, rhs : u8, carry : bool) -> u8 {
+        let carry = carry as u8;
+        let result = lhs.wrapping_add(rhs).wrapping_add(carry);
+
+        state.assign_flag(cpu::Flag::Zero, result == 0);
+        state.reset_flag(cpu::Flag::Negative);
+        state.assign_flag(cpu::Flag::HalfCarry, (lhs & 0xF) + (rhs & 0xF) + carry > 0xF);
+        state.assign_flag(cpu::Flag::Carry, lhs as u16 + rhs as u16 + carry as u16 > 0xFF);
+
+        result
+    }
+    /// 8 bit subtraction, optionally with an incoming borrow, updating all four flags
+    fn sub_with_flags(state : &mut cpu::Registers, lhs : u8, rhs : u8, carry : bool) -> u8 {
+        let carry = carry as u8;
+        let result = lhs.wrapping_sub(rhs).wrapping_sub(carry);
+
+        state.assign_flag(cpu::Flag::Zero, result == 0);
+        state.set_flag(cpu::Flag::Negative);
+        state.assign_flag(cpu::Flag::HalfCarry, (lhs & 0xF) < (rhs & 0xF) + carry);
+        state.assign_flag(cpu::Flag::Carry, (lhs as u16) < rhs as u16 + carry as u16);
+
+        result
+    }
+    /// Flags for AND/OR/XOR/SWAP. Only AND sets the half carry flag.
+    fn set_logic_flags(state : &mut cpu::Registers, result : u8, half_carry : bool) {
+        state.assign_flag(cpu::Flag::Zero, result == 0);
+        state.reset_flag(cpu::Flag::Negative);
+        state.assign_flag(cpu::Flag::HalfCarry, half_carry);
+        state.reset_flag(cpu::Flag::Carry);
+    }
+    /// Flags for rotates and shifts, with `carry` being the bit shifted out
+    fn set_shift_flags(state : &mut cpu::Registers, result : u8, carry : bool) {
+        state.assign_flag(cpu::Flag::Zero, result == 0);
+        state.reset_flag(cpu::Flag::Negative);
+        state.reset_flag(cpu::Flag::HalfCarry);
+        state.assign_flag(cpu::Flag::Carry, carry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::{Interrupt, InterruptController, IE_ADDRESS};
+    use crate::memory::Memory;
+
+    use std::cell::RefCell;
+
+    /// Mnemonic and size of every base opcode, in opcode order, decoded with operand bytes 0x34 0x12
+    const BASE_TABLE : [(&str, u8); 256] = [
+        //0x00
+        ("NOP", 1),
+        ("LD BC, $1234", 3),
+        ("LD (BC), A", 1),
+        ("INC BC", 1),
+        ("INC B", 1),
+        ("DEC B", 1),
+        ("LD B, $34", 2),
+        ("RLCA", 1),
+        //0x08
+        ("LD ($1234), SP", 3),
+        ("ADD HL, BC", 1),
+        ("LD A, (BC)", 1),
+        ("DEC BC", 1),
+        ("INC C", 1),
+        ("DEC C", 1),
+        ("LD C, $34", 2),
+        ("RRCA", 1),
+        //0x10
+        ("STOP", 2),
+        ("LD DE, $1234", 3),
+        ("LD (DE), A", 1),
+        ("INC DE", 1),
+        ("INC D", 1),
+        ("DEC D", 1),
+        ("LD D, $34", 2),
+        ("RLA", 1),
+        //0x18
+        ("JR  52", 2),
+        ("ADD HL, DE", 1),
+        ("LD A, (DE)", 1),
+        ("DEC DE", 1),
+        ("INC E", 1),
+        ("DEC E", 1),
+        ("LD E, $34", 2),
+        ("RRA", 1),
+        //0x20
+        ("JR NZ 52", 2),
+        ("LD HL, $1234", 3),
+        ("LD (HL+), A", 1),
+        ("INC HL", 1),
+        ("INC H", 1),
+        ("DEC H", 1),
+        ("LD H, $34", 2),
+        ("DAA", 1),
+        //0x28
+        ("JR Z 52", 2),
+        ("ADD HL, HL", 1),
+        ("LD A, (HL+)", 1),
+        ("DEC HL", 1),
+        ("INC L", 1),
+        ("DEC L", 1),
+        ("LD L, $34", 2),
+        ("CPL", 1),
+        //0x30
+        ("JR NC 52", 2),
+        ("LD SP, $1234", 3),
+        ("LD (HL-), A", 1),
+        ("INC SP", 1),
+        ("INC (HL)", 1),
+        ("DEC (HL)", 1),
+        ("LD (HL), $34", 2),
+        ("SCF", 1),
+        //0x38
+        ("JR C 52", 2),
+        ("ADD HL, SP", 1),
+        ("LD A, (HL-)", 1),
+        ("DEC SP", 1),
+        ("INC A", 1),
+        ("DEC A", 1),
+        ("LD A, $34", 2),
+        ("CCF", 1),
+        //0x40
+        ("LD B, B", 1),
+        ("LD B, C", 1),
+        ("LD B, D", 1),
+        ("LD B, E", 1),
+        ("LD B, H", 1),
+        ("LD B, L", 1),
+        ("LD B, (HL)", 1),
+        ("LD B, A", 1),
+        //0x48
+        ("LD C, B", 1),
+        ("LD C, C", 1),
+        ("LD C, D", 1),
+        ("LD C, E", 1),
+        ("LD C, H", 1),
+        ("LD C, L", 1),
+        ("LD C, (HL)", 1),
+        ("LD C, A", 1),
+        //0x50
+        ("LD D, B", 1),
+        ("LD D, C", 1),
+        ("LD D, D", 1),
+        ("LD D, E", 1),
+        ("LD D, H", 1),
+        ("LD D, L", 1),
+        ("LD D, (HL)", 1),
+        ("LD D, A", 1),
+        //0x58
+        ("LD E, B", 1),
+        ("LD E, C", 1),
+        ("LD E, D", 1),
+        ("LD E, E", 1),
+        ("LD E, H", 1),
+        ("LD E, L", 1),
+        ("LD E, (HL)", 1),
+        ("LD E, A", 1),
+        //0x60
+        ("LD H, B", 1),
+        ("LD H, C", 1),
+        ("LD H, D", 1),
+        ("LD H, E", 1),
+        ("LD H, H", 1),
+        ("LD H, L", 1),
+        ("LD H, (HL)", 1),
+        ("LD H, A", 1),
+        //0x68
+        ("LD L, B", 1),
+        ("LD L, C", 1),
+        ("LD L, D", 1),
+        ("LD L, E", 1),
+        ("LD L, H", 1),
+        ("LD L, L", 1),
+        ("LD L, (HL)", 1),
+        ("LD L, A", 1),
+        //0x70
+        ("LD (HL), B", 1),
+        ("LD (HL), C", 1),
+        ("LD (HL), D", 1),
+        ("LD (HL), E", 1),
+        ("LD (HL), H", 1),
+        ("LD (HL), L", 1),
+        ("HALT", 1),
+        ("LD (HL), A", 1),
+        //0x78
+        ("LD A, B", 1),
+        ("LD A, C", 1),
+        ("LD A, D", 1),
+        ("LD A, E", 1),
+        ("LD A, H", 1),
+        ("LD A, L", 1),
+        ("LD A, (HL)", 1),
+        ("LD A, A", 1),
+        //0x80
+        ("ADD A, B", 1),
+        ("ADD A, C", 1),
+        ("ADD A, D", 1),
+        ("ADD A, E", 1),
+        ("ADD A, H", 1),
+        ("ADD A, L", 1),
+        ("ADD A, (HL)", 1),
+        ("ADD A, A", 1),
+        //0x88
+        ("ADC A, B", 1),
+        ("ADC A, C", 1),
+        ("ADC A, D", 1),
+        ("ADC A, E", 1),
+        ("ADC A, H", 1),
+        ("ADC A, L", 1),
+        ("ADC A, (HL)", 1),
+        ("ADC A, A", 1),
+        //0x90
+        ("SUB A, B", 1),
+        ("SUB A, C", 1),
+        ("SUB A, D", 1),
+        ("SUB A, E", 1),
+        ("SUB A, H", 1),
+        ("SUB A, L", 1),
+        ("SUB A, (HL)", 1),
+        ("SUB A, A", 1),
+        //0x98
+        ("SBC A, B", 1),
+        ("SBC A, C", 1),
+        ("SBC A, D", 1),
+        ("SBC A, E", 1),
+        ("SBC A, H", 1),
+        ("SBC A, L", 1),
+        ("SBC A, (HL)", 1),
+        ("SBC A, A", 1),
+        //0xA0
+        ("AND A, B", 1),
+        ("AND A, C", 1),
+        ("AND A, D", 1),
+        ("AND A, E", 1),
+        ("AND A, H", 1),
+        ("AND A, L", 1),
+        ("AND A, (HL)", 1),
+        ("AND A, A", 1),
+        //0xA8
+        ("XOR A, B", 1),
+        ("XOR A, C", 1),
+        ("XOR A, D", 1),
+        ("XOR A, E", 1),
+        ("XOR A, H", 1),
+        ("XOR A, L", 1),
+        ("XOR A, (HL)", 1),
+        ("XOR A, A", 1),
+        //0xB0
+        ("OR  A, B", 1),
+        ("OR  A, C", 1),
+        ("OR  A, D", 1),
+        ("OR  A, E", 1),
+        ("OR  A, H", 1),
+        ("OR  A, L", 1),
+        ("OR  A, (HL)", 1),
+        ("OR  A, A", 1),
+        //0xB8
+        ("CMP A B", 1),
+        ("CMP A C", 1),
+        ("CMP A D", 1),
+        ("CMP A E", 1),
+        ("CMP A H", 1),
+        ("CMP A L", 1),
+        ("CMP A (HL)", 1),
+        ("CMP A A", 1),
+        //0xC0
+        ("RET NZ", 1),
+        ("POP BC", 1),
+        ("JP NZ $1234", 3),
+        ("JP  $1234", 3),
+        ("CALL NZ $1234", 3),
+        ("PUSH BC", 1),
+        ("ADD A, $34", 2),
+        ("RST $00", 1),
+        //0xC8
+        ("RET Z", 1),
+        ("RET", 1),
+        ("JP Z $1234", 3),
+        ("SWAP H", 2),
+        ("CALL Z $1234", 3),
+        ("CALL $1234", 3),
+        ("ADC A, $34", 2),
+        ("RST $08", 1),
+        //0xD0
+        ("RET NC", 1),
+        ("POP DE", 1),
+        ("JP NC $1234", 3),
+        ("ILLEGAL d3", 1),
+        ("CALL NC $1234", 3),
+        ("PUSH DE", 1),
+        ("SUB A, $34", 2),
+        ("RST $10", 1),
+        //0xD8
+        ("RET C", 1),
+        ("RETI", 1),
+        ("JP C $1234", 3),
+        ("ILLEGAL db", 1),
+        ("CALL C $1234", 3),
+        ("ILLEGAL dd", 1),
+        ("SBC A, $34", 2),
+        ("RST $18", 1),
+        //0xE0
+        ("LD ($FF00 + 34), A", 2),
+        ("POP HL", 1),
+        ("LD ($FF00 + C), A", 1),
+        ("ILLEGAL e3", 1),
+        ("ILLEGAL e4", 1),
+        ("PUSH HL", 1),
+        ("AND A, $34", 2),
+        ("RST $20", 1),
+        //0xE8
+        ("ADD SP, 52", 2),
+        ("JP  HL", 1),
+        ("LD ($1234), A", 3),
+        ("ILLEGAL eb", 1),
+        ("ILLEGAL ec", 1),
+        ("ILLEGAL ed", 1),
+        ("XOR A, $34", 2),
+        ("RST $28", 1),
+        //0xF0
+        ("LD A, ($FF00 + 34)", 2),
+        ("POP AF", 1),
+        ("LD A, ($FF00 + C)", 1),
+        ("DI", 1),
+        ("ILLEGAL f4", 1),
+        ("PUSH AF", 1),
+        ("OR  A, $34", 2),
+        ("RST $30", 1),
+        //0xF8
+        ("LD HL, SP + 52", 2),
+        ("LD SP, HL", 1),
+        ("LD A, ($1234)", 3),
+        ("EI", 1),
+        ("ILLEGAL fc", 1),
+        ("ILLEGAL fd", 1),
+        ("CMP A $34", 2),
+        ("RST $38", 1),
+    ];
+
+    /// Mnemonic, size and cycles of every CB prefixed opcode, in opcode order
+    const EXTENDED_TABLE : [(&str, u8, u8); 256] = [
+        //0xCB00
+        ("RLC B", 2, 8),
+        ("RLC C", 2, 8),
+        ("RLC D", 2, 8),
+        ("RLC E", 2, 8),
+        ("RLC H", 2, 8),
+        ("RLC L", 2, 8),
+        ("RLC (HL)", 2, 16),
+        ("RLC A", 2, 8),
+        //0xCB08
+        ("RRC B", 2, 8),
+        ("RRC C", 2, 8),
+        ("RRC D", 2, 8),
+        ("RRC E", 2, 8),
+        ("RRC H", 2, 8),
+        ("RRC L", 2, 8),
+        ("RRC (HL)", 2, 16),
+        ("RRC A", 2, 8),
+        //0xCB10
+        ("RL B", 2, 8),
+        ("RL C", 2, 8),
+        ("RL D", 2, 8),
+        ("RL E", 2, 8),
+        ("RL H", 2, 8),
+        ("RL L", 2, 8),
+        ("RL (HL)", 2, 16),
+        ("RL A", 2, 8),
+        //0xCB18
+        ("RR B", 2, 8),
+        ("RR C", 2, 8),
+        ("RR D", 2, 8),
+        ("RR E", 2, 8),
+        ("RR H", 2, 8),
+        ("RR L", 2, 8),
+        ("RR (HL)", 2, 16),
+        ("RR A", 2, 8),
+        //0xCB20
+        ("SLA B", 2, 8),
+        ("SLA C", 2, 8),
+        ("SLA D", 2, 8),
+        ("SLA E", 2, 8),
+        ("SLA H", 2, 8),
+        ("SLA L", 2, 8),
+        ("SLA (HL)", 2, 16),
+        ("SLA A", 2, 8),
+        //0xCB28
+        ("SRA B", 2, 8),
+        ("SRA C", 2, 8),
+        ("SRA D", 2, 8),
+        ("SRA E", 2, 8),
+        ("SRA H", 2, 8),
+        ("SRA L", 2, 8),
+        ("SRA (HL)", 2, 16),
+        ("SRA A", 2, 8),
+        //0xCB30
+        ("SWAP B", 2, 8),
+        ("SWAP C", 2, 8),
+        ("SWAP D", 2, 8),
+        ("SWAP E", 2, 8),
+        ("SWAP H", 2, 8),
+        ("SWAP L", 2, 8),
+        ("SWAP (HL)", 2, 16),
+        ("SWAP A", 2, 8),
+        //0xCB38
+        ("SRL B", 2, 8),
+        ("SRL C", 2, 8),
+        ("SRL D", 2, 8),
+        ("SRL E", 2, 8),
+        ("SRL H", 2, 8),
+        ("SRL L", 2, 8),
+        ("SRL (HL)", 2, 16),
+        ("SRL A", 2, 8),
+        //0xCB40
+        ("BIT 0, B", 2, 8),
+        ("BIT 0, C", 2, 8),
+        ("BIT 0, D", 2, 8),
+        ("BIT 0, E", 2, 8),
+        ("BIT 0, H", 2, 8),
+        ("BIT 0, L", 2, 8),
+        ("BIT 0, (HL)", 2, 12),
+        ("BIT 0, A", 2, 8),
+        //0xCB48
+        ("BIT 1, B", 2, 8),
+        ("BIT 1, C", 2, 8),
+        ("BIT 1, D", 2, 8),
+        ("BIT 1, E", 2, 8),
+        ("BIT 1, H", 2, 8),
+        ("BIT 1, L", 2, 8),
+        ("BIT 1, (HL)", 2, 12),
+        ("BIT 1, A", 2, 8),
+        //0xCB50
+        ("BIT 2, B", 2, 8),
+        ("BIT 2, C", 2, 8),
+        ("BIT 2, D", 2, 8),
+        ("BIT 2, E", 2, 8),
+        ("BIT 2, H", 2, 8),
+        ("BIT 2, L", 2, 8),
+        ("BIT 2, (HL)", 2, 12),
+        ("BIT 2, A", 2, 8),
+        //0xCB58
+        ("BIT 3, B", 2, 8),
+        ("BIT 3, C", 2, 8),
+        ("BIT 3, D", 2, 8),
+        ("BIT 3, E", 2, 8),
+        ("BIT 3, H", 2, 8),
+        ("BIT 3, L", 2, 8),
+        ("BIT 3, (HL)", 2, 12),
+        ("BIT 3, A", 2, 8),
+        //0xCB60
+        ("BIT 4, B", 2, 8),
+        ("BIT 4, C", 2, 8),
+        ("BIT 4, D", 2, 8),
+        ("BIT 4, E", 2, 8),
+        ("BIT 4, H", 2, 8),
+        ("BIT 4, L", 2, 8),
+        ("BIT 4, (HL)", 2, 12),
+        ("BIT 4, A", 2, 8),
+        //0xCB68
+        ("BIT 5, B", 2, 8),
+        ("BIT 5, C", 2, 8),
+        ("BIT 5, D", 2, 8),
+        ("BIT 5, E", 2, 8),
+        ("BIT 5, H", 2, 8),
+        ("BIT 5, L", 2, 8),
+        ("BIT 5, (HL)", 2, 12),
+        ("BIT 5, A", 2, 8),
+        //0xCB70
+        ("BIT 6, B", 2, 8),
+        ("BIT 6, C", 2, 8),
+        ("BIT 6, D", 2, 8),
+        ("BIT 6, E", 2, 8),
+        ("BIT 6, H", 2, 8),
+        ("BIT 6, L", 2, 8),
+        ("BIT 6, (HL)", 2, 12),
+        ("BIT 6, A", 2, 8),
+        //0xCB78
+        ("BIT 7, B", 2, 8),
+        ("BIT 7, C", 2, 8),
+        ("BIT 7, D", 2, 8),
+        ("BIT 7, E", 2, 8),
+        ("BIT 7, H", 2, 8),
+        ("BIT 7, L", 2, 8),
+        ("BIT 7, (HL)", 2, 12),
+        ("BIT 7, A", 2, 8),
+        //0xCB80
+        ("RES 0, B", 2, 8),
+        ("RES 0, C", 2, 8),
+        ("RES 0, D", 2, 8),
+        ("RES 0, E", 2, 8),
+        ("RES 0, H", 2, 8),
+        ("RES 0, L", 2, 8),
+        ("RES 0, (HL)", 2, 16),
+        ("RES 0, A", 2, 8),
+        //0xCB88
+        ("RES 1, B", 2, 8),
+        ("RES 1, C", 2, 8),
+        ("RES 1, D", 2, 8),
+        ("RES 1, E", 2, 8),
+        ("RES 1, H", 2, 8),
+        ("RES 1, L", 2, 8),
+        ("RES 1, (HL)", 2, 16),
+        ("RES 1, A", 2, 8),
+        //0xCB90
+        ("RES 2, B", 2, 8),
+        ("RES 2, C", 2, 8),
+        ("RES 2, D", 2, 8),
+        ("RES 2, E", 2, 8),
+        ("RES 2, H", 2, 8),
+        ("RES 2, L", 2, 8),
+        ("RES 2, (HL)", 2, 16),
+        ("RES 2, A", 2, 8),
+        //0xCB98
+        ("RES 3, B", 2, 8),
+        ("RES 3, C", 2, 8),
+        ("RES 3, D", 2, 8),
+        ("RES 3, E", 2, 8),
+        ("RES 3, H", 2, 8),
+        ("RES 3, L", 2, 8),
+        ("RES 3, (HL)", 2, 16),
+        ("RES 3, A", 2, 8),
+        //0xCBA0
+        ("RES 4, B", 2, 8),
+        ("RES 4, C", 2, 8),
+        ("RES 4, D", 2, 8),
+        ("RES 4, E", 2, 8),
+        ("RES 4, H", 2, 8),
+        ("RES 4, L", 2, 8),
+        ("RES 4, (HL)", 2, 16),
+        ("RES 4, A", 2, 8),
+        //0xCBA8
+        ("RES 5, B", 2, 8),
+        ("RES 5, C", 2, 8),
+        ("RES 5, D", 2, 8),
+        ("RES 5, E", 2, 8),
+        ("RES 5, H", 2, 8),
+        ("RES 5, L", 2, 8),
+        ("RES 5, (HL)", 2, 16),
+        ("RES 5, A", 2, 8),
+        //0xCBB0
+        ("RES 6, B", 2, 8),
+        ("RES 6, C", 2, 8),
+        ("RES 6, D", 2, 8),
+        ("RES 6, E", 2, 8),
+        ("RES 6, H", 2, 8),
+        ("RES 6, L", 2, 8),
+        ("RES 6, (HL)", 2, 16),
+        ("RES 6, A", 2, 8),
+        //0xCBB8
+        ("RES 7, B", 2, 8),
+        ("RES 7, C", 2, 8),
+        ("RES 7, D", 2, 8),
+        ("RES 7, E", 2, 8),
+        ("RES 7, H", 2, 8),
+        ("RES 7, L", 2, 8),
+        ("RES 7, (HL)", 2, 16),
+        ("RES 7, A", 2, 8),
+        //0xCBC0
+        ("SET 0, B", 2, 8),
+        ("SET 0, C", 2, 8),
+        ("SET 0, D", 2, 8),
+        ("SET 0, E", 2, 8),
+        ("SET 0, H", 2, 8),
+        ("SET 0, L", 2, 8),
+        ("SET 0, (HL)", 2, 16),
+        ("SET 0, A", 2, 8),
+        //0xCBC8
+        ("SET 1, B", 2, 8),
+        ("SET 1, C", 2, 8),
+        ("SET 1, D", 2, 8),
+        ("SET 1, E", 2, 8),
+        ("SET 1, H", 2, 8),
+        ("SET 1, L", 2, 8),
+        ("SET 1, (HL)", 2, 16),
+        ("SET 1, A", 2, 8),
+        //0xCBD0
+        ("SET 2, B", 2, 8),
+        ("SET 2, C", 2, 8),
+        ("SET 2, D", 2, 8),
+        ("SET 2, E", 2, 8),
+        ("SET 2, H", 2, 8),
+        ("SET 2, L", 2, 8),
+        ("SET 2, (HL)", 2, 16),
+        ("SET 2, A", 2, 8),
+        //0xCBD8
+        ("SET 3, B", 2, 8),
+        ("SET 3, C", 2, 8),
+        ("SET 3, D", 2, 8),
+        ("SET 3, E", 2, 8),
+        ("SET 3, H", 2, 8),
+        ("SET 3, L", 2, 8),
+        ("SET 3, (HL)", 2, 16),
+        ("SET 3, A", 2, 8),
+        //0xCBE0
+        ("SET 4, B", 2, 8),
+        ("SET 4, C", 2, 8),
+        ("SET 4, D", 2, 8),
+        ("SET 4, E", 2, 8),
+        ("SET 4, H", 2, 8),
+        ("SET 4, L", 2, 8),
+        ("SET 4, (HL)", 2, 16),
+        ("SET 4, A", 2, 8),
+        //0xCBE8
+        ("SET 5, B", 2, 8),
+        ("SET 5, C", 2, 8),
+        ("SET 5, D", 2, 8),
+        ("SET 5, E", 2, 8),
+        ("SET 5, H", 2, 8),
+        ("SET 5, L", 2, 8),
+        ("SET 5, (HL)", 2, 16),
+        ("SET 5, A", 2, 8),
+        //0xCBF0
+        ("SET 6, B", 2, 8),
+        ("SET 6, C", 2, 8),
+        ("SET 6, D", 2, 8),
+        ("SET 6, E", 2, 8),
+        ("SET 6, H", 2, 8),
+        ("SET 6, L", 2, 8),
+        ("SET 6, (HL)", 2, 16),
+        ("SET 6, A", 2, 8),
+        //0xCBF8
+        ("SET 7, B", 2, 8),
+        ("SET 7, C", 2, 8),
+        ("SET 7, D", 2, 8),
+        ("SET 7, E", 2, 8),
+        ("SET 7, H", 2, 8),
+        ("SET 7, L", 2, 8),
+        ("SET 7, (HL)", 2, 16),
+        ("SET 7, A", 2, 8),
+    ];
+
+    /// Machine cycles of every unprefixed opcode, from mooneye's instr_timing test.
+    /// Conditional branches are listed not taken, and opcodes it doesn't time are 0.
+    const INSTRUCTION_TIMINGS : [u8; 256] = [
+        1,3,2,2,1,1,2,1,5,2,2,2,1,1,2,1,
+        0,3,2,2,1,1,2,1,3,2,2,2,1,1,2,1,
+        2,3,2,2,1,1,2,1,2,2,2,2,1,1,2,1,
+        2,3,2,2,3,3,3,1,2,2,2,2,1,1,2,1,
+        1,1,1,1,1,1,2,1,1,1,1,1,1,1,2,1,
+        1,1,1,1,1,1,2,1,1,1,1,1,1,1,2,1,
+        1,1,1,1,1,1,2,1,1,1,1,1,1,1,2,1,
+        2,2,2,2,2,2,0,2,1,1,1,1,1,1,2,1,
+        1,1,1,1,1,1,2,1,1,1,1,1,1,1,2,1,
+        1,1,1,1,1,1,2,1,1,1,1,1,1,1,2,1,
+        1,1,1,1,1,1,2,1,1,1,1,1,1,1,2,1,
+        1,1,1,1,1,1,2,1,1,1,1,1,1,1,2,1,
+        2,3,3,4,3,4,2,4,2,4,3,0,3,6,2,4,
+        2,3,3,0,3,4,2,4,2,4,3,0,3,0,2,4,
+        3,3,2,0,0,4,2,4,4,1,4,0,0,0,2,4,
+        3,3,2,1,0,4,2,4,3,2,4,1,0,0,2,4,
+    ];
+
+    /// Cycle an access landed on, its address, and the data if it was a write
+    type Access = (u32, u16, Option<u8>);
+
+    /// Flat RAM which logs the cycle each access lands on, with the data for writes
+    struct TimingBus {
+        memory : Vec<u8>,
+        interrupts : InterruptController,
+        cycles : u32,
+        accesses : RefCell<Vec<Access>>,
+    }
+
+    impl TimingBus {
+        fn new(program : &[u8]) -> Self {
+            let mut memory = vec![0; 0x10000];
+            memory[0xC000..0xC000 + program.len()].copy_from_slice(program);
+            TimingBus { memory, interrupts : InterruptController::default(), cycles : 0, accesses : RefCell::default() }
+        }
+    }
+
+    impl Bus for TimingBus {
+        fn read(&self, addr : u16) -> u8 {
+            self.accesses.borrow_mut().push((self.cycles, addr, None));
+            self.memory[addr as usize]
+        }
+        fn write(&mut self, addr : u16, data : u8) {
+            self.accesses.borrow_mut().push((self.cycles, addr, Some(data)));
+            self.memory[addr as usize] = data;
+            if addr == IE_ADDRESS {
+                self.interrupts.write_enable(data);
+            }
+        }
+        fn tick(&mut self, cycles : u8) -> u32 {
+            self.cycles += cycles as u32;
+            0
+        }
+        fn interrupts(&self) -> &InterruptController {
+            &self.interrupts
+        }
+        fn interrupts_mut(&mut self) -> &mut InterruptController {
+            &mut self.interrupts
+        }
+        fn stop(&mut self) -> bool {
+            false
+        }
+    }
+
+    fn registers(flags : u8) -> cpu::Registers {
+        let mut state = cpu::Registers { pc : 0xC000, sp : 0xD000, flags, ..Default::default() };
+        state.set_hl(0xC100);
+        state
+    }
+
+    /// Run the instruction at the start of `program`, returning the cycles taken and the bus accesses
+    fn run(state : &mut cpu::Registers, program : &[u8]) -> (u32, Vec<Access>) {
+        let mut bus = TimingBus::new(program);
+        let cycles = cpu::step(state, &mut bus);
+        assert_eq!(cycles, bus.cycles, "cycles returned and ticked");
+        (cycles, bus.accesses.into_inner())
+    }
+
+    #[test]
+    fn opcodes_match_instr_timing() {
+        for (opcode, timing) in INSTRUCTION_TIMINGS.iter().enumerate() {
+            if *timing == 0 {
+                continue;
+            }
+            let instruction = Instruction::from_bytes(0, &[opcode as u8, 0, 0])
+                .expect("three bytes always decode");
+            assert_eq!(instruction.cycles, timing * 4, "cycles of {:02X} ({})", opcode, instruction.op);
+
+            //Every condition is taken one way or the other between these
+            for flags in [0x00, 0xF0] {
+                let mut state = registers(flags);
+                let taken = match &instruction.op {
+                    Op::JumpRelativeIf{condition, ..} => state.flag(*condition).then_some(3),
+                    Op::JumpIf{condition, ..} => state.flag(*condition).then_some(4),
+                    Op::CallIf{condition, ..} => state.flag(*condition).then_some(6),
+                    Op::ReturnIf{condition} => state.flag(*condition).then_some(5),
+                    _ => None,
+                };
+                let (cycles, _) = run(&mut state, &[opcode as u8, 0, 0]);
+                assert_eq!(cycles, taken.unwrap_or(*timing) as u32 * 4, "cycles running {:02X} ({}) with flags {:02X}", opcode, instruction.op, flags);
+            }
+        }
+        for (opcode, (mnemonic, _, cycles)) in EXTENDED_TABLE.iter().enumerate() {
+            let (taken, _) = run(&mut registers(0), &[0xCB, opcode as u8]);
+            assert_eq!(taken, *cycles as u32, "cycles running {}", mnemonic);
+        }
+    }
+
+    #[test]
+    fn base_opcodes_match_table() {
+        for (opcode, (mnemonic, size)) in BASE_TABLE.iter().enumerate() {
+            let instruction = Instruction::from_bytes(0, &[opcode as u8, 0x34, 0x12])
+                .expect("three bytes always decode");
+
+            assert_eq!(instruction.op.to_string(), *mnemonic, "mnemonic of {:02X}", opcode);
+            assert_eq!(instruction.size, *size, "size of {:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn illegal_opcodes_lock_up() {
+        for opcode in [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD] {
+            let instruction = Instruction::from_bytes(0, &[opcode, 0, 0]).expect("three bytes always decode");
+            assert!(matches!(instruction.op, Op::Illegal(illegal) if illegal == opcode), "{:02X}", opcode);
+
+            //PC stays on the opcode and nothing runs after, not even interrupts
+            let mut state = cpu::Registers { pc : 0xC000, sp : 0xD000, ime : true, ..Default::default() };
+            let mut memory = Memory::default();
+            memory.write(0xC000, opcode);
+            cpu::step(&mut state, &mut memory);
+            assert_eq!(state.mode, cpu::Mode::Locked, "{:02X}", opcode);
+            memory.interrupts.write_enable(Interrupt::VBlank.mask());
+            memory.interrupts.request(Interrupt::VBlank);
+            assert_eq!(cpu::step(&mut state, &mut memory), 4);
+            assert_eq!(state.pc(), 0xC000, "{:02X}", opcode);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn extended_opcodes_match_table() {
+        for (opcode, (mnemonic, size, cycles)) in EXTENDED_TABLE.iter().enumerate() {
+            let instruction = Instruction::from_bytes(0, &[0xCB, opcode as u8])
+                .expect("CB opcodes always decode");
+
+            assert_eq!(instruction.op.to_string(), *mnemonic, "mnemonic of CB {:02X}", opcode);
+            assert_eq!(instruction.size, *size, "size of CB {:02X}", opcode);
+            assert_eq!(instruction.cycles, *cycles, "cycles of CB {:02X}", opcode);
+        }
+    }
+
+    /// Name, program, then every access as the cycle it lands on, address and data written. From mem_timing,
+    /// along with the pushes and pops it doesn't cover, run with HL at 0xC100 and SP at 0xD000.
+    const MEMORY_ACCESSES : [(&str, &[u8], &[Access]); 20] = [
+        ("LD (HL),A", &[0x77], &[(4, 0xC000, None), (8, 0xC100, Some(0x00))]),
+        ("LD A,(HL+)", &[0x2A], &[(4, 0xC000, None), (8, 0xC100, None)]),
+        ("LD (HL),n", &[0x36, 0x5A], &[(4, 0xC000, None), (8, 0xC001, None), (12, 0xC100, Some(0x5A))]),
+        ("LDH (n),A", &[0xE0, 0x80], &[(4, 0xC000, None), (8, 0xC001, None), (12, 0xFF80, Some(0x00))]),
+        ("LDH A,(n)", &[0xF0, 0x80], &[(4, 0xC000, None), (8, 0xC001, None), (12, 0xFF80, None)]),
+        ("LD (nn),A", &[0xEA, 0x00, 0xC2], &[(4, 0xC000, None), (8, 0xC001, None), (12, 0xC002, None), (16, 0xC200, Some(0x00))]),
+        ("LD A,(nn)", &[0xFA, 0x00, 0xC2], &[(4, 0xC000, None), (8, 0xC001, None), (12, 0xC002, None), (16, 0xC200, None)]),
+        ("LD (nn),SP", &[0x08, 0x00, 0xC2], &[
+            (4, 0xC000, None), (8, 0xC001, None), (12, 0xC002, None), (16, 0xC200, Some(0x00)), (20, 0xC201, Some(0xD0)),
+        ]),
+        ("INC (HL)", &[0x34], &[(4, 0xC000, None), (8, 0xC100, None), (12, 0xC100, Some(0x01))]),
+        ("DEC (HL)", &[0x35], &[(4, 0xC000, None), (8, 0xC100, None), (12, 0xC100, Some(0xFF))]),
+        ("BIT 0,(HL)", &[0xCB, 0x46], &[(4, 0xC000, None), (8, 0xC001, None), (12, 0xC100, None)]),
+        ("SET 0,(HL)", &[0xCB, 0xC6], &[(4, 0xC000, None), (8, 0xC001, None), (12, 0xC100, None), (16, 0xC100, Some(0x01))]),
+        ("RES 0,(HL)", &[0xCB, 0x86], &[(4, 0xC000, None), (8, 0xC001, None), (12, 0xC100, None), (16, 0xC100, Some(0x00))]),
+        ("SWAP (HL)", &[0xCB, 0x36], &[(4, 0xC000, None), (8, 0xC001, None), (12, 0xC100, None), (16, 0xC100, Some(0x00))]),
+        //Pushes spend a cycle before writing, high byte first
+        ("PUSH HL", &[0xE5], &[(4, 0xC000, None), (12, 0xCFFF, Some(0xC1)), (16, 0xCFFE, Some(0x00))]),
+        ("RST 38", &[0xFF], &[(4, 0xC000, None), (12, 0xCFFF, Some(0xC0)), (16, 0xCFFE, Some(0x01))]),
+        ("CALL nn", &[0xCD, 0x34, 0x12], &[
+            (4, 0xC000, None), (8, 0xC001, None), (12, 0xC002, None), (20, 0xCFFF, Some(0xC0)), (24, 0xCFFE, Some(0x03)),
+        ]),
+        ("CALL NZ,nn", &[0xC4, 0x34, 0x12], &[
+            (4, 0xC000, None), (8, 0xC001, None), (12, 0xC002, None), (20, 0xCFFF, Some(0xC0)), (24, 0xCFFE, Some(0x03)),
+        ]),
+        //Pops read low byte first, returns then spend a cycle jumping
+        ("POP BC", &[0xC1], &[(4, 0xC000, None), (8, 0xD000, None), (12, 0xD001, None)]),
+        ("RET", &[0xC9], &[(4, 0xC000, None), (8, 0xD000, None), (12, 0xD001, None)]),
+    ];
+
+    #[test]
+    fn accesses_match_mem_timing() {
+        for (name, program, expected) in MEMORY_ACCESSES {
+            let (_, accesses) = run(&mut registers(0), program);
+            assert_eq!(accesses, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn dispatch_pushes_in_hardware_order() {
+        //Dispatch waits two cycles, pushes PC and spends a cycle jumping
+        let mut state = registers(0);
+        state.ime = true;
+        let mut bus = TimingBus::new(&[]);
+        bus.interrupts.write_enable(Interrupt::Timer.mask());
+        bus.interrupts.request(Interrupt::Timer);
+        assert_eq!(cpu::step(&mut state, &mut bus), 20);
+        assert_eq!(bus.accesses.into_inner(), [(12, 0xCFFF, Some(0xC0)), (16, 0xCFFE, Some(0x00))]);
+        assert_eq!(state.pc(), Interrupt::Timer.vector());
+    }
+
+    #[test]
+    fn pushing_over_ie_cancels_dispatch() {
+        //The high byte of PC lands on IE, turning off the interrupt before its vector is picked
+        let mut state = registers(0);
+        state.ime = true;
+        state.set_pc(0x0200);
+        state.set_sp(0x0000);
+        let mut bus = TimingBus::new(&[]);
+        bus.interrupts.write_enable(Interrupt::VBlank.mask());
+        bus.interrupts.request(Interrupt::VBlank);
+        cpu::step(&mut state, &mut bus);
+        assert_eq!(state.pc(), 0x0000);
+        assert_eq!(bus.interrupts.requested(), Interrupt::VBlank.mask());
+    }
+
+    /// Run `program` from WRAM until PC passes its end
+    fn execute_program(state : &mut cpu::Registers, memory : &mut Memory, program : &[u8]) {
+        for (offset, byte) in program.iter().enumerate() {
+            memory.write(0xC000 + offset as u16, *byte);
+        }
+        state.set_pc(0xC000);
+        while state.pc() < 0xC000 + program.len() as u16 {
+            cpu::step(state, memory);
+        }
+    }
+
+    /// Name, program, A, B and flags going in, then A and flags coming out
+    type AluCase = (&'static str, &'static [u8], u8, u8, u8, u8, u8);
+
+    const ALU_CASES : [AluCase; 30] = [
+        ("ADD to zero", &[0x80], 0x3A, 0xC6, 0x00, 0x00, 0xB0),
+        ("ADD half carry", &[0x80], 0x0F, 0x01, 0x00, 0x10, 0x20),
+        ("ADC carry in", &[0x88], 0xE1, 0x0F, 0x10, 0xF1, 0x20),
+        ("ADC to zero", &[0x88], 0xE1, 0x1E, 0x10, 0x00, 0xB0),
+        ("SUB to zero", &[0x90], 0x3E, 0x3E, 0x00, 0x00, 0xC0),
+        ("SUB half borrow", &[0x90], 0x3E, 0x0F, 0x00, 0x2F, 0x60),
+        ("SUB borrow", &[0x90], 0x3E, 0x40, 0x00, 0xFE, 0x50),
+        ("SBC carry in", &[0x98], 0x3B, 0x2A, 0x10, 0x10, 0x40),
+        ("SBC both borrows", &[0x98], 0x3B, 0x4F, 0x10, 0xEB, 0x70),
+        ("CP leaves A", &[0xB8], 0x3C, 0x2F, 0x00, 0x3C, 0x60),
+        ("CP equal", &[0xB8], 0x3C, 0x3C, 0x00, 0x3C, 0xC0),
+        ("AND sets H", &[0xA0], 0x5A, 0x3F, 0x10, 0x1A, 0x20),
+        ("OR", &[0xB0], 0x00, 0x00, 0x70, 0x00, 0x80),
+        ("XOR A", &[0xAF], 0x5A, 0x00, 0x70, 0x00, 0x80),
+        ("INC keeps C", &[0x3C], 0x0F, 0x00, 0x10, 0x10, 0x30),
+        ("DEC to zero", &[0x3D], 0x01, 0x00, 0x00, 0x00, 0xC0),
+        ("DEC half borrow", &[0x3D], 0x10, 0x00, 0x00, 0x0F, 0x60),
+        ("DAA after ADD", &[0x80, 0x27], 0x45, 0x38, 0x00, 0x83, 0x00),
+        ("DAA after SUB", &[0x90, 0x27], 0x83, 0x38, 0x00, 0x45, 0x40),
+        ("DAA carry out", &[0x80, 0x27], 0x99, 0x01, 0x00, 0x00, 0x90),
+        ("RLCA clears Z", &[0x07], 0x00, 0x00, 0x80, 0x00, 0x00),
+        ("RLCA carry", &[0x07], 0x85, 0x00, 0x00, 0x0B, 0x10),
+        ("RLC A sets Z", &[0xCB, 0x07], 0x00, 0x00, 0x00, 0x00, 0x80),
+        ("RRA through carry", &[0x1F], 0x01, 0x00, 0x10, 0x80, 0x10),
+        ("SWAP", &[0xCB, 0x37], 0xF0, 0x00, 0x10, 0x0F, 0x00),
+        ("SWAP zero", &[0xCB, 0x37], 0x00, 0x00, 0x00, 0x00, 0x80),
+        ("BIT clear keeps C", &[0xCB, 0x7F], 0x7F, 0x00, 0x10, 0x7F, 0xB0),
+        ("BIT set", &[0xCB, 0x7F], 0x80, 0x00, 0x40, 0x80, 0x20),
+        ("CPL", &[0x2F], 0x35, 0x00, 0x90, 0xCA, 0xF0),
+        ("CCF", &[0x3F], 0x00, 0x00, 0xF0, 0x00, 0x80),
+    ];
+
+    #[test]
+    fn alu_results_and_flags() {
+        for (name, program, a, b, flags, result, result_flags) in ALU_CASES {
+            let mut state = cpu::Registers { a, b, flags, ..Default::default() };
+            execute_program(&mut state, &mut Memory::default(), program);
+            assert_eq!(state.a, result, "result of {}", name);
+            assert_eq!(state.flags, result_flags, "flags of {}", name);
+        }
+    }
+
+    #[test]
+    fn stack_pointer_offsets_and_pop_af() {
+        let mut memory = Memory::default();
+
+        //Flags come from the low byte, and Z is always cleared
+        let mut state = cpu::Registers { sp : 0x0FF8, flags : 0x80, ..Default::default() };
+        execute_program(&mut state, &mut memory, &[0xE8, 0x08]);
+        assert_eq!((state.sp(), state.flags), (0x1000, 0x30));
+        let mut state = cpu::Registers { sp : 0x0000, ..Default::default() };
+        execute_program(&mut state, &mut memory, &[0xE8, 0xFF]);
+        assert_eq!((state.sp(), state.flags), (0xFFFF, 0x00));
+        let mut state = cpu::Registers { sp : 0xFFFF, ..Default::default() };
+        execute_program(&mut state, &mut memory, &[0xF8, 0x01]);
+        assert_eq!((state.hl(), state.sp(), state.flags), (0x0000, 0xFFFF, 0x30));
+
+        //The low nibble of F doesn't exist
+        memory.write(0xD000, 0xFF);
+        memory.write(0xD001, 0x12);
+        let mut state = cpu::Registers { sp : 0xD000, ..Default::default() };
+        execute_program(&mut state, &mut memory, &[0xF1]);
+        assert_eq!((state.af(), state.sp()), (0x12F0, 0xD002));
+    }
+}
@@ -0,0 +1,189 @@
+use crate::cpu;
+use crate::memory::Bus;
+
+use std::fmt::Display;
+
+/// Interrupt sources, in priority order. The discriminant is the bit index in IE and IF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank = 0, LcdStat = 1, Timer = 2, Serial = 3, Joypad = 4,
+}
+
+impl Interrupt {
+    const ALL : [Interrupt; 5] = [
+        Interrupt::VBlank, Interrupt::LcdStat, Interrupt::Timer, Interrupt::Serial, Interrupt::Joypad,
+    ];
+
+    pub fn mask(self) -> u8 {
+        1 << self as u8
+    }
+    /// Address the CPU jumps to when servicing this interrupt
+    pub fn vector(self) -> u16 {
+        0x40 + 8 * self as u16
+    }
+    /// The highest priority interrupt set in `flags`, if any
+    pub fn highest_priority(flags : u8) -> Option<Interrupt> {
+        Interrupt::ALL.into_iter().find(|interrupt| flags & interrupt.mask() != 0)
+    }
+}
+
+impl Display for Interrupt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Interrupt::VBlank => write!(f, "VBlank"),
+            Interrupt::LcdStat => write!(f, "STAT"),
+            Interrupt::Timer => write!(f, "Timer"),
+            Interrupt::Serial => write!(f, "Serial"),
+            Interrupt::Joypad => write!(f, "Joypad"),
+        }
+    }
+}
+
+pub const IF_ADDRESS : u16 = 0xFF0F;
+pub const IE_ADDRESS : u16 = 0xFFFF;
+
+/// Only the bottom five bits of IE and IF are backed by a source
+const INTERRUPT_BITS : u8 = 0b0001_1111;
+
+/// The IE and IF registers. Peripherals raise interrupts through `request`.
+#[derive(Default)]
+pub struct InterruptController {
+    enable : u8,
+    flags : u8,
+}
+
+impl InterruptController {
+    pub fn request(&mut self, interrupt : Interrupt) {
+        self.flags |= interrupt.mask();
+    }
+    pub fn acknowledge(&mut self, interrupt : Interrupt) {
+        self.flags &= !interrupt.mask();
+    }
+    /// Interrupts which are both requested and enabled
+    pub fn pending(&self) -> u8 {
+        self.enable & self.flags & INTERRUPT_BITS
+    }
+    /// Interrupts which are requested, regardless of IE
+    pub fn requested(&self) -> u8 {
+        self.flags & INTERRUPT_BITS
+    }
+    pub fn read_flags(&self) -> u8 {
+        //Unused upper bits of IF always read as set
+        self.flags | !INTERRUPT_BITS
+    }
+    pub fn write_flags(&mut self, value : u8) {
+        self.flags = value & INTERRUPT_BITS;
+    }
+    pub fn read_enable(&self) -> u8 {
+        //IE is a full read/write byte, even though only five bits do anything
+        self.enable
+    }
+    pub fn write_enable(&mut self, value : u8) {
+        self.enable = value;
+    }
+}
+
+/// Jump to the highest priority pending interrupt if IME allows it, returning whether one was dispatched.
+/// Takes two wait states, the two pushes and the jump.
+pub fn service<B : Bus>(state : &mut cpu::Registers, memory : &mut cpu::CpuBus<B>) -> bool {
+    if !state.ime || memory.interrupts().pending() == 0 {
+        return false;
+    }
+    state.ime = false;
+
+    memory.idle();
+    memory.idle();
+    //EI then HALT with an interrupt waiting returns to the HALT, rather than running into the HALT bug
+    let pc = if std::mem::take(&mut state.halt_bug) { state.pc().wrapping_sub(1) } else { state.pc() };
+    let [low, high] = pc.to_le_bytes();
+    let sp = state.sp().wrapping_sub(1);
+    memory.write(sp, high);
+    //The vector is picked after the high byte is pushed, which can land on IE and cancel the interrupt
+    let interrupt = Interrupt::highest_priority(memory.interrupts().pending());
+    let sp = sp.wrapping_sub(1);
+    memory.write(sp, low);
+    state.set_sp(sp);
+
+    let vector = match interrupt {
+        Some(interrupt) => {
+            memory.interrupts_mut().acknowledge(interrupt);
+            interrupt.vector()
+        },
+        None => 0x0000,
+    };
+    memory.idle();
+    state.set_pc(vector);
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    fn setup(program : &[u8], pending : u8) -> (cpu::Registers, Memory) {
+        let mut memory = Memory::default();
+        for (offset, byte) in program.iter().enumerate() {
+            memory.write(0xC000 + offset as u16, *byte);
+        }
+        memory.write(IE_ADDRESS, pending);
+        memory.write(IF_ADDRESS, pending);
+        let state = cpu::Registers { pc : 0xC000, sp : 0xD000, ..Default::default() };
+        (state, memory)
+    }
+
+    #[test]
+    fn ei_waits_an_instruction_and_di_cancels_it() {
+        //EI, NOP
+        let (mut state, mut memory) = setup(&[0xFB, 0x00], Interrupt::VBlank.mask());
+        cpu::step(&mut state, &mut memory);
+        assert!(!state.ime);
+        cpu::step(&mut state, &mut memory);
+        assert!(state.ime);
+        assert_eq!(state.pc(), 0xC002);
+        //Five M-cycles to get to the vector, with the return address pushed
+        assert_eq!(cpu::step(&mut state, &mut memory), 20);
+        assert_eq!(state.pc(), Interrupt::VBlank.vector());
+        assert_eq!(memory.read_u16(state.sp()), 0xC002);
+        assert!(!state.ime);
+
+        //EI, DI, NOP
+        let (mut state, mut memory) = setup(&[0xFB, 0xF3, 0x00], Interrupt::VBlank.mask());
+        for _ in 0..3 {
+            cpu::step(&mut state, &mut memory);
+        }
+        assert!(!state.ime);
+        assert_eq!(state.pc(), 0xC003);
+    }
+
+    #[test]
+    fn reti_enables_interrupts_straight_away() {
+        //RETI back to 0xC100
+        let (mut state, mut memory) = setup(&[0xD9], Interrupt::Timer.mask());
+        memory.write_u16(0xCFFE, 0xC100);
+        state.set_sp(0xCFFE);
+        cpu::step(&mut state, &mut memory);
+        assert!(state.ime);
+        assert_eq!(state.pc(), 0xC100);
+        cpu::step(&mut state, &mut memory);
+        assert_eq!(state.pc(), Interrupt::Timer.vector());
+    }
+
+    #[test]
+    fn highest_priority_is_dispatched_first() {
+        let pending = Interrupt::Joypad.mask() | Interrupt::Timer.mask() | Interrupt::LcdStat.mask();
+        let (mut state, mut memory) = setup(&[], pending);
+        state.ime = true;
+        cpu::step(&mut state, &mut memory);
+        assert_eq!(state.pc(), Interrupt::LcdStat.vector());
+        assert_eq!(memory.interrupts.requested(), Interrupt::Joypad.mask() | Interrupt::Timer.mask());
+
+        //Requested but not enabled interrupts are passed over
+        let (mut state, mut memory) = setup(&[], pending);
+        memory.write(IE_ADDRESS, Interrupt::Joypad.mask());
+        state.ime = true;
+        cpu::step(&mut state, &mut memory);
+        assert_eq!(state.pc(), Interrupt::Joypad.vector());
+    }
+}
@@ -0,0 +1,140 @@
+use crate::interrupts::{Interrupt, InterruptController};
+
+use bitflags::bitflags;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const P1_ADDRESS : u16 = 0xFF00;
+
+const SELECT_DPAD : u8 = 0x10;
+const SELECT_BUTTONS : u8 = 0x20;
+
+bitflags! {
+    /// Buttons held down. The d-pad is the low nibble and the buttons the high, in P1's bit order.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Buttons : u8 {
+        const RIGHT = 0x01;
+        const LEFT = 0x02;
+        const UP = 0x04;
+        const DOWN = 0x08;
+        const A = 0x10;
+        const B = 0x20;
+        const SELECT = 0x40;
+        const START = 0x80;
+    }
+}
+
+/// Something deciding which buttons are held, polled once a frame
+pub trait InputSource {
+    fn poll(&mut self, frame : u64) -> Buttons;
+}
+
+/// Input recorded as text, one line per frame listing the held buttons by name, such as `A RIGHT`.
+/// Frames past the end hold nothing.
+pub struct Movie {
+    frames : Vec<Buttons>,
+}
+
+impl Movie {
+    pub fn parse(text : &str) -> Result<Movie, String> {
+        let frames = text.lines().enumerate().map(|(line, buttons)| {
+            buttons.split_whitespace().try_fold(Buttons::empty(), |held, name| {
+                Buttons::from_name(&name.to_ascii_uppercase())
+                    .map(|button| held | button)
+                    .ok_or_else(|| format!("line {}: unknown button {}", line + 1, name))
+            })
+        }).collect::<Result<_, _>>()?;
+        Ok(Movie { frames })
+    }
+    pub fn load(path : &Path) -> io::Result<Movie> {
+        Movie::parse(&fs::read_to_string(path)?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+impl InputSource for Movie {
+    fn poll(&mut self, frame : u64) -> Buttons {
+        self.frames.get(frame as usize).copied().unwrap_or_default()
+    }
+}
+
+/// The P1 register, which reads the d-pad and button matrices through two select lines
+#[derive(Default)]
+pub struct Joypad {
+    ///Select bits as written, 0 selects a matrix
+    select : u8,
+    buttons : Buttons,
+}
+
+impl Joypad {
+    /// The four input lines, low for a held button on a selected matrix
+    fn lines(&self) -> u8 {
+        let mut held = 0;
+        if self.select & SELECT_DPAD == 0 {
+            held |= self.buttons.bits() & 0x0F;
+        }
+        if self.select & SELECT_BUTTONS == 0 {
+            held |= self.buttons.bits() >> 4;
+        }
+        !held & 0x0F
+    }
+    /// Run `change`, requesting the joypad interrupt if any line went from high to low
+    fn update(&mut self, interrupts : &mut InterruptController, change : impl FnOnce(&mut Joypad)) {
+        let before = self.lines();
+        change(self);
+        if before & !self.lines() != 0 {
+            interrupts.request(Interrupt::Joypad);
+        }
+    }
+
+    pub fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+    pub fn set_buttons(&mut self, buttons : Buttons, interrupts : &mut InterruptController) {
+        self.update(interrupts, |joypad| joypad.buttons = buttons);
+    }
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+    pub fn write(&mut self, data : u8, interrupts : &mut InterruptController) {
+        self.update(interrupts, |joypad| joypad.select = data & (SELECT_DPAD | SELECT_BUTTONS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matrices_and_interrupt() {
+        let mut joypad = Joypad::default();
+        let mut interrupts = InterruptController::default();
+
+        joypad.write(SELECT_BUTTONS, &mut interrupts);
+        joypad.set_buttons(Buttons::START | Buttons::LEFT, &mut interrupts);
+        assert_eq!(joypad.read(), 0xC0 | SELECT_BUTTONS | 0b1101);
+        assert_eq!(interrupts.requested(), Interrupt::Joypad.mask());
+
+        //Selecting the other matrix shows START
+        interrupts.write_flags(0);
+        joypad.write(SELECT_DPAD, &mut interrupts);
+        assert_eq!(joypad.read(), 0xC0 | SELECT_DPAD | 0b0111);
+        assert_eq!(interrupts.requested(), Interrupt::Joypad.mask());
+
+        //Releasing doesn't interrupt
+        interrupts.write_flags(0);
+        joypad.set_buttons(Buttons::empty(), &mut interrupts);
+        assert_eq!(interrupts.requested(), 0);
+    }
+
+    #[test]
+    fn parses_movies() {
+        let mut movie = Movie::parse("a right\n\nStart").unwrap();
+        assert_eq!(movie.poll(0), Buttons::A | Buttons::RIGHT);
+        assert_eq!(movie.poll(1), Buttons::empty());
+        assert_eq!(movie.poll(2), Buttons::START);
+        assert_eq!(movie.poll(3), Buttons::empty());
+        assert!(Movie::parse("TURBO").is_err());
+    }
+}
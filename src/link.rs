@@ -0,0 +1,318 @@
+use crate::serial::LinkDevice;
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+/// M-cycles between sync points, about 4ms
+const SYNC_INTERVAL : u32 = 4096;
+/// Sync points one end may run ahead of the other
+const SYNC_SLACK : u64 = 1;
+
+const SYNC : u8 = b'S';
+const TRANSFER : u8 = b'T';
+const REPLY : u8 = b'R';
+const HANGUP : u8 = b'H';
+
+/// Messages between the two ends of the cable
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Message {
+    ///The sender passed its next sync point
+    Sync,
+    ///The sender clocked out a byte
+    Transfer(u8),
+    ///The answer to a transfer, what the receiver had in SB
+    Reply(u8),
+    ///The sender is going away. Its socket may stay open a while after, so this can't wait for EOF.
+    Hangup,
+}
+
+impl Message {
+    fn encode(self) -> [u8; 2] {
+        match self {
+            Message::Sync => [SYNC, 0],
+            Message::Transfer(data) => [TRANSFER, data],
+            Message::Reply(data) => [REPLY, data],
+            Message::Hangup => [HANGUP, 0],
+        }
+    }
+    fn decode(bytes : [u8; 2]) -> Option<Message> {
+        match bytes[0] {
+            SYNC => Some(Message::Sync),
+            TRANSFER => Some(Message::Transfer(bytes[1])),
+            REPLY => Some(Message::Reply(bytes[1])),
+            HANGUP => Some(Message::Hangup),
+            _ => None,
+        }
+    }
+}
+
+/// A link cable to another emulator over a socket.
+///
+/// Both ends stop at a sync point every `SYNC_INTERVAL` M-cycles until the other end has
+/// nearly caught up, so neither runs more than a couple of intervals ahead. A transfer on the
+/// internal clock blocks until the other end replies with its SB, completing its transfer if it
+/// was waiting on the external clock.
+pub struct LinkCable {
+    writer : Box<dyn Write + Send>,
+    messages : Receiver<Message>,
+    ///M-cycles since the last sync point
+    cycles : u32,
+    ///Sync points passed by this end and by the other
+    synced : u64,
+    peer_synced : u64,
+    ///The other end went away, so the cable acts unplugged
+    disconnected : bool,
+}
+
+impl LinkCable {
+    /// A cable over a connected stream, read from a background thread
+    pub fn new(reader : impl Read + Send + 'static, writer : impl Write + Send + 'static) -> LinkCable {
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = reader;
+            let mut bytes = [0_u8; 2];
+            while reader.read_exact(&mut bytes).is_ok() {
+                match Message::decode(bytes) {
+                    Some(message) if sender.send(message).is_ok() => (),
+                    _ => break,
+                }
+            }
+        });
+        LinkCable {
+            writer : Box::new(writer),
+            messages,
+            cycles : 0,
+            synced : 0,
+            peer_synced : 0,
+            disconnected : false,
+        }
+    }
+    pub fn from_tcp(stream : TcpStream) -> io::Result<LinkCable> {
+        //Transfers wait on a round trip, so don't let small packets sit around
+        stream.set_nodelay(true)?;
+        Ok(LinkCable::new(stream.try_clone()?, stream))
+    }
+    #[cfg(unix)]
+    pub fn from_unix(stream : UnixStream) -> io::Result<LinkCable> {
+        Ok(LinkCable::new(stream.try_clone()?, stream))
+    }
+    /// Both ends of a cable, for linking two machines in one process
+    #[cfg(unix)]
+    pub fn pair() -> io::Result<(LinkCable, LinkCable)> {
+        let (first, second) = UnixStream::pair()?;
+        Ok((LinkCable::from_unix(first)?, LinkCable::from_unix(second)?))
+    }
+    /// Wait for the other end to connect to `address`, a TCP address or `unix:<path>`
+    pub fn listen(address : &str) -> io::Result<LinkCable> {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix:") {
+            //A socket file left over from an earlier run would stop the bind
+            let _ = std::fs::remove_file(path);
+            let (stream, _) = UnixListener::bind(path)?.accept()?;
+            return LinkCable::from_unix(stream);
+        }
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        LinkCable::from_tcp(stream)
+    }
+    /// Connect to the other end listening on `address`, a TCP address or `unix:<path>`
+    pub fn connect(address : &str) -> io::Result<LinkCable> {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix:") {
+            return LinkCable::from_unix(UnixStream::connect(path)?);
+        }
+        LinkCable::from_tcp(TcpStream::connect(address)?)
+    }
+
+    fn send(&mut self, message : Message) {
+        if self.writer.write_all(&message.encode()).and_then(|_| self.writer.flush()).is_err() {
+            self.disconnected = true;
+        }
+    }
+    /// Wait for the next message, or None once the other end has gone
+    fn receive(&mut self) -> Option<Message> {
+        if self.disconnected {
+            return None;
+        }
+        let message = self.messages.recv().ok();
+        self.disconnected = message.is_none();
+        message
+    }
+    /// Handle anything but a reply. `waiting` is what to answer transfers with, if one is expected.
+    /// Returns the byte of a transfer that was accepted.
+    fn handle(&mut self, message : Message, waiting : &mut Option<u8>) -> Option<u8> {
+        match message {
+            Message::Sync => self.peer_synced += 1,
+            Message::Transfer(data) => {
+                //Without a transfer waiting, the other end's clock finds nothing listening
+                self.send(Message::Reply(waiting.unwrap_or(0xFF)));
+                if waiting.take().is_some() {
+                    return Some(data);
+                }
+            },
+            //Only a transfer of ours is ever replied to, and that waits for it
+            Message::Reply(_) => (),
+            Message::Hangup => self.disconnected = true,
+        }
+        None
+    }
+}
+
+impl Drop for LinkCable {
+    fn drop(&mut self) {
+        //The reader thread holds its own handle on the socket, so dropping the writer doesn't close it
+        if !self.disconnected {
+            self.send(Message::Hangup);
+        }
+    }
+}
+
+impl LinkDevice for LinkCable {
+    fn transfer(&mut self, data : u8) -> u8 {
+        self.send(Message::Transfer(data));
+        while let Some(message) = self.receive() {
+            match message {
+                Message::Reply(data) => return data,
+                //Both ends clocking at once, neither is listening
+                message => { self.handle(message, &mut None); },
+            }
+        }
+        0xFF
+    }
+    fn step(&mut self, waiting : Option<u8>) -> Option<u8> {
+        if self.disconnected {
+            return None;
+        }
+        let mut waiting = waiting;
+        let mut received = None;
+
+        loop {
+            match self.messages.try_recv() {
+                Ok(message) => received = received.or(self.handle(message, &mut waiting)),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnected = true;
+                    return received;
+                },
+            }
+        }
+
+        self.cycles += 1;
+        if self.cycles == SYNC_INTERVAL {
+            self.cycles = 0;
+            self.synced += 1;
+            self.send(Message::Sync);
+            while self.peer_synced + SYNC_SLACK < self.synced {
+                let Some(message) = self.receive() else {
+                    break;
+                };
+                received = received.or(self.handle(message, &mut waiting));
+            }
+        }
+        received
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::InterruptController;
+    use crate::serial::{Serial, SB_ADDRESS, SC_ADDRESS};
+
+    /// Run until the transfer finishes, plus a while so the other end isn't left waiting on a sync
+    fn run_transfer(mut serial : Serial) -> u8 {
+        let mut interrupts = InterruptController::default();
+        while serial.read(SC_ADDRESS) & 0x80 != 0 {
+            serial.step(&mut interrupts);
+        }
+        for _ in 0..SYNC_INTERVAL * 4 {
+            serial.step(&mut interrupts);
+        }
+        serial.read(SB_ADDRESS)
+    }
+
+    fn exchange(master : LinkCable, slave : LinkCable) {
+        //The slave has to be waiting before the master clocks anything
+        let (armed, wait_for_slave) = mpsc::channel();
+        let slave = thread::spawn(move || {
+            let mut serial = Serial::default();
+            serial.connect(Box::new(slave));
+            serial.write(SB_ADDRESS, 0x42);
+            serial.write(SC_ADDRESS, 0x80);
+            armed.send(()).unwrap();
+            run_transfer(serial)
+        });
+        wait_for_slave.recv().unwrap();
+
+        let master = thread::spawn(move || {
+            let mut serial = Serial::default();
+            serial.connect(Box::new(master));
+            serial.write(SB_ADDRESS, 0x13);
+            serial.write(SC_ADDRESS, 0x81);
+            run_transfer(serial)
+        });
+
+        assert_eq!(master.join().unwrap(), 0x42);
+        assert_eq!(slave.join().unwrap(), 0x13);
+    }
+
+    #[test]
+    fn exchanges_over_tcp_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let connecting = thread::spawn(move || LinkCable::connect(&address).unwrap());
+        let (stream, _) = listener.accept().unwrap();
+
+        exchange(LinkCable::from_tcp(stream).unwrap(), connecting.join().unwrap());
+    }
+
+    /// Set for the copy of the test binary playing the other end in `exchanges_between_processes`
+    const PEER_ADDRESS : &str = "FUZZ_GB_LINK_PEER";
+
+    /// The slave end of `exchanges_between_processes`, only run by the process it starts
+    #[test]
+    #[ignore]
+    fn peer_process() {
+        let Ok(address) = std::env::var(PEER_ADDRESS) else {
+            return;
+        };
+        //Arm the transfer before connecting, so it's waiting whenever the master clocks
+        let mut serial = Serial::default();
+        serial.write(SB_ADDRESS, 0x42);
+        serial.write(SC_ADDRESS, 0x80);
+        serial.connect(Box::new(LinkCable::connect(&address).unwrap()));
+        assert_eq!(run_transfer(serial), 0x13);
+    }
+
+    #[test]
+    fn exchanges_between_processes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "link::tests::peer_process", "--ignored"])
+            .env(PEER_ADDRESS, listener.local_addr().unwrap().to_string())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let mut serial = Serial::default();
+        serial.connect(Box::new(LinkCable::from_tcp(stream).unwrap()));
+        serial.write(SB_ADDRESS, 0x13);
+        serial.write(SC_ADDRESS, 0x81);
+        assert_eq!(run_transfer(serial), 0x42);
+
+        let output = peer.wait_with_output().unwrap();
+        let output = String::from_utf8_lossy(&output.stdout);
+        assert!(output.contains("1 passed"), "{}", output);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exchanges_in_process() {
+        let (master, slave) = LinkCable::pair().unwrap();
+        exchange(master, slave);
+    }
+}
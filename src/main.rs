@@ -1,55 +1,308 @@
-use std::time::{Instant, Duration};
-
 pub mod cpu;
 pub mod bitmath;
 pub mod memory;
+pub mod interrupts;
+pub mod cartridge;
+pub mod mapper;
+pub mod rtc;
+pub mod boot;
+pub mod ppu;
+pub mod dma;
+pub mod timer;
+pub mod apu;
+pub mod wav;
+pub mod screenshot;
+pub mod joypad;
+pub mod serial;
+pub mod link;
+pub mod sgb;
+pub mod printer;
+pub mod save;
 mod instructions;
 
+use std::path::Path;
+
+use boot::{BootRom, Model};
+use cartridge::{Cartridge, CgbSupport};
 use instructions::Instruction;
-use memory::Memory;
+use joypad::{InputSource, Movie};
+use link::LinkCable;
+use printer::Printer;
+use memory::{Bus, Memory};
+use save::SaveFile;
 use ansi_term::Color::Blue;
 
-fn black_box<T>(dummy: T) -> T {
-    unsafe {
-        std::ptr::read_volatile(&dummy)
+/// What's on screen, the SGB's output with its border when there is one
+fn screen(memory : &Memory) -> &[u16] {
+    match &memory.sgb {
+        Some(sgb) => sgb.framebuffer(),
+        None => memory.ppu.framebuffer(),
     }
 }
 
 fn main() {
-    let data = include_bytes!("data/dmg_boot.bin");
+    let usage = || -> ! {
+        eprintln!("usage: fuzz_gb <rom> [--trace] [--fifo] [--boot <boot rom>] [--model dmg|mgb|sgb|cgb] [--frames <count>] [--serial] [--link-listen|--link-connect <address> | --printer <directory>] [--input <movie>] [--wav <file> [--stems]] [--screenshot <png>]");
+        std::process::exit(1);
+    };
+    let mut args = std::env::args().skip(1);
+    let Some(rom_path) = args.next() else {
+        usage()
+    };
+    let mut trace = false;
+    let mut fifo = false;
+    let mut boot_path = None;
+    let mut model = None;
+    let mut frames = None;
+    let mut wav_path = None;
+    let mut stems = false;
+    let mut input_path = None;
+    let mut serial = false;
+    let mut link = None;
+    let mut printer_path = None;
+    let mut screenshot_path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trace" => trace = true,
+            "--fifo" => fifo = true,
+            "--boot" => boot_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--model" => model = Some(args.next().as_deref().and_then(Model::from_name).unwrap_or_else(|| usage())),
+            "--frames" => frames = Some(args.next().and_then(|count| count.parse::<u64>().ok()).unwrap_or_else(|| usage())),
+            "--wav" => wav_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--stems" => stems = true,
+            "--serial" => serial = true,
+            "--link-listen" => link = Some((true, args.next().unwrap_or_else(|| usage()))),
+            "--link-connect" => link = Some((false, args.next().unwrap_or_else(|| usage()))),
+            "--printer" => printer_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--screenshot" => screenshot_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--input" => input_path = Some(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+    //Stems are written next to the mix
+    if stems && wav_path.is_none() {
+        usage();
+    }
+    //Only one thing fits in the link port
+    if link.is_some() && printer_path.is_some() {
+        usage();
+    }
+
+    let mapper = Cartridge::load(Path::new(&rom_path)).and_then(|cartridge| {
+        println!("{}", cartridge.header);
+        for warning in &cartridge.warnings {
+            eprintln!("{}: warning: {}", rom_path, warning);
+        }
+        let battery = cartridge.header.cartridge_type.battery;
+        let cgb = cartridge.header.cgb != CgbSupport::None;
+        let sgb = cartridge.header.sgb;
+        Ok((mapper::from_cartridge(cartridge)?, battery, cgb, sgb))
+    });
+    let (mut mapper, battery, cgb, sgb_packets) = match mapper {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("{}: {}", rom_path, err);
+            std::process::exit(1);
+        }
+    };
+
+    let mut input : Option<Box<dyn InputSource>> = match input_path {
+        Some(input_path) => match Movie::load(Path::new(&input_path)) {
+            Ok(movie) => Some(Box::new(movie)),
+            Err(err) => {
+                eprintln!("{}: {}", input_path, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut save_file = battery.then(|| SaveFile::for_rom(Path::new(&rom_path)));
+    if let Some(save_file) = &save_file {
+        if let Err(err) = save_file.load(mapper.as_mut()) {
+            eprintln!("{}: {}", save_file.path().display(), err);
+            std::process::exit(1);
+        }
+    }
+
+    //Carts that know about the CGB get one unless told otherwise
+    let model = model.unwrap_or(if cgb { Model::Cgb } else { Model::Dmg });
 
     let mut cpu_state = cpu::Registers::default();
-    let mut memory = Memory::default();
+    let mut memory = Memory::new(mapper);
+    memory.set_cgb(model == Model::Cgb);
+    if model == Model::Sgb {
+        memory.sgb = Some(sgb::Sgb::new(sgb_packets));
+    }
+    if let Some(printer_path) = printer_path {
+        if let Err(err) = std::fs::create_dir_all(&printer_path) {
+            eprintln!("{}: {}", printer_path, err);
+            std::process::exit(1);
+        }
+        memory.serial.connect(Box::new(Printer::new(printer_path.into())));
+    }
+    if let Some((listen, address)) = link {
+        let cable = if listen { LinkCable::listen(&address) } else { LinkCable::connect(&address) };
+        match cable {
+            Ok(cable) => memory.serial.connect(Box::new(cable)),
+            Err(err) => {
+                eprintln!("{}: {}", address, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    if fifo {
+        memory.ppu.set_renderer(ppu::Renderer::Fifo);
+    }
+    memory.apu.capture_stems(stems);
+
+    match boot_path {
+        Some(boot_path) => match BootRom::load(Path::new(&boot_path)) {
+            Ok(boot_rom) => memory.boot_rom = Some(boot_rom),
+            Err(err) => {
+                eprintln!("{}: {}", boot_path, err);
+                std::process::exit(1);
+            }
+        },
+        //Without a boot ROM, start where it would hand over to the cartridge
+        None => boot::skip_boot(model, &mut cpu_state, &mut memory),
+    }
+
+    //Frames are counted in PPU time, which isn't affected by double speed
+    let mut dots : u64 = 0;
+    let mut polled_frame = None;
+    let mut serial_output = String::new();
+    let mut test_result = None;
+    let mut samples = Vec::new();
+    let mut stem_samples : [Vec<i16>; 4] = Default::default();
+    //The last finished frame, as the framebuffer is redrawn line by line
+    let mut last_frame = None;
 
     loop {
-        let addr = cpu_state.pc();
-        let instruction = Instruction::from_bytes(addr as usize, data);
+        let frame = dots / ppu::FRAME_DOTS as u64;
+        if let Some(input) = &mut input {
+            if polled_frame != Some(frame) {
+                polled_frame = Some(frame);
+                let buttons = input.poll(frame);
+                memory.joypad.set_buttons(buttons, &mut memory.interrupts);
+            }
+        }
 
-        if let Some(instruction) = instruction {
+        if trace && cpu_state.mode == cpu::Mode::Running {
+            let addr = cpu_state.pc();
+            let instruction = Instruction::from_memory(addr, &memory);
 
-            print!("{:04X}: ", addr);
+            if let Some(instruction) = instruction {
 
-            let instruction_data = &data[(addr as usize)..((addr + instruction.size as u16) as usize)];
+                print!("{:04X}: ", addr);
 
-            for i in 0..3 {
-                if i < instruction_data.len() {
-                    print!("{:02X} ", instruction_data[i]);
-                } else {
-                    print!("   ");
+                for i in 0..3 {
+                    if i < instruction.size as u16 {
+                        print!("{:02X} ", memory.read(addr.wrapping_add(i)));
+                    } else {
+                        print!("   ");
+                    };
                 };
-            };
 
-            println!("| {}", Blue.bold().paint(format!("{}", instruction.op)));
+                println!("| {} ({} cycles)", Blue.bold().paint(format!("{}", instruction.op)), instruction.cycles);
+            }
+        }
+
+        let cycles = cpu::step(&mut cpu_state, &mut memory);
+        dots += if memory.speed.double_speed { cycles as u64 / 2 } else { cycles as u64 };
 
-            if instruction.size == 0 {
-                break
+        if screenshot_path.is_some() && memory.ppu.take_frame_ready() {
+            last_frame = Some(screen(&memory).to_vec());
+        }
+
+        //Keep the audio if it's being written out, otherwise it's dropped as it's produced
+        let new_samples = memory.apu.take_samples();
+        if wav_path.is_some() {
+            samples.extend(new_samples);
+            if let Some(new_stems) = memory.apu.take_stems() {
+                for (stem, new_samples) in stem_samples.iter_mut().zip(new_stems) {
+                    stem.extend(new_samples);
+                }
             }
+        }
+
+        if let Some(save_file) = &mut save_file {
+            if let Err(err) = save_file.tick(cycles, memory.cartridge_mut()) {
+                eprintln!("{}: {}", save_file.path().display(), err);
+            }
+        }
 
-            instruction.execute(&mut cpu_state, &mut memory);
+        //Test ROMs print their results over serial
+        if serial {
+            let output = String::from_utf8_lossy(&memory.serial.take_output()).into_owned();
+            print!("{}", output);
+            serial_output.push_str(&output);
+            //Wait for the end of the line, so the details after "Failed" get printed too
+            if serial_output.ends_with('\n') {
+                if serial_output.contains("Passed") {
+                    test_result = Some(true);
+                } else if serial_output.contains("Failed") {
+                    test_result = Some(false);
+                }
+            }
+            if test_result.is_some() {
+                break
+            }
         } else {
+            memory.serial.take_output();
+        }
+        //A print that couldn't be saved shouldn't stop the game
+        if let Some(err) = memory.serial.take_device_error() {
+            eprintln!("{}", err);
+        }
+
+        if cpu_state.mode == cpu::Mode::Locked {
+            println!("CPU locked up");
+            break
+        }
+        if frames.is_some_and(|frames| dots >= frames * ppu::FRAME_DOTS as u64) {
             break
         }
     }
 
+    if let Some(wav_path) = &wav_path {
+        let wav_path = Path::new(wav_path);
+        let sample_rate = memory.apu.sample_rate();
+        let mut outputs = vec![(wav_path.to_path_buf(), samples)];
+        if stems {
+            for (name, samples) in apu::CHANNEL_NAMES.into_iter().zip(stem_samples) {
+                outputs.push((wav::stem_path(wav_path, name), samples));
+            }
+        }
+        for (path, samples) in outputs {
+            if let Err(err) = wav::write(&path, sample_rate, &samples) {
+                eprintln!("{}: {}", path.display(), err);
+            }
+        }
+    }
+
+    if let Some(screenshot_path) = &screenshot_path {
+        //With the LCD never turned on there's no finished frame, so take whatever is there
+        let frame = last_frame.unwrap_or_else(|| screen(&memory).to_vec());
+        //The SGB draws the Game Boy's screen inside its border
+        let (width, height) = match memory.sgb {
+            Some(_) => (sgb::SCREEN_WIDTH, sgb::SCREEN_HEIGHT),
+            None => (ppu::SCREEN_WIDTH, ppu::SCREEN_HEIGHT),
+        };
+        if let Err(err) = screenshot::write(Path::new(screenshot_path), width, height, &frame) {
+            eprintln!("{}: {}", screenshot_path, err);
+        }
+    }
+
+    if let Some(save_file) = &mut save_file {
+        if let Err(err) = save_file.flush(memory.cartridge_mut()) {
+            eprintln!("{}: {}", save_file.path().display(), err);
+        }
+    }
+
     println!{"{}", cpu_state}
+
+    if test_result == Some(false) {
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,540 @@
+use crate::cartridge::{Cartridge, CartridgeError, MapperKind};
+use crate::rtc::{self, Rtc, SystemClock};
+
+pub const ROM_BANK_SIZE : usize = 0x4000;
+pub const RAM_BANK_SIZE : usize = 0x2000;
+
+/// Banking hardware on the cartridge, owning its ROM and external RAM.
+/// Addresses are as seen on the bus, 0x0000..=0x7FFF for ROM and 0xA000..=0xBFFF for RAM.
+pub trait Mapper {
+    fn read_rom(&self, addr : u16) -> u8;
+    /// ROM can't be written, writes to it configure the mapper instead
+    fn write_rom(&mut self, addr : u16, data : u8);
+    fn read_ram(&self, addr : u16) -> u8;
+    fn write_ram(&mut self, addr : u16, data : u8);
+
+    /// Whether the rumble motor is currently driven
+    fn rumble(&self) -> bool {
+        false
+    }
+
+    /// Battery backed state, in the raw format other emulators use for .sav files
+    fn save_data(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    /// Restore state previously produced by `save_data`
+    fn load_save_data(&mut self, _data : &[u8]) {}
+    /// Whether `save_data` changed since the last call to `mark_saved`
+    fn save_dirty(&self) -> bool {
+        false
+    }
+    fn mark_saved(&mut self) {}
+}
+
+/// Pick the mapper named by the cartridge type byte
+pub fn from_cartridge(cartridge : Cartridge) -> Result<Box<dyn Mapper>, CartridgeError> {
+    let header = cartridge.header;
+    let ram = vec![0_u8; header.ram_size];
+    let rom = cartridge.rom;
+
+    Ok(match header.cartridge_type.mapper {
+        MapperKind::None => Box::new(NoMapper{ rom, ram : ExternalRam::new(ram) }),
+        MapperKind::Mbc1 => Box::new(Mbc1::new(rom, ram)),
+        MapperKind::Mbc2 => Box::new(Mbc2::new(rom)),
+        MapperKind::Mbc3 => {
+            let rtc = header.cartridge_type.timer.then(|| Rtc::new(Box::new(SystemClock)));
+            Box::new(Mbc3::new(rom, ram, rtc))
+        },
+        MapperKind::Mbc5 => Box::new(Mbc5::new(rom, ram, header.cartridge_type.rumble)),
+        kind => return Err(CartridgeError::UnsupportedMapper(kind)),
+    })
+}
+
+/// Save data of mappers whose only battery backed state is the `ram` field, stored as a raw dump
+macro_rules! battery_backed_ram {
+    () => {
+        fn save_data(&self) -> Vec<u8> {
+            self.ram.data.clone()
+        }
+        fn load_save_data(&mut self, data : &[u8]) {
+            self.ram.load(data);
+        }
+        fn save_dirty(&self) -> bool {
+            self.ram.dirty
+        }
+        fn mark_saved(&mut self) {
+            self.ram.dirty = false;
+        }
+    };
+}
+
+/// Byte of `rom` at `addr` within a 16KiB bank. Bank numbers wrap around the image size.
+fn banked_rom(rom : &[u8], bank : usize, addr : u16) -> u8 {
+    let banks = (rom.len() / ROM_BANK_SIZE).max(1);
+    rom.get((bank % banks) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1)))
+        .copied()
+        .unwrap_or(0xFF)
+}
+
+/// Cartridge RAM, tracking whether it was written since it was last saved
+pub struct ExternalRam {
+    data : Vec<u8>,
+    dirty : bool,
+}
+
+impl ExternalRam {
+    pub fn new(data : Vec<u8>) -> ExternalRam {
+        ExternalRam { data, dirty : false }
+    }
+    /// Index of `addr` within an 8KiB bank, if the cartridge has RAM there
+    fn index(&self, bank : usize, addr : u16) -> Option<usize> {
+        if self.data.is_empty() {
+            return None;
+        }
+        //Carts with less than a full bank mirror it
+        Some((bank * RAM_BANK_SIZE + (addr as usize & (RAM_BANK_SIZE - 1))) % self.data.len())
+    }
+    pub fn read(&self, bank : usize, addr : u16) -> u8 {
+        self.index(bank, addr).map_or(0xFF, |index| self.data[index])
+    }
+    pub fn write(&mut self, bank : usize, addr : u16, data : u8) {
+        if let Some(index) = self.index(bank, addr) {
+            self.data[index] = data;
+            self.dirty = true;
+        }
+    }
+    /// Overwrite the start of RAM with `data`, returning how many bytes were used
+    pub fn load(&mut self, data : &[u8]) -> usize {
+        let size = self.data.len().min(data.len());
+        self.data[..size].copy_from_slice(&data[..size]);
+        size
+    }
+}
+
+/// 32KiB ROM-only cartridges, optionally with up to 8KiB of RAM
+pub struct NoMapper {
+    rom : Vec<u8>,
+    ram : ExternalRam,
+}
+
+impl NoMapper {
+    pub fn new(rom : Vec<u8>) -> NoMapper {
+        NoMapper { rom, ram : ExternalRam::new(Vec::new()) }
+    }
+}
+
+impl Mapper for NoMapper {
+    fn read_rom(&self, addr : u16) -> u8 {
+        self.rom.get(addr as usize).copied().unwrap_or(0xFF)
+    }
+    fn write_rom(&mut self, _addr : u16, _data : u8) {}
+    fn read_ram(&self, addr : u16) -> u8 {
+        self.ram.read(0, addr)
+    }
+    fn write_ram(&mut self, addr : u16, data : u8) {
+        self.ram.write(0, addr, data);
+    }
+    battery_backed_ram!();
+}
+
+pub struct Mbc1 {
+    rom : Vec<u8>,
+    ram : ExternalRam,
+    ram_enabled : bool,
+    ///Low ROM bank bits, 0x2000..=0x3FFF
+    bank1 : u8,
+    ///High ROM bank bits or RAM bank, 0x4000..=0x5FFF
+    bank2 : u8,
+    ///Mode select. When set, bank2 also applies to 0x0000..=0x3FFF and to RAM.
+    advanced_mode : bool,
+    ///MBC1M multicarts only wire up four of the five bank1 bits
+    multicart : bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom : Vec<u8>, ram : Vec<u8>) -> Mbc1 {
+        let multicart = Mbc1::is_multicart(&rom);
+        Mbc1 { rom, ram : ExternalRam::new(ram), ram_enabled : false, bank1 : 1, bank2 : 0, advanced_mode : false, multicart }
+    }
+    /// Multicarts are 1MiB and repeat the Nintendo logo at the start of each 256KiB game
+    fn is_multicart(rom : &[u8]) -> bool {
+        const LOGO : std::ops::Range<usize> = 0x0104..0x0134;
+        const SECOND_GAME : usize = 0x10 * ROM_BANK_SIZE;
+
+        rom.len() == 0x100000
+            && rom[LOGO] == rom[SECOND_GAME + LOGO.start..SECOND_GAME + LOGO.end]
+    }
+    fn bank2_shift(&self) -> u32 {
+        if self.multicart {4} else {5}
+    }
+    fn ram_bank(&self) -> usize {
+        if self.advanced_mode {self.bank2 as usize} else {0}
+    }
+}
+
+impl Mapper for Mbc1 {
+    fn read_rom(&self, addr : u16) -> u8 {
+        let high_bits = (self.bank2 as usize) << self.bank2_shift();
+        let bank = if addr < 0x4000 {
+            if self.advanced_mode {high_bits} else {0}
+        } else {
+            let low_mask = if self.multicart {0x0F} else {0x1F};
+            high_bits | (self.bank1 & low_mask) as usize
+        };
+        banked_rom(&self.rom, bank, addr)
+    }
+    fn write_rom(&mut self, addr : u16, data : u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = data & 0x0F == 0x0A,
+            //The zero check sees all five bits, so banks 0x20, 0x40 and 0x60 can't be selected
+            0x2000..=0x3FFF => self.bank1 = match data & 0x1F {0 => 1, bank => bank},
+            0x4000..=0x5FFF => self.bank2 = data & 0x03,
+            _ => self.advanced_mode = data & 0x01 != 0,
+        }
+    }
+    fn read_ram(&self, addr : u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        self.ram.read(self.ram_bank(), addr)
+    }
+    fn write_ram(&mut self, addr : u16, data : u8) {
+        if self.ram_enabled {
+            self.ram.write(self.ram_bank(), addr, data);
+        }
+    }
+    battery_backed_ram!();
+}
+
+pub struct Mbc2 {
+    rom : Vec<u8>,
+    ///512 half-bytes built into the mapper, stored one per byte
+    ram : ExternalRam,
+    ram_enabled : bool,
+    rom_bank : u8,
+}
+
+impl Mbc2 {
+    const RAM_SIZE : usize = 0x200;
+
+    pub fn new(rom : Vec<u8>) -> Mbc2 {
+        Mbc2 { rom, ram : ExternalRam::new(vec![0; Mbc2::RAM_SIZE]), ram_enabled : false, rom_bank : 1 }
+    }
+}
+
+impl Mapper for Mbc2 {
+    fn read_rom(&self, addr : u16) -> u8 {
+        let bank = if addr < 0x4000 {0} else {self.rom_bank as usize};
+        banked_rom(&self.rom, bank, addr)
+    }
+    fn write_rom(&mut self, addr : u16, data : u8) {
+        //Both registers live at 0x0000..=0x3FFF, told apart by address bit 8
+        if addr >= 0x4000 {
+            return;
+        }
+        if addr & 0x0100 == 0 {
+            self.ram_enabled = data & 0x0F == 0x0A;
+        } else {
+            self.rom_bank = match data & 0x0F {0 => 1, bank => bank};
+        }
+    }
+    fn read_ram(&self, addr : u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        //Only the low nibble exists, the upper one floats high.
+        //The 512 entries echo through the whole 0xA000..=0xBFFF range.
+        0xF0 | self.ram.read(0, addr)
+    }
+    fn write_ram(&mut self, addr : u16, data : u8) {
+        if self.ram_enabled {
+            self.ram.write(0, addr, data & 0x0F);
+        }
+    }
+    battery_backed_ram!();
+}
+
+pub struct Mbc3 {
+    rom : Vec<u8>,
+    ram : ExternalRam,
+    ram_enabled : bool,
+    rom_bank : u8,
+    ///0x00..=0x03 select a RAM bank, 0x08..=0x0C a clock register
+    ram_select : u8,
+    rtc : Option<Rtc>,
+}
+
+impl Mbc3 {
+    pub fn new(rom : Vec<u8>, ram : Vec<u8>, rtc : Option<Rtc>) -> Mbc3 {
+        Mbc3 { rom, ram : ExternalRam::new(ram), ram_enabled : false, rom_bank : 1, ram_select : 0, rtc }
+    }
+}
+
+impl Mapper for Mbc3 {
+    fn read_rom(&self, addr : u16) -> u8 {
+        let bank = if addr < 0x4000 {0} else {self.rom_bank as usize};
+        banked_rom(&self.rom, bank, addr)
+    }
+    fn write_rom(&mut self, addr : u16, data : u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = data & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = match data & 0x7F {0 => 1, bank => bank},
+            0x4000..=0x5FFF => self.ram_select = data,
+            _ => if let Some(rtc) = &mut self.rtc {
+                rtc.write_latch(data);
+            },
+        }
+    }
+    fn read_ram(&self, addr : u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match (self.ram_select, &self.rtc) {
+            (0x00..=0x03, _) => self.ram.read(self.ram_select as usize, addr),
+            (0x08..=0x0C, Some(rtc)) => rtc.read(self.ram_select),
+            _ => 0xFF,
+        }
+    }
+    fn write_ram(&mut self, addr : u16, data : u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        match (self.ram_select, &mut self.rtc) {
+            (0x00..=0x03, _) => self.ram.write(self.ram_select as usize, addr, data),
+            (0x08..=0x0C, Some(rtc)) => {
+                rtc.write(self.ram_select, data);
+                self.ram.dirty = true;
+            },
+            _ => (),
+        }
+    }
+
+    /// RAM followed by the clock footer, if the cartridge has a clock
+    fn save_data(&self) -> Vec<u8> {
+        let mut data = self.ram.data.clone();
+        if let Some(rtc) = &self.rtc {
+            data.extend_from_slice(&rtc.to_footer());
+        }
+        data
+    }
+    fn load_save_data(&mut self, data : &[u8]) {
+        let ram_size = self.ram.load(data);
+
+        //Saves without a footer keep the clock running from now
+        if let Some(rtc) = &mut self.rtc {
+            let footer = &data[ram_size..];
+            if footer.len() == rtc::FOOTER_SIZE || footer.len() == rtc::SHORT_FOOTER_SIZE {
+                rtc.load_footer(footer);
+            }
+        }
+    }
+    fn save_dirty(&self) -> bool {
+        self.ram.dirty
+    }
+    fn mark_saved(&mut self) {
+        self.ram.dirty = false;
+    }
+}
+
+pub struct Mbc5 {
+    rom : Vec<u8>,
+    ram : ExternalRam,
+    ram_enabled : bool,
+    ///Nine bits, unlike earlier MBCs bank 0 can be mapped at 0x4000
+    rom_bank : u16,
+    ram_bank : u8,
+    ///Rumble carts drive the motor with bit 3 of the RAM bank register
+    has_rumble : bool,
+    rumble : bool,
+}
+
+impl Mbc5 {
+    pub fn new(rom : Vec<u8>, ram : Vec<u8>, has_rumble : bool) -> Mbc5 {
+        Mbc5 { rom, ram : ExternalRam::new(ram), ram_enabled : false, rom_bank : 1, ram_bank : 0, has_rumble, rumble : false }
+    }
+}
+
+impl Mapper for Mbc5 {
+    fn read_rom(&self, addr : u16) -> u8 {
+        let bank = if addr < 0x4000 {0} else {self.rom_bank as usize};
+        banked_rom(&self.rom, bank, addr)
+    }
+    fn write_rom(&mut self, addr : u16, data : u8) {
+        match addr {
+            //MBC5 only accepts exactly 0x0A
+            0x0000..=0x1FFF => self.ram_enabled = data == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | data as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0x0FF) | ((data as u16 & 0x01) << 8),
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.rumble = data & 0x08 != 0;
+                    self.ram_bank = data & 0x07;
+                } else {
+                    self.ram_bank = data & 0x0F;
+                }
+            },
+            _ => (),
+        }
+    }
+    fn read_ram(&self, addr : u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        self.ram.read(self.ram_bank as usize, addr)
+    }
+    fn write_ram(&mut self, addr : u16, data : u8) {
+        if self.ram_enabled {
+            self.ram.write(self.ram_bank as usize, addr, data);
+        }
+    }
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+    battery_backed_ram!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ROM where the first two bytes of every bank are the bank number
+    fn numbered_rom(banks : usize) -> Vec<u8> {
+        let mut rom = vec![0_u8; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+            rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn mbc1_banking() {
+        let mut mbc = Mbc1::new(numbered_rom(128), vec![0; 0x8000]);
+
+        //Bank 0 is remapped to bank 1, as is 0x20 which only differs in bank2
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x01);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x21);
+        assert_eq!(mbc.read_rom(0x0000), 0x00);
+
+        //Mode 1 applies bank2 to the bottom half and to RAM
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x20);
+
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0xA000, 0x55);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 0x55);
+    }
+
+    #[test]
+    fn mbc1m_multicart_wiring() {
+        let mut rom = numbered_rom(64);
+        for (offset, byte) in rom[0x0104..0x0134].iter_mut().enumerate() {
+            *byte = offset as u8 ^ 0xCE;
+        }
+        rom.copy_within(0x0104..0x0134, 0x10 * ROM_BANK_SIZE + 0x0104);
+        let mut mbc = Mbc1::new(rom, Vec::new());
+
+        //bank2 lands on bit 4, with only four bank1 bits wired
+        mbc.write_rom(0x2000, 0x12);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x12);
+        //0x10 passes the zero check but leaves bank 0 mapped
+        mbc.write_rom(0x2000, 0x10);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x00);
+
+        //Mode 1 mirrors the first bank of each game at 0x0000
+        mbc.write_rom(0x6000, 0x01);
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_rom(0x0000), 0x20);
+        assert_eq!(mbc.read_rom(0x4000), 0x20);
+    }
+
+    #[test]
+    fn mbc2_nibble_ram_and_address_bit_8() {
+        let mut mbc = Mbc2::new(numbered_rom(16));
+
+        //Bit 8 clear is RAMG, set is ROMB, anywhere in 0x0000..=0x3FFF
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+        mbc.write_rom(0x2000, 0x0A);
+        assert_eq!(mbc.read_rom(0x4000), 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 0xF0);
+        mbc.write_rom(0x0100, 0x05);
+        assert_eq!(mbc.read_rom(0x4000), 0x05);
+        mbc.write_rom(0x3F00, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x01);
+
+        //Only the low nibble is stored, and the 512 entries repeat through the range
+        mbc.write_ram(0xA003, 0xA7);
+        assert_eq!(mbc.read_ram(0xA003), 0xF7);
+        assert_eq!(mbc.read_ram(0xA203), 0xF7);
+        assert_eq!(mbc.read_ram(0xBE03), 0xF7);
+        assert_eq!(mbc.save_data().len(), 0x200);
+    }
+
+    #[test]
+    fn mbc3_rom_and_ram_banks() {
+        let mut mbc = Mbc3::new(numbered_rom(128), vec![0; 0x8000], None);
+
+        mbc.write_rom(0x2000, 0x7F);
+        assert_eq!(mbc.read_rom(0x4000), 0x7F);
+        //Unlike MBC1, every bank but 0 can be selected
+        mbc.write_rom(0x2000, 0x20);
+        assert_eq!(mbc.read_rom(0x4000), 0x20);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x01);
+
+        mbc.write_rom(0x0000, 0x0A);
+        for bank in 0..4 {
+            mbc.write_rom(0x4000, bank);
+            mbc.write_ram(0xA000, 0x40 | bank);
+        }
+        for bank in 0..4 {
+            mbc.write_rom(0x4000, bank);
+            assert_eq!(mbc.read_ram(0xA000), 0x40 | bank);
+        }
+        //Clock registers read open bus without a clock
+        mbc.write_rom(0x4000, 0x08);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mbc5_nine_bit_banks() {
+        let mut mbc = Mbc5::new(numbered_rom(512), Vec::new(), false);
+
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x00);
+        mbc.write_rom(0x2000, 0x05);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x05);
+        assert_eq!(mbc.read_rom(0x4001), 0x01);
+    }
+
+    #[test]
+    fn save_data_tracks_writes() {
+        let mut mbc = Mbc1::new(numbered_rom(4), vec![0; 0x2000]);
+        assert!(!mbc.save_dirty());
+
+        //Writes while RAM is disabled don't reach it
+        mbc.write_ram(0xA000, 0x12);
+        assert!(!mbc.save_dirty());
+
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0xA001, 0x34);
+        assert!(mbc.save_dirty());
+        let save = mbc.save_data();
+        assert_eq!(&save[..2], &[0x00, 0x34]);
+        mbc.mark_saved();
+        assert!(!mbc.save_dirty());
+
+        let mut restored = Mbc1::new(numbered_rom(4), vec![0; 0x2000]);
+        restored.load_save_data(&save);
+        restored.write_rom(0x0000, 0x0A);
+        assert_eq!(restored.read_ram(0xA001), 0x34);
+    }
+}
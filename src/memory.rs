@@ -1,24 +1,35 @@
+use crate::apu::{self, Apu};
 use crate::bitmath::join_u8;
+use crate::boot::{self, BootRom};
+use crate::cpu::{self, SpeedSwitch};
+use crate::dma::{self, OamDma, VramDma};
+use crate::interrupts::{self, InterruptController};
+use crate::joypad::{self, Joypad};
+use crate::mapper::{Mapper, NoMapper};
+use crate::ppu::{self, Ppu};
+use crate::serial::{self, Serial};
+use crate::sgb::Sgb;
+use crate::timer::{self, Timer};
 
-pub struct Memory {
-    data : [u8; 0xffff],
-}
+/// The address space as seen by the CPU
+pub trait Bus {
+    fn read(&self, addr : u16) -> u8;
+    fn write(&mut self, addr : u16, data : u8);
+    /// Advance everything attached to the bus by `cycles` clock cycles.
+    /// Returns how many more cycles the CPU was held off the bus for, by VRAM DMA.
+    fn tick(&mut self, cycles : u8) -> u32;
 
-impl Memory {
-    pub fn read(&self, addr : u16) -> u8 {
-        self.data[addr as usize]
-    }
-    pub fn read_mut<'a>(&'a mut self, addr : u16) -> &'a mut u8 {
-        &mut self.data[addr as usize]
-    }
-    pub fn write(&mut self, addr : u16, data : u8) -> () {
-        self.data[addr as usize] = data;
-    }
-    pub fn write_u16(&mut self, addr : u16, data : u16) -> () {
+    fn interrupts(&self) -> &InterruptController;
+    fn interrupts_mut(&mut self) -> &mut InterruptController;
+    /// Called when the CPU executes STOP.
+    /// Returns whether a CGB speed switch was performed, in which case the CPU keeps running.
+    fn stop(&mut self) -> bool;
+
+    fn write_u16(&mut self, addr : u16, data : u16) {
         self.write(addr,                (data & 0xff) as u8);
         self.write(addr.wrapping_add(1),(data >> 8) as u8);
     }
-    pub fn read_u16(&self, addr : u16) -> u16 {
+    fn read_u16(&self, addr : u16) -> u16 {
         join_u8(
             self.read(addr),
             self.read(addr.wrapping_add(1))
@@ -26,8 +37,332 @@ impl Memory {
     }
 }
 
+pub const ROM_START : u16 = 0x0000;
+pub const ROM_END : u16 = 0x7FFF;
+pub const VRAM_START : u16 = 0x8000;
+pub const VRAM_END : u16 = 0x9FFF;
+pub const EXTERNAL_RAM_START : u16 = 0xA000;
+pub const EXTERNAL_RAM_END : u16 = 0xBFFF;
+pub const WRAM_START : u16 = 0xC000;
+pub const WRAM_END : u16 = 0xDFFF;
+pub const ECHO_START : u16 = 0xE000;
+pub const ECHO_END : u16 = 0xFDFF;
+pub const OAM_START : u16 = 0xFE00;
+pub const OAM_END : u16 = 0xFE9F;
+pub const UNUSABLE_START : u16 = 0xFEA0;
+pub const UNUSABLE_END : u16 = 0xFEFF;
+pub const IO_START : u16 = 0xFF00;
+pub const IO_END : u16 = 0xFF7F;
+pub const HRAM_START : u16 = 0xFF80;
+pub const HRAM_END : u16 = 0xFFFE;
+
+/// CGB WRAM bank select for 0xD000..=0xDFFF
+pub const SVBK_ADDRESS : u16 = 0xFF70;
+const WRAM_BANK_SIZE : usize = 0x1000;
+
+/// Memory map of the console, dispatching each access to the region that owns it
+pub struct Memory {
+    cartridge : Box<dyn Mapper>,
+    ///Eight banks on the CGB, bank 0 fixed at 0xC000 and the rest switched in at 0xD000
+    wram : [u8; 0x8000],
+    wram_bank : usize,
+    ///Running on CGB hardware, and in CGB mode rather than DMG compatibility
+    cgb : bool,
+    cgb_mode : bool,
+    hram : [u8; 0x7F],
+
+    ///Overlays the cartridge until 0xFF50 is written
+    pub boot_rom : Option<BootRom>,
+    ///Set by writing 0xFF50, whether or not there was a boot ROM
+    key0_locked : bool,
+    pub interrupts : InterruptController,
+    pub ppu : Ppu,
+    pub dma : OamDma,
+    pub vram_dma : VramDma,
+    pub timer : Timer,
+    pub apu : Apu,
+    pub joypad : Joypad,
+    pub serial : Serial,
+    pub speed : SpeedSwitch,
+    ///Watches P1 for command packets and colours the screen when running on a Super Game Boy
+    pub sgb : Option<Sgb>,
+}
+
+impl Memory {
+    pub fn new(cartridge : Box<dyn Mapper>) -> Memory {
+        Memory { cartridge, ..Memory::default() }
+    }
+    /// A memory map with `rom` plugged in as a cartridge without banking or RAM
+    pub fn with_rom(rom : Vec<u8>) -> Memory {
+        Memory::new(Box::new(NoMapper::new(rom)))
+    }
+    pub fn cartridge(&self) -> &dyn Mapper {
+        self.cartridge.as_ref()
+    }
+    pub fn cartridge_mut(&mut self) -> &mut dyn Mapper {
+        self.cartridge.as_mut()
+    }
+    /// Switch to CGB hardware, which starts out in CGB mode until KEY0 says otherwise
+    pub fn set_cgb(&mut self, cgb : bool) {
+        self.cgb = cgb;
+        self.set_cgb_mode(cgb);
+    }
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+    fn set_cgb_mode(&mut self, cgb_mode : bool) {
+        self.cgb_mode = cgb_mode;
+        self.speed.cgb = cgb_mode;
+        self.serial.cgb = cgb_mode;
+        self.vram_dma.cgb = cgb_mode;
+        self.ppu.set_cgb(self.cgb, cgb_mode);
+        if !cgb_mode {
+            self.wram_bank = 1;
+        }
+    }
+    /// Index into WRAM of `offset` from the start of WRAM or echo RAM
+    fn wram_index(&self, offset : u16) -> usize {
+        let offset = offset as usize;
+        if offset < WRAM_BANK_SIZE {
+            offset
+        } else {
+            self.wram_bank * WRAM_BANK_SIZE + (offset - WRAM_BANK_SIZE)
+        }
+    }
+    fn read_io(&self, addr : u16) -> u8 {
+        match addr {
+            interrupts::IF_ADDRESS => self.interrupts.read_flags(),
+            joypad::P1_ADDRESS => match &self.sgb {
+                Some(sgb) => sgb.read_p1(self.joypad.read()),
+                None => self.joypad.read(),
+            },
+            serial::SB_ADDRESS | serial::SC_ADDRESS => self.serial.read(addr),
+            cpu::KEY1_ADDRESS => self.speed.read(),
+            boot::BOOT_OFF_ADDRESS | boot::KEY0_ADDRESS => 0xFF,
+            SVBK_ADDRESS if self.cgb_mode => 0xF8 | self.wram_bank as u8,
+            SVBK_ADDRESS => 0xFF,
+            dma::DMA_ADDRESS => self.dma.read(),
+            dma::HDMA1_ADDRESS..=dma::HDMA5_ADDRESS => self.vram_dma.read(addr),
+            timer::DIV_ADDRESS..=timer::TAC_ADDRESS => self.timer.read(addr),
+            apu::NR10_ADDRESS..=apu::WAVE_RAM_END => self.apu.read(addr),
+            ppu::LCDC_ADDRESS..=ppu::LYC_ADDRESS | ppu::BGP_ADDRESS..=ppu::WX_ADDRESS
+                | ppu::VBK_ADDRESS | ppu::BCPS_ADDRESS..=ppu::OCPD_ADDRESS => self.ppu.read(addr),
+            //Nothing drives the bus for unmapped registers
+            _ => 0xFF,
+        }
+    }
+    fn write_io(&mut self, addr : u16, data : u8) {
+        match addr {
+            interrupts::IF_ADDRESS => self.interrupts.write_flags(data),
+            joypad::P1_ADDRESS => {
+                self.joypad.write(data, &mut self.interrupts);
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_p1(data);
+                }
+            },
+            serial::SB_ADDRESS | serial::SC_ADDRESS => self.serial.write(addr, data),
+            cpu::KEY1_ADDRESS => self.speed.write(data),
+            boot::BOOT_OFF_ADDRESS => {
+                self.boot_rom = None;
+                self.key0_locked = true;
+            },
+            //Only the boot ROM gets to pick the mode
+            boot::KEY0_ADDRESS if self.cgb && !self.key0_locked => {
+                self.set_cgb_mode(data & boot::KEY0_DMG_MODE == 0);
+            },
+            //Bank 0 can't be switched in, selecting it gives bank 1
+            SVBK_ADDRESS if self.cgb_mode => self.wram_bank = (data as usize & 7).max(1),
+            dma::DMA_ADDRESS => self.dma.write(data),
+            dma::HDMA1_ADDRESS..=dma::HDMA5_ADDRESS => {
+                self.vram_dma.write(addr, data);
+                //HBlank DMA started during HBlank, or with the LCD off, copies its first block right away
+                if addr == dma::HDMA5_ADDRESS && self.ppu.mode() == ppu::Mode::HBlank {
+                    self.vram_dma.hblank();
+                }
+            },
+            timer::DIV_ADDRESS..=timer::TAC_ADDRESS => self.timer.write(addr, data),
+            apu::NR10_ADDRESS..=apu::WAVE_RAM_END => self.apu.write(addr, data),
+            ppu::LCDC_ADDRESS..=ppu::LYC_ADDRESS | ppu::BGP_ADDRESS..=ppu::WX_ADDRESS
+                | ppu::VBK_ADDRESS | ppu::BCPS_ADDRESS..=ppu::OCPD_ADDRESS => self.ppu.write(addr, data),
+            _ => (),
+        }
+    }
+    /// Read without the CPU's restrictions, as OAM DMA sees the bus
+    fn read_direct(&self, addr : u16) -> u8 {
+        match addr {
+            ROM_START..=ROM_END => match self.boot_rom.as_ref().and_then(|boot_rom| boot_rom.read(addr)) {
+                Some(data) => data,
+                None => self.cartridge.read_rom(addr),
+            },
+            VRAM_START..=VRAM_END => self.ppu.read_vram(addr - VRAM_START),
+            EXTERNAL_RAM_START..=EXTERNAL_RAM_END => self.cartridge.read_ram(addr),
+            WRAM_START..=WRAM_END => self.wram[self.wram_index(addr - WRAM_START)],
+            //Echo RAM mirrors the bottom of WRAM
+            ECHO_START..=ECHO_END => self.wram[self.wram_index(addr - ECHO_START)],
+            OAM_START..=OAM_END => self.ppu.read_oam(addr - OAM_START),
+            UNUSABLE_START..=UNUSABLE_END => 0x00,
+            IO_START..=IO_END => self.read_io(addr),
+            HRAM_START..=HRAM_END => self.hram[(addr - HRAM_START) as usize],
+            interrupts::IE_ADDRESS => self.interrupts.read_enable(),
+        }
+    }
+    /// Advance everything but the CPU by `cycles` clock cycles
+    fn tick_peripherals(&mut self, cycles : u8) {
+        //The PPU and APU keep their pace when the CPU switches to double speed
+        let clocks = if self.speed.double_speed { cycles / 2 } else { cycles };
+        self.ppu.tick(clocks, &mut self.interrupts);
+        self.apu.tick(clocks);
+        if self.ppu.take_hblank_started() {
+            self.vram_dma.hblank();
+        }
+        if self.ppu.take_vblank_started() {
+            if let Some(sgb) = &mut self.sgb {
+                sgb.frame(self.ppu.shades());
+            }
+        }
+
+        //The timer, serial port and DMA run off the CPU clock, so speed up with it
+        for _ in 0..cycles / 4 {
+            self.timer.step(&mut self.interrupts);
+            self.serial.step(&mut self.interrupts);
+            if let Some((source, index)) = self.dma.step() {
+                let data = self.read_direct(source);
+                self.ppu.write_oam_direct(index, data);
+            }
+        }
+    }
+}
+
+impl Bus for Memory {
+    fn read(&self, addr : u16) -> u8 {
+        //OAM DMA holds the bus, leaving the CPU only HRAM to run from
+        if self.dma.active() && !(HRAM_START..=HRAM_END).contains(&addr) {
+            return 0xFF;
+        }
+        self.read_direct(addr)
+    }
+    fn write(&mut self, addr : u16, data : u8) {
+        if self.dma.active() && !(HRAM_START..=HRAM_END).contains(&addr) {
+            return;
+        }
+        match addr {
+            ROM_START..=ROM_END => self.cartridge.write_rom(addr, data),
+            VRAM_START..=VRAM_END => self.ppu.write_vram(addr - VRAM_START, data),
+            EXTERNAL_RAM_START..=EXTERNAL_RAM_END => self.cartridge.write_ram(addr, data),
+            WRAM_START..=WRAM_END => self.wram[self.wram_index(addr - WRAM_START)] = data,
+            ECHO_START..=ECHO_END => self.wram[self.wram_index(addr - ECHO_START)] = data,
+            OAM_START..=OAM_END => self.ppu.write_oam(addr - OAM_START, data),
+            UNUSABLE_START..=UNUSABLE_END => (),
+            IO_START..=IO_END => self.write_io(addr, data),
+            HRAM_START..=HRAM_END => self.hram[(addr - HRAM_START) as usize] = data,
+            interrupts::IE_ADDRESS => self.interrupts.write_enable(data),
+        }
+    }
+    fn tick(&mut self, cycles : u8) -> u32 {
+        self.tick_peripherals(cycles);
+
+        //The CPU sits out VRAM DMA while everything else carries on, for 32 dots a block at either speed
+        let block_cycles = if self.speed.double_speed { 64 } else { 32 };
+        let mut stalled = 0;
+        while let Some((source, destination)) = self.vram_dma.next_block() {
+            for offset in 0..dma::VRAM_DMA_BLOCK {
+                let data = self.read_direct(source.wrapping_add(offset));
+                self.ppu.write_vram(destination + offset, data);
+            }
+            self.tick_peripherals(block_cycles);
+            stalled += block_cycles as u32;
+        }
+        stalled
+    }
+
+    fn interrupts(&self) -> &InterruptController {
+        &self.interrupts
+    }
+    fn interrupts_mut(&mut self) -> &mut InterruptController {
+        &mut self.interrupts
+    }
+    fn stop(&mut self) -> bool {
+        //STOP resets DIV whether or not it switches speed
+        self.timer.write(timer::DIV_ADDRESS, 0);
+        self.speed.switch()
+    }
+}
+
 impl Default for Memory {
     fn default() -> Self {
-        Memory { data: [0_u8; 0xffff] }
+        Memory {
+            cartridge : Box::new(NoMapper::new(Vec::new())),
+            wram : [0; 0x8000],
+            wram_bank : 1,
+            cgb : false,
+            cgb_mode : false,
+            hram : [0; 0x7F],
+            boot_rom : None,
+            key0_locked : false,
+            interrupts : InterruptController::default(),
+            ppu : Ppu::new(),
+            dma : OamDma::default(),
+            vram_dma : VramDma::default(),
+            timer : Timer::default(),
+            apu : Apu::default(),
+            joypad : Joypad::default(),
+            serial : Serial::default(),
+            speed : SpeedSwitch::default(),
+            sgb : None,
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_dispatch_by_address() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0150] = 0x12;
+        let mut memory = Memory::with_rom(rom);
+        assert_eq!(memory.read(0x0150), 0x12);
+        //ROM writes go to the mapper, which has nothing to switch
+        memory.write(0x0150, 0x34);
+        assert_eq!(memory.read(0x0150), 0x12);
+
+        for addr in [VRAM_START, VRAM_END, WRAM_START, WRAM_END, OAM_START, OAM_END, HRAM_START, HRAM_END] {
+            memory.write(addr, addr as u8 ^ 0xA5);
+            assert_eq!(memory.read(addr), addr as u8 ^ 0xA5, "{:04X}", addr);
+        }
+    }
+
+    #[test]
+    fn echo_ram_mirrors_wram() {
+        let mut memory = Memory::default();
+        memory.write(0xC123, 0x45);
+        assert_eq!(memory.read(0xE123), 0x45);
+        memory.write(ECHO_END, 0x67);
+        assert_eq!(memory.read(ECHO_END - ECHO_START + WRAM_START), 0x67);
+    }
+
+    #[test]
+    fn unusable_range_and_unmapped_io() {
+        let mut memory = Memory::default();
+        memory.write(UNUSABLE_START, 0x12);
+        memory.write(UNUSABLE_END, 0x12);
+        assert_eq!(memory.read(UNUSABLE_START), 0x00);
+        assert_eq!(memory.read(UNUSABLE_END), 0x00);
+
+        //0xFF03 has nothing behind it
+        memory.write(0xFF03, 0x12);
+        assert_eq!(memory.read(0xFF03), 0xFF);
+    }
+
+    #[test]
+    fn ie_sits_at_the_top() {
+        let mut memory = Memory::default();
+        memory.write(interrupts::IE_ADDRESS, 0x15);
+        assert_eq!(memory.read(interrupts::IE_ADDRESS), 0x15);
+        assert_eq!(memory.interrupts.read_enable(), 0x15);
+        //HRAM ends just below it
+        memory.write(HRAM_END, 0x00);
+        assert_eq!(memory.read(interrupts::IE_ADDRESS), 0x15);
+    }
+}
@@ -0,0 +1,778 @@
+use crate::interrupts::{Interrupt, InterruptController};
+
+mod fifo;
+mod palette;
+
+use palette::PaletteRam;
+
+pub const SCREEN_WIDTH : usize = 160;
+pub const SCREEN_HEIGHT : usize = 144;
+
+pub const LCDC_ADDRESS : u16 = 0xFF40;
+pub const STAT_ADDRESS : u16 = 0xFF41;
+pub const SCY_ADDRESS : u16 = 0xFF42;
+pub const SCX_ADDRESS : u16 = 0xFF43;
+pub const LY_ADDRESS : u16 = 0xFF44;
+pub const LYC_ADDRESS : u16 = 0xFF45;
+pub const BGP_ADDRESS : u16 = 0xFF47;
+pub const OBP0_ADDRESS : u16 = 0xFF48;
+pub const OBP1_ADDRESS : u16 = 0xFF49;
+pub const WY_ADDRESS : u16 = 0xFF4A;
+pub const WX_ADDRESS : u16 = 0xFF4B;
+pub const VBK_ADDRESS : u16 = 0xFF4F;
+pub const BCPS_ADDRESS : u16 = 0xFF68;
+pub const BCPD_ADDRESS : u16 = 0xFF69;
+pub const OCPS_ADDRESS : u16 = 0xFF6A;
+pub const OCPD_ADDRESS : u16 = 0xFF6B;
+
+const DOTS_PER_LINE : u16 = 456;
+const OAM_SCAN_DOTS : u16 = 80;
+/// Shortest mode 3, without scrolling, window or sprites
+const MIN_DRAWING_DOTS : u16 = 172;
+const VISIBLE_LINES : u8 = 144;
+const LINES_PER_FRAME : u8 = 154;
+pub const FRAME_DOTS : u32 = DOTS_PER_LINE as u32 * LINES_PER_FRAME as u32;
+const MAX_SPRITES_PER_LINE : usize = 10;
+
+const LCD_ENABLE : u8 = 0x80;
+const WINDOW_MAP : u8 = 0x40;
+const WINDOW_ENABLE : u8 = 0x20;
+const TILE_DATA : u8 = 0x10;
+const BG_MAP : u8 = 0x08;
+const SPRITE_SIZE : u8 = 0x04;
+const SPRITE_ENABLE : u8 = 0x02;
+const BG_ENABLE : u8 = 0x01;
+
+const LYC_SELECT : u8 = 0x40;
+const OAM_SCAN_SELECT : u8 = 0x20;
+const VBLANK_SELECT : u8 = 0x10;
+const HBLANK_SELECT : u8 = 0x08;
+const STAT_SELECTS : u8 = LYC_SELECT | OAM_SCAN_SELECT | VBLANK_SELECT | HBLANK_SELECT;
+const LYC_EQUAL : u8 = 0x04;
+
+//Sprite flags. In CGB mode, BG map attributes in VRAM bank 1 share the layout apart from the palette select.
+const BEHIND_BG : u8 = 0x80;
+const BG_PRIORITY : u8 = 0x80;
+const Y_FLIP : u8 = 0x40;
+const X_FLIP : u8 = 0x20;
+const OBP1_SELECT : u8 = 0x10;
+const VRAM_BANK : u8 = 0x08;
+const CGB_PALETTE : u8 = 0x07;
+
+/// DMG shades as 15-bit colours, white to black
+const DMG_COLORS : [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+/// PPU modes, numbered as they read in the bottom of STAT
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    HBlank = 0, VBlank = 1, OamScan = 2, Drawing = 3,
+}
+
+/// How mode 3 turns VRAM into pixels
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Renderer {
+    /// Draw each line in one go as mode 3 starts, estimating how long mode 3 takes
+    #[default]
+    Scanline,
+    /// Model the pixel FIFOs and fetchers dot by dot, so mid-line register writes land where they should
+    Fifo,
+}
+
+/// An OAM entry selected for the current line
+#[derive(Clone, Copy)]
+struct Sprite {
+    y : u8,
+    x : u8,
+    tile : u8,
+    flags : u8,
+}
+
+/// Picture processing unit
+pub struct Ppu {
+    renderer : Renderer,
+    ///Running on a CGB, so colours come from palette RAM even in DMG compatibility mode
+    cgb : bool,
+    ///CGB features are enabled, rather than DMG compatibility
+    cgb_mode : bool,
+
+    ///Tile data and maps in bank 0, tile data and BG map attributes in bank 1
+    vram : [[u8; 0x2000]; 2],
+    vram_bank : usize,
+    oam : [u8; 0xA0],
+    bg_palettes : PaletteRam,
+    obj_palettes : PaletteRam,
+
+    lcdc : u8,
+    ///Interrupt selects of STAT, the rest of it is derived from the PPU state
+    stat : u8,
+    scy : u8,
+    scx : u8,
+    ly : u8,
+    lyc : u8,
+    bgp : u8,
+    obp0 : u8,
+    obp1 : u8,
+    wy : u8,
+    wx : u8,
+
+    mode : Mode,
+    ///Dot within the current line
+    dot : u16,
+    ///Length of mode 3 on the current line
+    drawing_dots : u16,
+    ///Line of the window to draw next, which only advances on lines the window was drawn on
+    window_line : u8,
+    ///Whether LY has matched WY this frame
+    window_triggered : bool,
+    sprites : Vec<Sprite>,
+    line : fifo::LineState,
+    ///STAT interrupts fire on the rising edge of the OR of all selected sources
+    stat_line : bool,
+
+    ///15-bit colours, red in the low bits
+    framebuffer : Box<[u16; SCREEN_WIDTH * SCREEN_HEIGHT]>,
+    ///Shades 0 to 3 out of the DMG palettes, which the SGB colours itself
+    shades : Box<[u8; SCREEN_WIDTH * SCREEN_HEIGHT]>,
+    frame_ready : bool,
+    ///Set entering VBlank, for the SGB
+    vblank_started : bool,
+    ///Set going from mode 3 to HBlank, for HBlank DMA
+    hblank_started : bool,
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu {
+            renderer : Renderer::Scanline,
+            cgb : false,
+            cgb_mode : false,
+            vram : [[0; 0x2000]; 2],
+            vram_bank : 0,
+            oam : [0; 0xA0],
+            bg_palettes : PaletteRam::new(),
+            obj_palettes : PaletteRam::new(),
+            lcdc : 0,
+            stat : 0,
+            scy : 0,
+            scx : 0,
+            ly : 0,
+            lyc : 0,
+            bgp : 0,
+            obp0 : 0,
+            obp1 : 0,
+            wy : 0,
+            wx : 0,
+            mode : Mode::HBlank,
+            dot : 0,
+            drawing_dots : MIN_DRAWING_DOTS,
+            window_line : 0,
+            window_triggered : false,
+            sprites : Vec::with_capacity(MAX_SPRITES_PER_LINE),
+            line : fifo::LineState::default(),
+            stat_line : false,
+            framebuffer : Box::new([0; SCREEN_WIDTH * SCREEN_HEIGHT]),
+            shades : Box::new([0; SCREEN_WIDTH * SCREEN_HEIGHT]),
+            frame_ready : false,
+            vblank_started : false,
+            hblank_started : false,
+        }
+    }
+    pub fn set_renderer(&mut self, renderer : Renderer) {
+        self.renderer = renderer;
+    }
+    /// Select CGB hardware, and whether it's in CGB mode or DMG compatibility mode
+    pub fn set_cgb(&mut self, cgb : bool, cgb_mode : bool) {
+        self.cgb = cgb;
+        self.cgb_mode = cgb && cgb_mode;
+        if !self.cgb_mode {
+            self.vram_bank = 0;
+        }
+    }
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+    pub fn framebuffer(&self) -> &[u16] {
+        self.framebuffer.as_slice()
+    }
+    /// Shades of the DMG palettes behind each pixel of the framebuffer
+    pub fn shades(&self) -> &[u8] {
+        self.shades.as_slice()
+    }
+    /// Whether a frame finished since the last call
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+    /// Like `take_frame_ready`, but kept apart so the SGB doesn't take frames from whoever's displaying them
+    pub fn take_vblank_started(&mut self) -> bool {
+        std::mem::take(&mut self.vblank_started)
+    }
+    /// Whether a visible line entered HBlank since the last call
+    pub fn take_hblank_started(&mut self) -> bool {
+        std::mem::take(&mut self.hblank_started)
+    }
+
+    /// VRAM as the CPU sees it, which is locked while the PPU is drawing
+    pub fn read_vram(&self, addr : u16) -> u8 {
+        match self.mode {
+            Mode::Drawing => 0xFF,
+            _ => self.vram[self.vram_bank][addr as usize & 0x1FFF],
+        }
+    }
+    pub fn write_vram(&mut self, addr : u16, data : u8) {
+        if self.mode != Mode::Drawing {
+            self.vram[self.vram_bank][addr as usize & 0x1FFF] = data;
+        }
+    }
+    /// OAM as the CPU sees it, which is locked while the PPU is scanning or drawing
+    pub fn read_oam(&self, addr : u16) -> u8 {
+        match self.mode {
+            Mode::OamScan | Mode::Drawing => 0xFF,
+            _ => self.oam[addr as usize % self.oam.len()],
+        }
+    }
+    pub fn write_oam(&mut self, addr : u16, data : u8) {
+        if !matches!(self.mode, Mode::OamScan | Mode::Drawing) {
+            self.oam[addr as usize % self.oam.len()] = data;
+        }
+    }
+    /// OAM DMA writes regardless of what the PPU is doing
+    pub fn write_oam_direct(&mut self, index : u8, data : u8) {
+        self.oam[index as usize % self.oam.len()] = data;
+    }
+
+    pub fn read(&self, addr : u16) -> u8 {
+        match addr {
+            LCDC_ADDRESS => self.lcdc,
+            STAT_ADDRESS => {
+                let lyc_equal = if self.ly == self.lyc { LYC_EQUAL } else { 0 };
+                0x80 | self.stat | lyc_equal | self.mode as u8
+            },
+            SCY_ADDRESS => self.scy,
+            SCX_ADDRESS => self.scx,
+            LY_ADDRESS => self.ly,
+            LYC_ADDRESS => self.lyc,
+            BGP_ADDRESS => self.bgp,
+            OBP0_ADDRESS => self.obp0,
+            OBP1_ADDRESS => self.obp1,
+            WY_ADDRESS => self.wy,
+            WX_ADDRESS => self.wx,
+            //The CGB registers are hidden in DMG compatibility mode
+            _ if !self.cgb_mode => 0xFF,
+            VBK_ADDRESS => 0xFE | self.vram_bank as u8,
+            BCPS_ADDRESS => self.bg_palettes.read_index(),
+            OCPS_ADDRESS => self.obj_palettes.read_index(),
+            //Palette RAM is locked along with VRAM
+            BCPD_ADDRESS | OCPD_ADDRESS if self.mode == Mode::Drawing => 0xFF,
+            BCPD_ADDRESS => self.bg_palettes.read_data(),
+            OCPD_ADDRESS => self.obj_palettes.read_data(),
+            _ => 0xFF,
+        }
+    }
+    pub fn write(&mut self, addr : u16, data : u8) {
+        match addr {
+            LCDC_ADDRESS => self.write_lcdc(data),
+            STAT_ADDRESS => self.stat = data & STAT_SELECTS,
+            SCY_ADDRESS => self.scy = data,
+            SCX_ADDRESS => self.scx = data,
+            //LY is read only
+            LY_ADDRESS => (),
+            LYC_ADDRESS => self.lyc = data,
+            BGP_ADDRESS => self.bgp = data,
+            OBP0_ADDRESS => self.obp0 = data,
+            OBP1_ADDRESS => self.obp1 = data,
+            WY_ADDRESS => self.wy = data,
+            WX_ADDRESS => self.wx = data,
+            _ if !self.cgb_mode => (),
+            VBK_ADDRESS => self.vram_bank = (data & 1) as usize,
+            BCPS_ADDRESS => self.bg_palettes.write_index(data),
+            BCPD_ADDRESS => self.bg_palettes.write_data(data, self.mode == Mode::Drawing),
+            OCPS_ADDRESS => self.obj_palettes.write_index(data),
+            OCPD_ADDRESS => self.obj_palettes.write_data(data, self.mode == Mode::Drawing),
+            _ => (),
+        }
+    }
+    fn write_lcdc(&mut self, data : u8) {
+        let was_enabled = self.lcdc & LCD_ENABLE != 0;
+        self.lcdc = data;
+        match (was_enabled, data & LCD_ENABLE != 0) {
+            //Turning the LCD off resets it to the top of the screen, with VRAM and OAM unlocked
+            (true, false) => {
+                self.ly = 0;
+                self.dot = 0;
+                self.mode = Mode::HBlank;
+            },
+            (false, true) => {
+                self.window_line = 0;
+                self.window_triggered = false;
+                self.start_line();
+            },
+            _ => (),
+        }
+    }
+
+    /// Advance by `dots` PPU clocks
+    pub fn tick(&mut self, dots : u8, interrupts : &mut InterruptController) {
+        if self.lcdc & LCD_ENABLE == 0 {
+            return;
+        }
+        for _ in 0..dots {
+            self.step_dot(interrupts);
+        }
+    }
+    fn step_dot(&mut self, interrupts : &mut InterruptController) {
+        self.dot += 1;
+        match self.mode {
+            Mode::OamScan if self.dot == OAM_SCAN_DOTS => {
+                self.scan_oam();
+                match self.renderer {
+                    Renderer::Scanline => {
+                        self.drawing_dots = self.drawing_length();
+                        self.render_line();
+                    },
+                    Renderer::Fifo => self.start_fifo_line(),
+                }
+                self.mode = Mode::Drawing;
+            },
+            Mode::Drawing => {
+                let finished = match self.renderer {
+                    Renderer::Scanline => self.dot == OAM_SCAN_DOTS + self.drawing_dots,
+                    Renderer::Fifo => self.step_fifo(),
+                };
+                if finished {
+                    self.mode = Mode::HBlank;
+                    self.hblank_started = true;
+                }
+            },
+            _ => (),
+        }
+
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.ly += 1;
+            if self.ly == VISIBLE_LINES {
+                self.mode = Mode::VBlank;
+                self.frame_ready = true;
+                self.vblank_started = true;
+                self.window_line = 0;
+                self.window_triggered = false;
+                interrupts.request(Interrupt::VBlank);
+            } else if self.ly == LINES_PER_FRAME {
+                self.ly = 0;
+                self.start_line();
+            } else if self.ly < VISIBLE_LINES {
+                self.start_line();
+            }
+        }
+        self.update_stat_line(interrupts);
+    }
+    fn start_line(&mut self) {
+        if self.ly == self.wy {
+            self.window_triggered = true;
+        }
+        self.mode = Mode::OamScan;
+    }
+    fn update_stat_line(&mut self, interrupts : &mut InterruptController) {
+        let selected = |select : u8| self.stat & select != 0;
+        let line = (selected(LYC_SELECT) && self.ly == self.lyc) || match self.mode {
+            Mode::HBlank => selected(HBLANK_SELECT),
+            Mode::VBlank => selected(VBLANK_SELECT),
+            Mode::OamScan => selected(OAM_SCAN_SELECT),
+            Mode::Drawing => false,
+        };
+        if line && !self.stat_line {
+            interrupts.request(Interrupt::LcdStat);
+        }
+        self.stat_line = line;
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc & SPRITE_SIZE != 0 { 16 } else { 8 }
+    }
+    /// Pick the first ten sprites in OAM overlapping this line
+    fn scan_oam(&mut self) {
+        let height = self.sprite_height();
+        self.sprites.clear();
+        for entry in self.oam.chunks_exact(4) {
+            let row = (self.ly as i16) + 16 - entry[0] as i16;
+            if (0..height as i16).contains(&row) {
+                self.sprites.push(Sprite { y : entry[0], x : entry[1], tile : entry[2], flags : entry[3] });
+                if self.sprites.len() == MAX_SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+    }
+    fn window_visible(&self) -> bool {
+        //On the DMG, clearing the BG enable also hides the window. In CGB mode it only takes away the BG's priority.
+        let enables = if self.cgb_mode { WINDOW_ENABLE } else { WINDOW_ENABLE | BG_ENABLE };
+        self.lcdc & enables == enables && self.window_triggered && self.wx <= 166
+    }
+    /// Mode 3 is stretched by fine scrolling, the window starting, and fetching sprites
+    fn drawing_length(&self) -> u16 {
+        let mut dots = MIN_DRAWING_DOTS + (self.scx & 7) as u16;
+        if self.window_visible() {
+            dots += 6;
+        }
+        if self.lcdc & SPRITE_ENABLE != 0 {
+            for sprite in &self.sprites {
+                dots += 11 - 5.min((sprite.x.wrapping_add(self.scx) & 7) as u16);
+            }
+        }
+        dots
+    }
+
+    /// Colour index of pixel `x`, `y` of the tile whose data starts at `address` in VRAM bank `bank`.
+    /// `y` may run into the next tile.
+    fn tile_pixel(&self, bank : usize, address : usize, x : u8, y : u8) -> u8 {
+        let low = self.vram[bank][address + y as usize * 2];
+        let high = self.vram[bank][address + y as usize * 2 + 1];
+        let bit = 7 - x;
+        ((high >> bit) & 1) << 1 | (low >> bit) & 1
+    }
+    /// Start of background or window tile `tile` in VRAM
+    fn tile_data_address(&self, tile : u8) -> usize {
+        if self.lcdc & TILE_DATA != 0 {
+            tile as usize * 16
+        } else {
+            //Signed tile indices relative to 0x9000
+            (0x1000 + tile as i8 as i32 * 16) as usize
+        }
+    }
+    /// Tile number and attributes at tile `column`, `row` of the tile map selected by LCDC bit `map_select`
+    fn tile_map_entry(&self, map_select : u8, column : u8, row : u8) -> (u8, u8) {
+        let map = if self.lcdc & map_select != 0 { 0x1C00 } else { 0x1800 };
+        let index = map + (row as usize & 31) * 32 + (column as usize & 31);
+        let attributes = if self.cgb_mode { self.vram[1][index] } else { 0 };
+        (self.vram[0][index], attributes)
+    }
+    /// Row `y` of the tile, flipped and banked according to its attributes, as (VRAM bank, address)
+    fn tile_row(&self, tile : u8, attributes : u8, y : u8) -> (usize, usize) {
+        let y = if attributes & Y_FLIP != 0 { 7 - y } else { y };
+        let bank = if attributes & VRAM_BANK != 0 { 1 } else { 0 };
+        (bank, self.tile_data_address(tile) + y as usize * 2)
+    }
+    /// Colour index and attributes at `x`, `y` of the tile map selected by LCDC bit `map_select`
+    fn tile_map_pixel(&self, map_select : u8, x : u8, y : u8) -> (u8, u8) {
+        let (tile, attributes) = self.tile_map_entry(map_select, x / 8, y / 8);
+        let (bank, address) = self.tile_row(tile, attributes, y % 8);
+        let column = if attributes & X_FLIP != 0 { 7 - x % 8 } else { x % 8 };
+        (self.tile_pixel(bank, address, column, 0), attributes)
+    }
+    fn sprite_pixel(&self, sprite : &Sprite, x : u8) -> u8 {
+        let height = self.sprite_height();
+        let mut row = self.ly + 16 - sprite.y;
+        if sprite.flags & Y_FLIP != 0 {
+            row = height - 1 - row;
+        }
+        let column = if sprite.flags & X_FLIP != 0 { 7 - x } else { x };
+        //Tall sprites ignore the bottom bit of the tile, the next tile is the bottom half
+        let tile = if height == 16 { sprite.tile & 0xFE } else { sprite.tile };
+        let bank = if self.cgb_mode && sprite.flags & VRAM_BANK != 0 { 1 } else { 0 };
+        self.tile_pixel(bank, tile as usize * 16, column, row)
+    }
+    /// Whether a sprite pixel of colour `color` shows over a background pixel
+    fn sprite_visible(&self, flags : u8, color : u8, bg_color : u8, bg_attributes : u8) -> bool {
+        if color == 0 {
+            return false;
+        }
+        //In CGB mode, clearing the BG enable puts every sprite on top
+        if bg_color == 0 || (self.cgb_mode && self.lcdc & BG_ENABLE == 0) {
+            return true;
+        }
+        flags & BEHIND_BG == 0 && bg_attributes & BG_PRIORITY == 0
+    }
+    /// Output colour and DMG shade of a background pixel
+    fn background_output(&self, attributes : u8, color : u8) -> (u16, u8) {
+        if self.cgb_mode {
+            return (self.bg_palettes.color(attributes & CGB_PALETTE, color), color);
+        }
+        let shade = palette_shade(self.bgp, color);
+        //DMG compatibility mode runs BGP through the first CGB palette
+        let output = if self.cgb { self.bg_palettes.color(0, shade) } else { DMG_COLORS[shade as usize] };
+        (output, shade)
+    }
+    fn sprite_output(&self, flags : u8, color : u8) -> (u16, u8) {
+        if self.cgb_mode {
+            return (self.obj_palettes.color(flags & CGB_PALETTE, color), color);
+        }
+        let (palette, number) = if flags & OBP1_SELECT != 0 { (self.obp1, 1) } else { (self.obp0, 0) };
+        let shade = palette_shade(palette, color);
+        let output = if self.cgb { self.obj_palettes.color(number, shade) } else { DMG_COLORS[shade as usize] };
+        (output, shade)
+    }
+    fn put_pixel(&mut self, x : usize, (output, shade) : (u16, u8)) {
+        let index = self.ly as usize * SCREEN_WIDTH + x;
+        self.framebuffer[index] = output;
+        self.shades[index] = shade;
+    }
+    fn render_line(&mut self) {
+        let mut bg_pixels = [(0_u8, 0_u8); SCREEN_WIDTH];
+        let window = self.window_visible();
+        //The window's left edge is at WX - 7
+        let window_x = self.wx as i16 - 7;
+        //On the DMG, clearing the BG enable blanks it
+        if self.cgb_mode || self.lcdc & BG_ENABLE != 0 {
+            for (x, pixel) in bg_pixels.iter_mut().enumerate() {
+                *pixel = if window && x as i16 >= window_x {
+                    self.tile_map_pixel(WINDOW_MAP, (x as i16 - window_x) as u8, self.window_line)
+                } else {
+                    self.tile_map_pixel(BG_MAP, self.scx.wrapping_add(x as u8), self.scy.wrapping_add(self.ly))
+                };
+            }
+        }
+        if window {
+            self.window_line += 1;
+        }
+
+        let mut line = [(0_u16, 0_u8); SCREEN_WIDTH];
+        for (output, &(color, attributes)) in line.iter_mut().zip(&bg_pixels) {
+            *output = self.background_output(attributes, color);
+        }
+
+        if self.lcdc & SPRITE_ENABLE != 0 {
+            //Leftmost sprites win, ties going to the earlier one in OAM. In CGB mode only OAM order counts.
+            let mut sprites = self.sprites.clone();
+            if !self.cgb_mode {
+                sprites.sort_by_key(|sprite| sprite.x);
+            }
+            for (x, output) in line.iter_mut().enumerate() {
+                for sprite in &sprites {
+                    let column = x as i16 + 8 - sprite.x as i16;
+                    if !(0..8).contains(&column) {
+                        continue;
+                    }
+                    let color = self.sprite_pixel(sprite, column as u8);
+                    if color == 0 {
+                        continue;
+                    }
+                    let (bg_color, bg_attributes) = bg_pixels[x];
+                    if self.sprite_visible(sprite.flags, color, bg_color, bg_attributes) {
+                        *output = self.sprite_output(sprite.flags, color);
+                    }
+                    break;
+                }
+            }
+        }
+
+        for (x, output) in line.into_iter().enumerate() {
+            self.put_pixel(x, output);
+        }
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Ppu::new()
+    }
+}
+
+fn palette_shade(palette : u8, color : u8) -> u8 {
+    (palette >> (color * 2)) & 0b11
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_dots(ppu : &mut Ppu, interrupts : &mut InterruptController, dots : u32) {
+        for _ in 0..dots {
+            ppu.tick(1, interrupts);
+        }
+    }
+
+    #[test]
+    fn line_and_frame_timing() {
+        let mut ppu = Ppu::new();
+        let mut interrupts = InterruptController::default();
+        ppu.write(LCDC_ADDRESS, LCD_ENABLE | BG_ENABLE);
+
+        assert_eq!(ppu.mode(), Mode::OamScan);
+        run_dots(&mut ppu, &mut interrupts, OAM_SCAN_DOTS as u32);
+        assert_eq!(ppu.mode(), Mode::Drawing);
+        run_dots(&mut ppu, &mut interrupts, MIN_DRAWING_DOTS as u32);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+
+        run_dots(&mut ppu, &mut interrupts, (DOTS_PER_LINE - OAM_SCAN_DOTS - MIN_DRAWING_DOTS) as u32);
+        assert_eq!(ppu.read(LY_ADDRESS), 1);
+
+        run_dots(&mut ppu, &mut interrupts, DOTS_PER_LINE as u32 * 143 - 1);
+        assert_eq!(interrupts.requested(), 0);
+        run_dots(&mut ppu, &mut interrupts, 1);
+        assert_eq!(ppu.mode(), Mode::VBlank);
+        assert_eq!(interrupts.requested(), Interrupt::VBlank.mask());
+        assert!(ppu.take_frame_ready());
+    }
+
+    /// STAT interrupts requested over a frame from turning the LCD on with `stat` and `lyc`
+    fn stat_interrupts(stat : u8, lyc : u8) -> Vec<u8> {
+        let mut ppu = Ppu::new();
+        let mut interrupts = InterruptController::default();
+        ppu.write(STAT_ADDRESS, stat);
+        ppu.write(LYC_ADDRESS, lyc);
+        ppu.write(LCDC_ADDRESS, LCD_ENABLE | BG_ENABLE);
+        let mut lines = Vec::new();
+        //Stop short of the next frame's first OAM scan
+        for _ in 0..FRAME_DOTS - 1 {
+            ppu.tick(1, &mut interrupts);
+            if interrupts.requested() & Interrupt::LcdStat.mask() != 0 {
+                interrupts.acknowledge(Interrupt::LcdStat);
+                lines.push(ppu.read(LY_ADDRESS));
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn stat_interrupt_sources() {
+        //LY passing LYC requests once, as it reaches it
+        assert_eq!(stat_interrupts(LYC_SELECT, 5), [5]);
+        assert_eq!(stat_interrupts(VBLANK_SELECT, 0), [144]);
+        assert_eq!(stat_interrupts(HBLANK_SELECT, 0), (0..144).collect::<Vec<_>>());
+        assert_eq!(stat_interrupts(OAM_SCAN_SELECT, 0), (0..144).collect::<Vec<_>>());
+        //Nothing selected, nothing requested
+        assert_eq!(stat_interrupts(0, 5), []);
+    }
+
+    #[test]
+    fn stat_interrupt_blocking() {
+        //HBlank runs straight into the next OAM scan, so only line 0's scan sees the line rise
+        let mut expected = vec![0];
+        expected.extend(0..144);
+        assert_eq!(stat_interrupts(HBLANK_SELECT | OAM_SCAN_SELECT, 0), expected);
+
+        //Line 4's HBlank holds the line high into LY=LYC, which then holds it through line 5's HBlank
+        let expected = (0..144).filter(|line| *line != 5).collect::<Vec<_>>();
+        assert_eq!(stat_interrupts(HBLANK_SELECT | LYC_SELECT, 5), expected);
+    }
+
+    #[test]
+    fn sprites_draw_over_background() {
+        let mut ppu = Ppu::new();
+        let mut interrupts = InterruptController::default();
+        //Tile 0 is solid colour 1, tile 1 solid colour 3
+        for row in 0..8 {
+            ppu.write_vram(row * 2, 0xFF);
+            ppu.write_vram(16 + row * 2, 0xFF);
+            ppu.write_vram(16 + row * 2 + 1, 0xFF);
+        }
+        //A sprite at the top left of the screen using tile 1, flipped so it still covers the same pixels
+        ppu.write_oam(0, 16);
+        ppu.write_oam(1, 8);
+        ppu.write_oam(2, 1);
+        ppu.write_oam(3, X_FLIP);
+        ppu.write(BGP_ADDRESS, 0b11_10_01_00);
+        ppu.write(OBP0_ADDRESS, 0b00_11_10_01);
+        ppu.write(LCDC_ADDRESS, LCD_ENABLE | TILE_DATA | SPRITE_ENABLE | BG_ENABLE);
+
+        run_dots(&mut ppu, &mut interrupts, OAM_SCAN_DOTS as u32);
+        assert_eq!(ppu.framebuffer()[7], DMG_COLORS[0b00]);
+        assert_eq!(ppu.framebuffer()[8], DMG_COLORS[0b01]);
+    }
+
+    #[test]
+    fn cgb_attributes_and_palettes() {
+        let mut ppu = Ppu::new();
+        let mut interrupts = InterruptController::default();
+        ppu.set_cgb(true, true);
+        //Tile 0 in bank 1 has a left column of colour 1, used X flipped with palette 2 by the first map entry
+        ppu.write(VBK_ADDRESS, 1);
+        assert_eq!(ppu.read(VBK_ADDRESS), 0xFF);
+        for row in 0..8 {
+            ppu.write_vram(row * 2, 0x80);
+        }
+        ppu.write_vram(0x1800, X_FLIP | VRAM_BANK | 2);
+        ppu.write(VBK_ADDRESS, 0);
+
+        //Colour 1 of BG palette 2, written with auto-increment
+        ppu.write(BCPS_ADDRESS, 0x80 | (2 * 8 + 2));
+        ppu.write(BCPD_ADDRESS, 0x1F);
+        ppu.write(BCPD_ADDRESS, 0x7C);
+        assert_eq!(ppu.read(BCPS_ADDRESS), 0xC0 | (2 * 8 + 4));
+        ppu.write(BCPS_ADDRESS, 2 * 8 + 2);
+        assert_eq!(ppu.read(BCPD_ADDRESS), 0x1F);
+        ppu.write(LCDC_ADDRESS, LCD_ENABLE | TILE_DATA | BG_ENABLE);
+
+        run_dots(&mut ppu, &mut interrupts, OAM_SCAN_DOTS as u32);
+        assert_eq!(ppu.framebuffer()[0], 0x0000);
+        assert_eq!(ppu.framebuffer()[7], 0x7C1F);
+        //Palette RAM is locked while drawing, but the index still moves on
+        ppu.write(BCPS_ADDRESS, 0x80);
+        ppu.write(BCPD_ADDRESS, 0x55);
+        assert_eq!(ppu.read(BCPD_ADDRESS), 0xFF);
+        assert_eq!(ppu.read(BCPS_ADDRESS), 0xC1);
+    }
+
+    /// Set up a frame with scrolled background, window and overlapping sprites.
+    /// In CGB mode, BG attributes, sprite banks and palettes are filled in too.
+    fn busy_scene(renderer : Renderer, cgb : bool) -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.set_renderer(renderer);
+        ppu.set_cgb(cgb, cgb);
+        for (index, byte) in ppu.vram[0][..0x1800].iter_mut().enumerate() {
+            *byte = (index * 7 % 251) as u8;
+        }
+        for (index, tile) in ppu.vram[0][0x1800..].iter_mut().enumerate() {
+            *tile = (index % 64) as u8;
+        }
+        for (index, byte) in ppu.vram[1].iter_mut().enumerate() {
+            *byte = (index * 13 % 241) as u8;
+        }
+        let flags_mask = if cgb { 0xFF } else { 0xF0 };
+        for (index, entry) in ppu.oam.chunks_exact_mut(4).enumerate() {
+            entry.copy_from_slice(&[16 + index as u8 * 3, 4 + index as u8 * 5, index as u8, (index * 0x35) as u8 & flags_mask]);
+        }
+        for address in [BCPS_ADDRESS, OCPS_ADDRESS] {
+            ppu.write(address, 0x80);
+            for index in 0..64 {
+                ppu.write(address + 1, (index * 29 + address as usize) as u8);
+            }
+        }
+        ppu.write(SCX_ADDRESS, 13);
+        ppu.write(SCY_ADDRESS, 5);
+        ppu.write(WY_ADDRESS, 40);
+        ppu.write(WX_ADDRESS, 87);
+        ppu.write(BGP_ADDRESS, 0b11_10_01_00);
+        ppu.write(OBP0_ADDRESS, 0b00_01_10_11);
+        ppu.write(OBP1_ADDRESS, 0b01_10_11_00);
+        ppu.write(LCDC_ADDRESS, LCD_ENABLE | WINDOW_MAP | WINDOW_ENABLE | TILE_DATA | SPRITE_ENABLE | BG_ENABLE);
+        ppu
+    }
+
+    #[test]
+    fn fifo_matches_scanline() {
+        let mut interrupts = InterruptController::default();
+        for cgb in [false, true] {
+            let mut scanline = busy_scene(Renderer::Scanline, cgb);
+            let mut fifo = busy_scene(Renderer::Fifo, cgb);
+            run_dots(&mut scanline, &mut interrupts, DOTS_PER_LINE as u32 * VISIBLE_LINES as u32);
+            run_dots(&mut fifo, &mut interrupts, DOTS_PER_LINE as u32 * VISIBLE_LINES as u32);
+            assert!(scanline.framebuffer() == fifo.framebuffer());
+        }
+    }
+
+    #[test]
+    fn fifo_mode_3_length() {
+        let mut interrupts = InterruptController::default();
+        let mut drawing_dots = |scx : u8, sprite_x : Option<u8>| {
+            let mut ppu = Ppu::new();
+            ppu.set_renderer(Renderer::Fifo);
+            ppu.write(SCX_ADDRESS, scx);
+            if let Some(x) = sprite_x {
+                ppu.write_oam(0, 16);
+                ppu.write_oam(1, x);
+            }
+            ppu.write(LCDC_ADDRESS, LCD_ENABLE | SPRITE_ENABLE | BG_ENABLE);
+            run_dots(&mut ppu, &mut interrupts, OAM_SCAN_DOTS as u32);
+            let mut dots = 0;
+            while ppu.mode() == Mode::Drawing {
+                run_dots(&mut ppu, &mut interrupts, 1);
+                dots += 1;
+            }
+            dots
+        };
+        assert_eq!(drawing_dots(0, None), MIN_DRAWING_DOTS);
+        assert_eq!(drawing_dots(3, None), MIN_DRAWING_DOTS + 3);
+        assert_eq!(drawing_dots(0, Some(8)), MIN_DRAWING_DOTS + 11);
+    }
+}
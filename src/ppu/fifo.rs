@@ -0,0 +1,288 @@
+use super::*;
+
+use std::collections::VecDeque;
+
+/// Dots at the start of mode 3 spent on the tile fetch the hardware throws away
+const STARTUP_DOTS : u8 = 6;
+/// Dots to fetch a sprite's tile once the background fetcher has stopped for it
+const SPRITE_FETCH_DOTS : u8 = 6;
+/// Longest wait for the background fetcher to finish the tile under a sprite
+const SPRITE_WAIT_DOTS : u8 = 5;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum FetchStep {
+    #[default]
+    Tile, DataLow, DataHigh, Push,
+}
+
+#[derive(Clone, Copy, Default)]
+struct BackgroundPixel {
+    color : u8,
+    ///BG map attributes, always 0 outside CGB mode
+    attributes : u8,
+}
+
+#[derive(Clone, Copy, Default)]
+struct SpritePixel {
+    color : u8,
+    flags : u8,
+    ///Entry in `Ppu::sprites`, which are in OAM order
+    index : usize,
+}
+
+/// State of the pixel FIFOs and fetchers through one line of mode 3
+#[derive(Default)]
+pub(super) struct LineState {
+    background : VecDeque<BackgroundPixel>,
+    sprites : VecDeque<SpritePixel>,
+
+    step : FetchStep,
+    ///Dots spent in the current fetch step
+    step_dots : u8,
+    tile : u8,
+    attributes : u8,
+    low : u8,
+    high : u8,
+    ///Tile column of the next background or window fetch
+    fetch_x : u8,
+    window : bool,
+
+    startup : u8,
+    ///Pixels still to drop off the front of the line for fine scrolling
+    discard : u8,
+    ///Pixels output so far
+    x : u8,
+    ///Bit per entry of `Ppu::sprites` which has been fetched already
+    fetched_sprites : u16,
+    ///Sprite currently being fetched, with the dots left to fetch it
+    sprite_fetch : Option<(usize, u8)>,
+}
+
+impl Ppu {
+    pub(super) fn start_fifo_line(&mut self) {
+        self.line = LineState {
+            startup : STARTUP_DOTS,
+            discard : self.scx & 7,
+            ..LineState::default()
+        };
+    }
+    /// Row within the background or window tile being fetched, read fresh so mid-line SCY writes take effect
+    fn fetch_row(&self) -> u8 {
+        if self.line.window {
+            self.window_line
+        } else {
+            self.ly.wrapping_add(self.scy)
+        }
+    }
+    fn step_fetcher(&mut self) {
+        if self.line.step == FetchStep::Push {
+            if self.line.background.is_empty() {
+                let attributes = self.line.attributes;
+                //On the DMG, clearing the BG enable blanks it
+                let enabled = self.cgb_mode || self.lcdc & BG_ENABLE != 0;
+                for column in 0..8 {
+                    let bit = if attributes & X_FLIP != 0 { column } else { 7 - column };
+                    let color = ((self.line.high >> bit) & 1) << 1 | (self.line.low >> bit) & 1;
+                    self.line.background.push_back(BackgroundPixel { color : if enabled { color } else { 0 }, attributes });
+                }
+                self.line.fetch_x = self.line.fetch_x.wrapping_add(1);
+                self.line.step = FetchStep::Tile;
+            }
+            return;
+        }
+
+        self.line.step_dots += 1;
+        if self.line.step_dots < 2 {
+            return;
+        }
+        self.line.step_dots = 0;
+
+        let row = self.fetch_row();
+        match self.line.step {
+            FetchStep::Tile => {
+                let (map_select, column) = if self.line.window {
+                    (WINDOW_MAP, self.line.fetch_x & 31)
+                } else {
+                    (BG_MAP, ((self.scx >> 3).wrapping_add(self.line.fetch_x)) & 31)
+                };
+                (self.line.tile, self.line.attributes) = self.tile_map_entry(map_select, column, row / 8);
+                self.line.step = FetchStep::DataLow;
+            },
+            FetchStep::DataLow => {
+                let (bank, address) = self.tile_row(self.line.tile, self.line.attributes, row % 8);
+                self.line.low = self.vram[bank][address];
+                self.line.step = FetchStep::DataHigh;
+            },
+            FetchStep::DataHigh => {
+                let (bank, address) = self.tile_row(self.line.tile, self.line.attributes, row % 8);
+                self.line.high = self.vram[bank][address + 1];
+                self.line.step = FetchStep::Push;
+            },
+            FetchStep::Push => unreachable!(),
+        }
+    }
+    /// The next sprite on this line whose left edge has been reached, if it hasn't been fetched yet
+    fn pending_sprite(&self) -> Option<usize> {
+        if self.lcdc & SPRITE_ENABLE == 0 {
+            return None;
+        }
+        //Leftmost first, so they win over sprites fetched after them
+        self.sprites.iter().enumerate()
+            .filter(|(index, sprite)| self.line.fetched_sprites & (1 << index) == 0 && sprite.x <= self.line.x + 8)
+            .min_by_key(|(_, sprite)| sprite.x)
+            .map(|(index, _)| index)
+    }
+    /// Merge a fetched sprite into the sprite FIFO, underneath any sprite pixels already there.
+    /// In CGB mode it goes on top of those from later in OAM instead.
+    fn mix_sprite(&mut self, index : usize) {
+        let sprite = self.sprites[index];
+        while self.line.sprites.len() < 8 {
+            self.line.sprites.push_back(SpritePixel::default());
+        }
+        for column in 0..8 {
+            //Columns left of the screen, or already shifted out, are skipped
+            let screen_x = sprite.x as i16 - 8 + column as i16;
+            let slot = screen_x - self.line.x as i16;
+            if slot < 0 {
+                continue;
+            }
+            let color = self.sprite_pixel(&sprite, column);
+            let pixel = &mut self.line.sprites[slot as usize];
+            let wins = pixel.color == 0 || (self.cgb_mode && color != 0 && index < pixel.index);
+            if wins {
+                *pixel = SpritePixel { color, flags : sprite.flags, index };
+            }
+        }
+    }
+    fn start_window(&mut self) {
+        self.line.window = true;
+        self.line.background.clear();
+        self.line.step = FetchStep::Tile;
+        self.line.step_dots = 0;
+        self.line.fetch_x = 0;
+        //A WX below 7 pushes the window's left edge off screen
+        self.line.discard = 7_u8.saturating_sub(self.wx);
+    }
+
+    /// Run the fetchers and FIFOs for a dot of mode 3. Returns true once the line is finished.
+    pub(super) fn step_fifo(&mut self) -> bool {
+        if self.line.startup > 0 {
+            self.line.startup -= 1;
+            return false;
+        }
+
+        if let Some((index, dots)) = self.line.sprite_fetch {
+            if dots > 1 {
+                self.line.sprite_fetch = Some((index, dots - 1));
+            } else {
+                self.line.sprite_fetch = None;
+                self.mix_sprite(index);
+            }
+            return false;
+        }
+
+        if !self.line.window && self.window_visible() && self.line.x as u16 + 7 >= self.wx as u16 {
+            self.start_window();
+        }
+
+        //Sprites stall the FIFO while the background fetcher finishes the tile under them
+        if let Some(index) = self.pending_sprite() {
+            let offset = self.sprites[index].x.wrapping_add(self.scx) & 7;
+            let wait = SPRITE_WAIT_DOTS - offset.min(SPRITE_WAIT_DOTS);
+            self.line.fetched_sprites |= 1 << index;
+            //This dot is the first of the fetch
+            self.line.sprite_fetch = Some((index, SPRITE_FETCH_DOTS + wait - 1));
+            return false;
+        }
+
+        self.step_fetcher();
+
+        let Some(background) = self.line.background.pop_front() else {
+            return false;
+        };
+        if self.line.discard > 0 {
+            self.line.discard -= 1;
+            return false;
+        }
+        let sprite = self.line.sprites.pop_front().unwrap_or_default();
+
+        //Palettes are applied as pixels leave the FIFO, so mid-line palette writes show up
+        let visible = self.lcdc & SPRITE_ENABLE != 0
+            && self.sprite_visible(sprite.flags, sprite.color, background.color, background.attributes);
+        let output = if visible {
+            self.sprite_output(sprite.flags, sprite.color)
+        } else {
+            self.background_output(background.attributes, background.color)
+        };
+        self.put_pixel(self.line.x as usize, output);
+        self.line.x += 1;
+
+        if self.line.x as usize == SCREEN_WIDTH {
+            if self.line.window {
+                self.window_line += 1;
+            }
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot::{self, Model};
+    use crate::cartridge::Cartridge;
+    use crate::cpu;
+    use crate::mapper;
+    use crate::memory::{Bus, Memory};
+
+    use std::fs::File;
+    use std::path::Path;
+
+    /// Where to put dmg-acid2.gb and reference-dmg.png from https://github.com/mattcurrie/dmg-acid2
+    const ACID2_DIRECTORY : &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_roms/dmg-acid2");
+    /// LD B,B, which the test runs once the screen is drawn
+    const BREAKPOINT : u8 = 0x40;
+
+    /// Shades of a greyscale reference image, from 0 for white to 3 for black
+    fn reference_shades(path : &Path) -> Vec<u8> {
+        let mut decoder = png::Decoder::new(File::open(path).unwrap());
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+        assert_eq!((info.width as usize, info.height as usize), (SCREEN_WIDTH, SCREEN_HEIGHT));
+        //Grey images have the same value in every colour channel, so the first of each pixel will do
+        data.chunks_exact(info.color_type.samples())
+            .map(|pixel| 3 - ((pixel[0] as u16 + 42) / 85) as u8)
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "needs dmg-acid2 in test_roms/dmg-acid2"]
+    fn passes_dmg_acid2() {
+        let directory = Path::new(ACID2_DIRECTORY);
+        let cartridge = Cartridge::load(&directory.join("dmg-acid2.gb")).unwrap();
+        let mut memory = Memory::new(mapper::from_cartridge(cartridge).unwrap());
+        memory.ppu.set_renderer(Renderer::Fifo);
+        let mut state = cpu::Registers::default();
+        boot::skip_boot(Model::Dmg, &mut state, &mut memory);
+
+        let mut frames = 0;
+        while memory.read(state.pc()) != BREAKPOINT {
+            cpu::step(&mut state, &mut memory);
+            frames += memory.ppu.take_frame_ready() as u32;
+            assert!(frames < 60, "never reached the breakpoint");
+        }
+        //Let the frame being drawn finish
+        while !memory.ppu.take_frame_ready() {
+            cpu::step(&mut state, &mut memory);
+        }
+
+        let expected = reference_shades(&directory.join("reference-dmg.png"));
+        let mismatch = memory.ppu.shades().iter().zip(&expected).position(|(shade, expected)| shade != expected);
+        if let Some(index) = mismatch {
+            panic!("first mismatch at {}, {}", index % SCREEN_WIDTH, index / SCREEN_WIDTH);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+/// Auto-increment bit of BCPS and OCPS
+const AUTO_INCREMENT : u8 = 0x80;
+const INDEX_MASK : u8 = 0x3F;
+
+/// Eight CGB palettes of four 15-bit colours, reached through an index and a data register
+pub struct PaletteRam {
+    data : [u8; 64],
+    ///BCPS or OCPS, the byte BCPD or OCPD accesses and whether writes move it on
+    index : u8,
+}
+
+impl PaletteRam {
+    pub fn new() -> PaletteRam {
+        PaletteRam { data : [0; 64], index : 0 }
+    }
+    pub fn read_index(&self) -> u8 {
+        0x40 | self.index
+    }
+    pub fn write_index(&mut self, data : u8) {
+        self.index = data & (AUTO_INCREMENT | INDEX_MASK);
+    }
+    pub fn read_data(&self) -> u8 {
+        self.data[(self.index & INDEX_MASK) as usize]
+    }
+    /// Write the byte at the index, unless the PPU has the palettes `locked`. The index moves on either way.
+    pub fn write_data(&mut self, data : u8, locked : bool) {
+        if !locked {
+            self.data[(self.index & INDEX_MASK) as usize] = data;
+        }
+        if self.index & AUTO_INCREMENT != 0 {
+            self.index = AUTO_INCREMENT | (self.index + 1) & INDEX_MASK;
+        }
+    }
+    /// Colour `color` of palette `palette`, little endian with red in the low bits
+    pub fn color(&self, palette : u8, color : u8) -> u16 {
+        let index = (palette as usize & 7) * 8 + color as usize * 2;
+        u16::from_le_bytes([self.data[index], self.data[index + 1]]) & 0x7FFF
+    }
+}
+
+impl Default for PaletteRam {
+    fn default() -> Self {
+        PaletteRam::new()
+    }
+}
@@ -0,0 +1,326 @@
+use crate::serial::LinkDevice;
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+const MAGIC : [u8; 2] = [0x88, 0x33];
+
+const INIT : u8 = 0x01;
+const PRINT : u8 = 0x02;
+const DATA : u8 = 0x04;
+const STATUS : u8 = 0x0F;
+
+/// Sent back while the Game Boy clocks out the first status byte
+const ALIVE : u8 = 0x81;
+
+const CHECKSUM_ERROR : u8 = 0x01;
+const BUSY : u8 = 0x02;
+const IMAGE_FULL : u8 = 0x04;
+const UNPROCESSED : u8 = 0x08;
+
+/// Size of the printer's image buffer
+const BUFFER_SIZE : usize = 0x2000;
+/// Tiles across the paper
+const WIDTH_TILES : usize = 20;
+const TILE_SIZE : usize = 16;
+/// M-cycles the printer reports itself busy after a print command
+const PRINT_CYCLES : u32 = 0x40000;
+
+/// Where in a packet the next byte lands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Magic(usize),
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// Game Boy Printer, saving each print as a PNG strip
+pub struct Printer {
+    directory : PathBuf,
+    state : State,
+
+    command : u8,
+    compressed : bool,
+    length : u16,
+    data : Vec<u8>,
+    checksum : u16,
+
+    status : u8,
+    buffer : Vec<u8>,
+    ///M-cycles until the current print finishes
+    busy_cycles : u32,
+    printed : usize,
+    ///Failure writing the last print, kept for whoever owns the printer to report
+    error : Option<io::Error>,
+}
+
+impl Printer {
+    /// A printer writing its output into `directory`
+    pub fn new(directory : PathBuf) -> Printer {
+        Printer {
+            directory,
+            state : State::Magic(0),
+            command : 0,
+            compressed : false,
+            length : 0,
+            data : Vec::new(),
+            checksum : 0,
+            status : 0,
+            buffer : Vec::new(),
+            busy_cycles : 0,
+            printed : 0,
+            error : None,
+        }
+    }
+    /// Number of images printed so far
+    pub fn printed(&self) -> usize {
+        self.printed
+    }
+
+    /// Run the packet that was just received, if its checksum matches
+    fn execute(&mut self, checksum : u16) {
+        let expected = [self.command, self.compressed as u8, self.length as u8, (self.length >> 8) as u8].iter()
+            .chain(&self.data)
+            .fold(0_u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        if checksum != expected {
+            self.status |= CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !CHECKSUM_ERROR;
+
+        match self.command {
+            INIT => {
+                self.buffer.clear();
+                self.status = 0;
+            },
+            DATA => {
+                let data = if self.compressed { decompress(&self.data) } else { std::mem::take(&mut self.data) };
+                let space = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend(data.into_iter().take(space));
+                if !self.buffer.is_empty() {
+                    self.status |= UNPROCESSED;
+                }
+            },
+            PRINT => {
+                //Sheets, margins, palette, exposure
+                let palette = match self.data.get(2) {
+                    Some(0) | None => 0xE4,
+                    Some(&palette) => palette,
+                };
+                self.print(palette);
+                self.status = (self.status & !UNPROCESSED) | BUSY | IMAGE_FULL;
+                self.busy_cycles = PRINT_CYCLES;
+            },
+            //Only asks for the status, which every packet gets back
+            STATUS => (),
+            _ => (),
+        }
+    }
+    fn print(&mut self, palette : u8) {
+        let Some(image) = decode_image(&self.buffer, palette) else {
+            return;
+        };
+        self.buffer.clear();
+        self.printed += 1;
+        let path = self.directory.join(format!("print_{:04}.png", self.printed));
+        if let Err(err) = write_png(&path, &image) {
+            self.error = Some(io::Error::new(err.kind(), format!("{}: {}", path.display(), err)));
+        }
+    }
+}
+
+impl LinkDevice for Printer {
+    fn transfer(&mut self, data : u8) -> u8 {
+        let mut response = 0x00;
+        self.state = match self.state {
+            //Anything that isn't the start of a packet is ignored
+            State::Magic(index) if data == MAGIC[index] => {
+                if index + 1 == MAGIC.len() { State::Command } else { State::Magic(index + 1) }
+            },
+            State::Magic(_) => State::Magic(0),
+            State::Command => {
+                self.command = data;
+                State::Compression
+            },
+            State::Compression => {
+                self.compressed = data & 0x01 != 0;
+                State::LengthLow
+            },
+            State::LengthLow => {
+                self.length = data as u16;
+                State::LengthHigh
+            },
+            State::LengthHigh => {
+                self.length |= (data as u16) << 8;
+                self.data.clear();
+                if self.length == 0 { State::ChecksumLow } else { State::Data }
+            },
+            State::Data => {
+                self.data.push(data);
+                if self.data.len() == self.length as usize { State::ChecksumLow } else { State::Data }
+            },
+            State::ChecksumLow => {
+                self.checksum = data as u16;
+                State::ChecksumHigh
+            },
+            State::ChecksumHigh => {
+                self.checksum |= (data as u16) << 8;
+                self.execute(self.checksum);
+                State::Alive
+            },
+            State::Alive => {
+                response = ALIVE;
+                State::Status
+            },
+            State::Status => {
+                response = self.status;
+                State::Magic(0)
+            },
+        };
+        response
+    }
+    fn step(&mut self, _waiting : Option<u8>) -> Option<u8> {
+        if self.busy_cycles > 0 {
+            self.busy_cycles -= 1;
+            if self.busy_cycles == 0 {
+                self.status &= !(BUSY | IMAGE_FULL);
+            }
+        }
+        //The printer never drives the clock
+        None
+    }
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
+
+/// Expand the printer's run length encoding. Control bytes with the top bit clear are followed
+/// by that many plus one literal bytes, otherwise by one byte repeated the low bits plus two times.
+pub fn decompress(data : &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut bytes = data.iter().copied();
+    while let Some(control) = bytes.next() {
+        if control & 0x80 == 0 {
+            output.extend(bytes.by_ref().take(control as usize + 1));
+        } else if let Some(byte) = bytes.next() {
+            output.extend(std::iter::repeat_n(byte, (control & 0x7F) as usize + 2));
+        }
+    }
+    output
+}
+
+/// A printed strip as 8 bit grayscale
+pub struct Image {
+    pub width : usize,
+    pub height : usize,
+    pub pixels : Vec<u8>,
+}
+
+/// Turn 2bpp tiles, 20 to a row, into grayscale through `palette`. None if there's not a whole row of tiles.
+pub fn decode_image(data : &[u8], palette : u8) -> Option<Image> {
+    let rows = data.len() / (WIDTH_TILES * TILE_SIZE);
+    if rows == 0 {
+        return None;
+    }
+    let width = WIDTH_TILES * 8;
+    let height = rows * 8;
+    let mut pixels = vec![0; width * height];
+    for (index, tile) in data.chunks_exact(TILE_SIZE).take(rows * WIDTH_TILES).enumerate() {
+        let (tile_x, tile_y) = (index % WIDTH_TILES * 8, index / WIDTH_TILES * 8);
+        for (y, row) in tile.chunks_exact(2).enumerate() {
+            for x in 0..8 {
+                let bit = 7 - x;
+                let color = ((row[1] >> bit) & 1) << 1 | (row[0] >> bit) & 1;
+                let shade = (palette >> (color * 2)) & 0b11;
+                pixels[(tile_y + y) * width + tile_x + x] = 255 - shade * 85;
+            }
+        }
+    }
+    Some(Image { width, height, pixels })
+}
+
+pub fn write_png(path : &Path, image : &Image) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), image.width as u32, image.height as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer.write_image_data(&image.pixels).map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send a whole packet, returning the printer's answers to the two status bytes
+    fn send_packet(printer : &mut Printer, command : u8, compressed : bool, data : &[u8]) -> [u8; 2] {
+        let length = data.len() as u16;
+        let header = [command, compressed as u8, length as u8, (length >> 8) as u8];
+        let checksum = header.iter().chain(data).fold(0_u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        for &byte in MAGIC.iter().chain(&header).chain(data).chain(&checksum.to_le_bytes()) {
+            assert_eq!(printer.transfer(byte), 0x00);
+        }
+        [printer.transfer(0x00), printer.transfer(0x00)]
+    }
+
+    #[test]
+    fn decompresses_runs() {
+        assert_eq!(decompress(&[0x01, 0xAA, 0xBB, 0x82, 0xCC]), [0xAA, 0xBB, 0xCC, 0xCC, 0xCC, 0xCC]);
+    }
+
+    #[test]
+    fn prints_a_strip() {
+        let directory = std::env::temp_dir().join(format!("fuzz_gb_printer_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut printer = Printer::new(directory.clone());
+
+        assert_eq!(send_packet(&mut printer, INIT, false, &[]), [ALIVE, 0x00]);
+        //Two rows of tiles in colour 3, compressed into runs of 129 bytes
+        let run = [0xFF, 0xFF];
+        let data : Vec<u8> = (0..5).flat_map(|_| run).collect();
+        assert_eq!(send_packet(&mut printer, DATA, true, &data), [ALIVE, UNPROCESSED]);
+        assert_eq!(send_packet(&mut printer, DATA, false, &[]), [ALIVE, UNPROCESSED]);
+        assert_eq!(send_packet(&mut printer, PRINT, false, &[1, 0x13, 0xE4, 0x40]), [ALIVE, BUSY | IMAGE_FULL]);
+
+        for _ in 0..PRINT_CYCLES {
+            printer.step(None);
+        }
+        assert_eq!(send_packet(&mut printer, STATUS, false, &[]), [ALIVE, 0x00]);
+
+        //A bad checksum is reported and the packet dropped
+        let mut bad = MAGIC.to_vec();
+        bad.extend([INIT, 0, 0, 0, 0x55, 0x55]);
+        for byte in bad {
+            printer.transfer(byte);
+        }
+        assert_eq!([printer.transfer(0), printer.transfer(0)], [ALIVE, CHECKSUM_ERROR]);
+
+        assert_eq!(printer.printed(), 1);
+        let image = decode_image(&[0xFF; 640], 0xE4).unwrap();
+        assert_eq!((image.width, image.height), (160, 16));
+        assert!(image.pixels.iter().all(|&pixel| pixel == 0));
+        assert!(directory.join("print_0001.png").exists());
+        assert!(printer.take_error().is_none());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn write_failures_are_kept() {
+        let directory = std::env::temp_dir().join(format!("fuzz_gb_printer_missing_{}", std::process::id()));
+        let mut printer = Printer::new(directory.clone());
+        send_packet(&mut printer, DATA, false, &[0xFF; 640]);
+        send_packet(&mut printer, PRINT, false, &[1, 0x13, 0xE4, 0x40]);
+
+        let err = printer.take_error().expect("the directory doesn't exist");
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().starts_with(&directory.join("print_0001.png").display().to_string()));
+        assert!(printer.take_error().is_none());
+    }
+}
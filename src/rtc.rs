@@ -0,0 +1,244 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of wall-clock time for the RTC, so tests can control it
+pub trait Clock {
+    /// Seconds since the unix epoch
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs())
+    }
+}
+
+const SECONDS : u8 = 0x08;
+const MINUTES : u8 = 0x09;
+const HOURS : u8 = 0x0A;
+const DAYS_LOW : u8 = 0x0B;
+const DAYS_HIGH : u8 = 0x0C;
+
+const DAY_HIGH_BIT : u8 = 0x01;
+const HALT_BIT : u8 = 0x40;
+const DAY_CARRY_BIT : u8 = 0x80;
+
+/// Size of the footer appended to save RAM by other emulators
+pub const FOOTER_SIZE : usize = 48;
+/// Older variant of the footer with a 32 bit timestamp
+pub const SHORT_FOOTER_SIZE : usize = 44;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+struct Registers {
+    seconds : u8,
+    minutes : u8,
+    hours : u8,
+    days_low : u8,
+    ///Bit 0 is day bit 8, bit 6 halts the clock, bit 7 is the day counter carry
+    days_high : u8,
+}
+
+impl Registers {
+    fn read(&self, select : u8) -> u8 {
+        match select {
+            SECONDS => self.seconds,
+            MINUTES => self.minutes,
+            HOURS => self.hours,
+            DAYS_LOW => self.days_low,
+            DAYS_HIGH => self.days_high,
+            _ => 0xFF,
+        }
+    }
+    fn days(&self) -> u64 {
+        ((self.days_high & DAY_HIGH_BIT) as u64) << 8 | self.days_low as u64
+    }
+    fn advance(&mut self, seconds : u64) {
+        let total = self.seconds as u64 + 60 * self.minutes as u64 + 3600 * self.hours as u64 + seconds;
+        let mut days = self.days() + total / 86400;
+        let total = total % 86400;
+
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600) as u8;
+
+        //The day counter is 9 bits, overflowing sets the sticky carry bit
+        if days > 0x1FF {
+            self.days_high |= DAY_CARRY_BIT;
+            days &= 0x1FF;
+        }
+        self.days_low = days as u8;
+        self.days_high = (self.days_high & !DAY_HIGH_BIT) | (days >> 8) as u8;
+    }
+}
+
+/// MBC3 real time clock, mapped into 0xA000..=0xBFFF by selecting RAM banks 0x08..=0x0C
+pub struct Rtc {
+    clock : Box<dyn Clock>,
+    live : Registers,
+    ///Copy of the live registers taken by the latch sequence, which is what reads see
+    latched : Registers,
+    ///Last value written to the latch register, latching happens on a 0x00 to 0x01 transition
+    latch_write : u8,
+    ///Wall-clock time the live registers were last brought up to date
+    last_update : u64,
+}
+
+impl Rtc {
+    pub fn new(clock : Box<dyn Clock>) -> Rtc {
+        let last_update = clock.now();
+        Rtc {
+            clock,
+            live : Registers::default(),
+            latched : Registers::default(),
+            latch_write : 0xFF,
+            last_update,
+        }
+    }
+    /// The live registers caught up to the current time, along with that time
+    fn current(&self) -> (Registers, u64) {
+        let now = self.clock.now();
+        let mut live = self.live;
+        if live.days_high & HALT_BIT == 0 {
+            live.advance(now.saturating_sub(self.last_update));
+        }
+        (live, now)
+    }
+    /// Catch the live registers up with the wall clock
+    fn update(&mut self) {
+        (self.live, self.last_update) = self.current();
+    }
+    pub fn write_latch(&mut self, data : u8) {
+        if self.latch_write == 0x00 && data == 0x01 {
+            self.update();
+            self.latched = self.live;
+        }
+        self.latch_write = data;
+    }
+    pub fn read(&self, select : u8) -> u8 {
+        self.latched.read(select)
+    }
+    pub fn write(&mut self, select : u8, data : u8) {
+        //Time up to the write counts towards the old values
+        self.update();
+        match select {
+            SECONDS => self.live.seconds = data & 0x3F,
+            MINUTES => self.live.minutes = data & 0x3F,
+            HOURS => self.live.hours = data & 0x1F,
+            DAYS_LOW => self.live.days_low = data,
+            DAYS_HIGH => self.live.days_high = data & (DAY_HIGH_BIT | HALT_BIT | DAY_CARRY_BIT),
+            _ => (),
+        }
+    }
+
+    /// The clock in the footer format appended to .sav files by other emulators:
+    /// live then latched registers as 32 bit little endian words, then a 64 bit unix timestamp.
+    pub fn to_footer(&self) -> [u8; FOOTER_SIZE] {
+        let (live, now) = self.current();
+
+        let mut footer = [0_u8; FOOTER_SIZE];
+        let registers = [live, self.latched].into_iter().flat_map(|registers| [
+            registers.seconds, registers.minutes, registers.hours, registers.days_low, registers.days_high,
+        ]);
+        for (word, value) in footer.chunks_exact_mut(4).zip(registers) {
+            word.copy_from_slice(&(value as u32).to_le_bytes());
+        }
+        footer[40..48].copy_from_slice(&now.to_le_bytes());
+        footer
+    }
+    /// Restore the clock from a 48 or 44 byte footer, advancing it by the time since it was saved
+    pub fn load_footer(&mut self, footer : &[u8]) -> bool {
+        let timestamp = match footer.len() {
+            FOOTER_SIZE => u64::from_le_bytes(footer[40..48].try_into().unwrap()),
+            SHORT_FOOTER_SIZE => u32::from_le_bytes(footer[40..44].try_into().unwrap()) as u64,
+            _ => return false,
+        };
+
+        let mut values = footer[..40].chunks_exact(4).map(|word| word[0]);
+        let mut next_registers = || Registers {
+            seconds : values.next().unwrap_or(0),
+            minutes : values.next().unwrap_or(0),
+            hours : values.next().unwrap_or(0),
+            days_low : values.next().unwrap_or(0),
+            days_high : values.next().unwrap_or(0),
+        };
+        self.live = next_registers();
+        self.latched = next_registers();
+        self.last_update = timestamp;
+        self.update();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct FakeClock(Rc<Cell<u64>>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    fn fake_rtc() -> (Rtc, Rc<Cell<u64>>) {
+        let time = Rc::new(Cell::new(1_000_000));
+        (Rtc::new(Box::new(FakeClock(time.clone()))), time)
+    }
+
+    fn latch(rtc : &mut Rtc) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    #[test]
+    fn counts_and_latches() {
+        let (mut rtc, time) = fake_rtc();
+
+        time.set(time.get() + 86400 + 3600 + 60 + 1);
+        //Reads only change once latched
+        assert_eq!(rtc.read(SECONDS), 0);
+        latch(&mut rtc);
+        assert_eq!(
+            [rtc.read(SECONDS), rtc.read(MINUTES), rtc.read(HOURS), rtc.read(DAYS_LOW), rtc.read(DAYS_HIGH)],
+            [1, 1, 1, 1, 0]
+        );
+
+        //Halted clocks don't count
+        rtc.write(DAYS_HIGH, HALT_BIT);
+        time.set(time.get() + 100);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(SECONDS), 1);
+    }
+
+    #[test]
+    fn day_counter_carries() {
+        let (mut rtc, time) = fake_rtc();
+
+        rtc.write(DAYS_LOW, 0xFF);
+        rtc.write(DAYS_HIGH, DAY_HIGH_BIT);
+        time.set(time.get() + 86400);
+        latch(&mut rtc);
+
+        assert_eq!(rtc.read(DAYS_LOW), 0x00);
+        assert_eq!(rtc.read(DAYS_HIGH), DAY_CARRY_BIT);
+    }
+
+    #[test]
+    fn footer_round_trip() {
+        let (mut rtc, time) = fake_rtc();
+        rtc.write(MINUTES, 30);
+        let footer = rtc.to_footer();
+
+        //Time passes while the emulator isn't running
+        let (mut restored, restored_time) = fake_rtc();
+        restored_time.set(time.get() + 60);
+        assert!(restored.load_footer(&footer));
+        latch(&mut restored);
+        assert_eq!(restored.read(MINUTES), 31);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::mapper::Mapper;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Emulated cycles between checks for unsaved cartridge RAM, about a second
+const FLUSH_INTERVAL : u64 = 4_194_304;
+
+/// The .sav file next to a ROM holding its battery backed RAM.
+/// The contents are the raw RAM (plus the RTC footer for MBC3), as other emulators store it.
+pub struct SaveFile {
+    path : PathBuf,
+    cycles_since_flush : u64,
+}
+
+impl SaveFile {
+    pub fn for_rom(rom : &Path) -> SaveFile {
+        SaveFile { path : rom.with_extension("sav"), cycles_since_flush : 0 }
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Load an existing save into `mapper`. A missing file is not an error, the game starts fresh.
+    pub fn load(&self, mapper : &mut dyn Mapper) -> io::Result<()> {
+        match fs::read(&self.path) {
+            Ok(data) => {
+                mapper.load_save_data(&data);
+                Ok(())
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+    /// Write the save to disk if it changed since it was last written
+    pub fn flush(&mut self, mapper : &mut dyn Mapper) -> io::Result<()> {
+        self.cycles_since_flush = 0;
+        if !mapper.save_dirty() {
+            return Ok(());
+        }
+        //Write next to the real file and swap it in, so a crash mid-write can't lose the old save
+        let temporary = self.path.with_extension("sav.tmp");
+        fs::write(&temporary, mapper.save_data())?;
+        fs::rename(&temporary, &self.path)?;
+        mapper.mark_saved();
+        Ok(())
+    }
+    /// Count emulated time, flushing periodically
+    pub fn tick(&mut self, cycles : u32, mapper : &mut dyn Mapper) -> io::Result<()> {
+        self.cycles_since_flush += cycles as u64;
+        if self.cycles_since_flush >= FLUSH_INTERVAL {
+            self.flush(mapper)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::Mbc1;
+
+    fn run_for(save_file : &mut SaveFile, cycles : u64, mapper : &mut dyn Mapper) {
+        for _ in 0..cycles / 4 {
+            save_file.tick(4, mapper).unwrap();
+        }
+    }
+
+    #[test]
+    fn round_trips_through_the_sav_file() {
+        let directory = std::env::temp_dir().join(format!("fuzz_gb_save_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let mut save_file = SaveFile::for_rom(&directory.join("game.gb"));
+        assert_eq!(save_file.path(), directory.join("game.sav"));
+        let mut mapper = Mbc1::new(vec![0; 0x8000], vec![0; 0x2000]);
+
+        //Nothing to load yet, and nothing written while clean
+        save_file.load(&mut mapper).unwrap();
+        run_for(&mut save_file, FLUSH_INTERVAL, &mut mapper);
+        assert!(!save_file.path().exists());
+
+        //A dirty cart is written out once the interval passes, through the temporary file
+        fs::write(directory.join("game.sav.tmp"), b"stale").unwrap();
+        mapper.write_rom(0x0000, 0x0A);
+        mapper.write_ram(0xA123, 0x5A);
+        run_for(&mut save_file, FLUSH_INTERVAL - 4, &mut mapper);
+        assert!(!save_file.path().exists());
+        run_for(&mut save_file, 4, &mut mapper);
+        let data = fs::read(save_file.path()).unwrap();
+        assert!(!directory.join("game.sav.tmp").exists());
+        assert!(!mapper.save_dirty());
+
+        //Raw RAM, the size of the cart's RAM with no header
+        assert_eq!(data.len(), 0x2000);
+        assert_eq!(data[0x123], 0x5A);
+
+        //Once saved it isn't written again until something changes
+        fs::remove_file(save_file.path()).unwrap();
+        run_for(&mut save_file, FLUSH_INTERVAL, &mut mapper);
+        assert!(!save_file.path().exists());
+        save_file.flush(&mut mapper).unwrap();
+        assert!(!save_file.path().exists());
+
+        fs::write(save_file.path(), &data).unwrap();
+        let mut restored = Mbc1::new(vec![0; 0x8000], vec![0; 0x2000]);
+        save_file.load(&mut restored).unwrap();
+        restored.write_rom(0x0000, 0x0A);
+        assert_eq!(restored.read_ram(0xA123), 0x5A);
+        assert!(!restored.save_dirty());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
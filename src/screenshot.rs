@@ -0,0 +1,44 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+/// Write a frame of 15-bit colours, red in the low bits, as an 8 bit RGB PNG
+pub fn write(path : &Path, width : usize, height : usize, pixels : &[u16]) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    let data : Vec<u8> = pixels.iter().flat_map(|&color| to_rgb(color)).collect();
+    writer.write_image_data(&data).map_err(io::Error::other)
+}
+
+/// Scale each 5 bit channel up to 8 bits, repeating the top bits so white stays white
+fn to_rgb(color : u16) -> [u8; 3] {
+    let channel = |shift : u16| {
+        let value = ((color >> shift) & 0x1F) as u8;
+        value << 3 | value >> 2
+    };
+    [channel(0), channel(5), channel(10)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_rgb_png() {
+        let path = std::env::temp_dir().join(format!("fuzz_gb_screenshot_{}.png", std::process::id()));
+        write(&path, 3, 2, &[0x7FFF, 0x001F, 0x03E0, 0x7C00, 0x0000, 0x294A]).unwrap();
+
+        let mut reader = png::Decoder::new(File::open(&path).unwrap()).read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((info.width, info.height, info.color_type), (3, 2, png::ColorType::Rgb));
+        assert_eq!(data, [
+            0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00,
+            0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0x52, 0x52, 0x52,
+        ]);
+    }
+}
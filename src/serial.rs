@@ -0,0 +1,157 @@
+use crate::interrupts::{Interrupt, InterruptController};
+
+use std::io;
+
+pub const SB_ADDRESS : u16 = 0xFF01;
+pub const SC_ADDRESS : u16 = 0xFF02;
+
+const TRANSFER_START : u8 = 0x80;
+///CGB only, shifts 32 times faster
+const FAST_CLOCK : u8 = 0x02;
+const INTERNAL_CLOCK : u8 = 0x01;
+
+/// M-cycles per bit on the 8192Hz internal clock
+const BIT_CYCLES : u16 = 128;
+/// M-cycles per bit on the CGB's 262144Hz internal clock
+const FAST_BIT_CYCLES : u16 = 4;
+
+/// Something plugged into the link port
+pub trait LinkDevice {
+    /// This end drives the clock: send `data` and return the byte shifted back in
+    fn transfer(&mut self, data : u8) -> u8;
+    /// Called every M-cycle. `waiting` is SB while an externally clocked transfer waits for the other end.
+    /// Returns the byte shifted in if the other end drove a transfer.
+    fn step(&mut self, _waiting : Option<u8>) -> Option<u8> {
+        None
+    }
+    /// The last error the device ran into, if it hasn't been taken yet
+    fn take_error(&mut self) -> Option<io::Error> {
+        None
+    }
+}
+
+/// The serial port. Bytes sent are kept in a buffer, which is how test ROMs report their results.
+#[derive(Default)]
+pub struct Serial {
+    pub cgb : bool,
+    data : u8,
+    control : u8,
+    ///Bits left in the transfer in progress
+    bits_left : u8,
+    ///M-cycles until the next bit shifts
+    timer : u16,
+    ///Byte being sent, which SB loses as bits shift in
+    sending : u8,
+    ///Bits still to shift in from the other end, top first
+    incoming : u8,
+    output : Vec<u8>,
+    device : Option<Box<dyn LinkDevice>>,
+}
+
+impl Serial {
+    fn bit_cycles(&self) -> u16 {
+        if self.cgb && self.control & FAST_CLOCK != 0 { FAST_BIT_CYCLES } else { BIT_CYCLES }
+    }
+    /// Plug `device` into the port, replacing whatever was there
+    pub fn connect(&mut self, device : Box<dyn LinkDevice>) {
+        self.device = Some(device);
+    }
+    /// Bytes sent since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+    /// The last error from the device plugged in, if any
+    pub fn take_device_error(&mut self) -> Option<io::Error> {
+        self.device.as_mut().and_then(|device| device.take_error())
+    }
+
+    pub fn read(&self, addr : u16) -> u8 {
+        match addr {
+            SB_ADDRESS => self.data,
+            SC_ADDRESS => {
+                let unused = if self.cgb { 0x7C } else { 0x7E };
+                unused | self.control
+            },
+            _ => 0xFF,
+        }
+    }
+    pub fn write(&mut self, addr : u16, data : u8) {
+        match addr {
+            SB_ADDRESS => self.data = data,
+            SC_ADDRESS => {
+                let used = if self.cgb { TRANSFER_START | FAST_CLOCK | INTERNAL_CLOCK } else { TRANSFER_START | INTERNAL_CLOCK };
+                self.control = data & used;
+                if self.control & TRANSFER_START != 0 {
+                    self.bits_left = 8;
+                    self.timer = self.bit_cycles();
+                    self.sending = self.data;
+                    if self.control & INTERNAL_CLOCK != 0 {
+                        //With nothing plugged in, the input line floats high
+                        self.incoming = self.device.as_mut().map_or(0xFF, |device| device.transfer(self.data));
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn finish(&mut self, interrupts : &mut InterruptController) {
+        self.bits_left = 0;
+        self.control &= !TRANSFER_START;
+        self.output.push(self.sending);
+        interrupts.request(Interrupt::Serial);
+    }
+
+    /// Advance by one M-cycle
+    pub fn step(&mut self, interrupts : &mut InterruptController) {
+        let waiting = self.bits_left > 0 && self.control & INTERNAL_CLOCK == 0;
+        if let Some(device) = &mut self.device {
+            if let Some(received) = device.step(waiting.then_some(self.data)) {
+                //The other end's clock shifts the whole byte through
+                self.data = received;
+                self.finish(interrupts);
+                return;
+            }
+        }
+        //With the external clock, nothing shifts until the other end drives it
+        if self.bits_left == 0 || waiting {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.bit_cycles();
+
+        self.data = self.data << 1 | self.incoming >> 7;
+        self.incoming <<= 1;
+        self.bits_left -= 1;
+        if self.bits_left == 0 {
+            self.finish(interrupts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_clock_transfer() {
+        let mut serial = Serial::default();
+        let mut interrupts = InterruptController::default();
+        serial.write(SB_ADDRESS, b'P');
+        serial.write(SC_ADDRESS, TRANSFER_START | INTERNAL_CLOCK);
+
+        for _ in 0..8 * BIT_CYCLES - 1 {
+            serial.step(&mut interrupts);
+        }
+        assert_eq!(serial.read(SC_ADDRESS), 0xFF);
+        serial.step(&mut interrupts);
+
+        assert_eq!(serial.read(SC_ADDRESS), 0x7F);
+        assert_eq!(serial.read(SB_ADDRESS), 0xFF);
+        assert_eq!(interrupts.requested(), Interrupt::Serial.mask());
+        assert_eq!(serial.take_output(), b"P");
+    }
+}
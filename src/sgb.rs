@@ -0,0 +1,572 @@
+use crate::ppu;
+
+pub const SCREEN_WIDTH : usize = 256;
+pub const SCREEN_HEIGHT : usize = 224;
+/// Where the Game Boy's screen sits inside the border
+const SCREEN_X : usize = 48;
+const SCREEN_Y : usize = 40;
+
+/// The Game Boy screen in 8x8 cells, each of which gets one of the four palettes
+const CELLS_WIDE : usize = ppu::SCREEN_WIDTH / 8;
+const CELLS_HIGH : usize = ppu::SCREEN_HEIGHT / 8;
+
+const PACKET_SIZE : usize = 16;
+const PACKET_BITS : usize = PACKET_SIZE * 8;
+/// Bytes sent by the VRAM transfer commands, read off the screen
+const TRANSFER_SIZE : usize = 0x1000;
+const ATTRIBUTE_FILE_SIZE : usize = 90;
+const ATTRIBUTE_FILES : usize = 45;
+
+const PAL01 : u8 = 0x00;
+const PAL23 : u8 = 0x01;
+const PAL03 : u8 = 0x02;
+const PAL12 : u8 = 0x03;
+const ATTR_BLK : u8 = 0x04;
+const ATTR_LIN : u8 = 0x05;
+const ATTR_DIV : u8 = 0x06;
+const ATTR_CHR : u8 = 0x07;
+const PAL_SET : u8 = 0x0A;
+const PAL_TRN : u8 = 0x0B;
+const MLT_REQ : u8 = 0x11;
+const CHR_TRN : u8 = 0x13;
+const PCT_TRN : u8 = 0x14;
+const ATTR_TRN : u8 = 0x15;
+const ATTR_SET : u8 = 0x16;
+const MASK_EN : u8 = 0x17;
+
+/// P1 select lines as written, P14 in bit 4 and P15 in bit 5
+const SELECT_MASK : u8 = 0x30;
+const PULSE_ZERO : u8 = 0x20;
+const PULSE_ONE : u8 = 0x10;
+
+/// SGB palettes before any are sent, the same greys as a DMG
+const DEFAULT_PALETTE : [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+/// What MASK_EN does to the Game Boy screen
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mask {
+    #[default]
+    None,
+    /// Keep showing the last frame
+    Freeze,
+    Black,
+    /// Fill with colour 0
+    Color0,
+}
+
+/// Data a command is waiting on the next frame to carry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Transfer {
+    SystemPalettes,
+    ///Border tiles, the upper half if set
+    BorderTiles(bool),
+    BorderMap,
+    AttributeFiles,
+}
+
+/// Super Game Boy, taking command packets through P1 and colouring the Game Boy's output inside a border
+pub struct Sgb {
+    ///P1 select lines last written
+    select : u8,
+    ///Next bit of the packet coming in, None between packets
+    bit : Option<usize>,
+    packet : [u8; PACKET_SIZE],
+    ///Packets received so far of a command spanning several
+    command : Vec<u8>,
+    transfer : Option<Transfer>,
+
+    palettes : [[u16; 4]; 4],
+    ///Palette of each 8x8 cell of the screen
+    attributes : [u8; CELLS_WIDE * CELLS_HIGH],
+    system_palettes : Box<[u8; TRANSFER_SIZE]>,
+    attribute_files : Box<[u8; TRANSFER_SIZE]>,
+    ///256 tiles in the SNES 4bpp format
+    border_tiles : Box<[u8; TRANSFER_SIZE * 2]>,
+    ///32x28 entries of tile, palette and flips
+    border_map : Box<[u8; 0x800]>,
+    ///Palettes 4 to 7 of sixteen colours, which the border uses
+    border_palettes : [[u16; 16]; 4],
+    mask : Mask,
+
+    players : u8,
+    player : u8,
+
+    framebuffer : Box<[u16; SCREEN_WIDTH * SCREEN_HEIGHT]>,
+    ///The BIOS only listens for packets from carts with the SGB flag in their header
+    packets : bool,
+}
+
+impl Sgb {
+    pub fn new(packets : bool) -> Sgb {
+        Sgb {
+            select : SELECT_MASK,
+            bit : None,
+            packet : [0; PACKET_SIZE],
+            command : Vec::new(),
+            transfer : None,
+            palettes : [DEFAULT_PALETTE; 4],
+            attributes : [0; CELLS_WIDE * CELLS_HIGH],
+            system_palettes : Box::new([0; TRANSFER_SIZE]),
+            attribute_files : Box::new([0; TRANSFER_SIZE]),
+            border_tiles : Box::new([0; TRANSFER_SIZE * 2]),
+            border_map : Box::new([0; 0x800]),
+            border_palettes : [[0; 16]; 4],
+            mask : Mask::None,
+            players : 1,
+            player : 0,
+            framebuffer : Box::new([0; SCREEN_WIDTH * SCREEN_HEIGHT]),
+            packets,
+        }
+    }
+    /// The Game Boy's screen in its border, as 15-bit colours with red in the low bits
+    pub fn framebuffer(&self) -> &[u16] {
+        self.framebuffer.as_slice()
+    }
+    pub fn mask(&self) -> Mask {
+        self.mask
+    }
+    /// Palette of the 8x8 cell at `x`, `y`
+    pub fn cell_palette(&self, x : usize, y : usize) -> u8 {
+        self.attributes[y * CELLS_WIDE + x]
+    }
+
+    /// P1 as read, given what the joypad reads. With several players, only the first has buttons and
+    /// deselecting both matrices reads which player is current.
+    pub fn read_p1(&self, p1 : u8) -> u8 {
+        if self.players == 1 {
+            p1
+        } else if p1 & SELECT_MASK == SELECT_MASK {
+            (p1 & 0xF0) | (0x0F - self.player)
+        } else if self.player != 0 {
+            p1 | 0x0F
+        } else {
+            p1
+        }
+    }
+    /// Watch P1 writes for packets. Both lines low resets, then each pulse of one line is a bit.
+    pub fn write_p1(&mut self, data : u8) {
+        if !self.packets {
+            return;
+        }
+        let select = data & SELECT_MASK;
+        let previous = std::mem::replace(&mut self.select, select);
+        match select {
+            0x00 => {
+                self.bit = Some(0);
+                self.packet = [0; PACKET_SIZE];
+            },
+            //Outside of a packet, raising P15 moves on to the next player
+            SELECT_MASK if self.bit.is_none() && previous & PULSE_ZERO == 0 => {
+                self.player = (self.player + 1) % self.players;
+            },
+            SELECT_MASK => (),
+            _ if previous == SELECT_MASK => {
+                if let Some(bit) = self.bit {
+                    self.receive_bit(bit, select == PULSE_ONE);
+                }
+            },
+            _ => (),
+        }
+    }
+    fn receive_bit(&mut self, bit : usize, one : bool) {
+        //Each packet ends with a 0
+        if bit == PACKET_BITS {
+            self.bit = None;
+            if !one {
+                self.receive_packet();
+            }
+            return;
+        }
+        if one {
+            self.packet[bit / 8] |= 1 << (bit % 8);
+        }
+        self.bit = Some(bit + 1);
+    }
+    fn receive_packet(&mut self) {
+        //The first packet of a command gives the number of packets in its low bits
+        if self.command.is_empty() && self.packet[0] & 7 == 0 {
+            return;
+        }
+        self.command.extend(self.packet);
+        if self.command.len() == (self.command[0] & 7) as usize * PACKET_SIZE {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, data : &[u8]) {
+        match data[0] >> 3 {
+            PAL01 => self.set_palettes(0, 1, data),
+            PAL23 => self.set_palettes(2, 3, data),
+            PAL03 => self.set_palettes(0, 3, data),
+            PAL12 => self.set_palettes(1, 2, data),
+            ATTR_BLK => self.attribute_blocks(data),
+            ATTR_LIN => self.attribute_lines(data),
+            ATTR_DIV => self.attribute_divide(data),
+            ATTR_CHR => self.attribute_cells(data),
+            PAL_SET => {
+                for (palette, number) in self.palettes.iter_mut().zip(data[1..9].chunks_exact(2)) {
+                    let start = (u16::from_le_bytes([number[0], number[1]]) as usize & 0x1FF) * 8;
+                    *palette = colors(&self.system_palettes[start..start + 8]);
+                }
+                self.set_attribute_file(data[9]);
+            },
+            ATTR_SET => self.set_attribute_file(data[1] | 0x80),
+            PAL_TRN => self.transfer = Some(Transfer::SystemPalettes),
+            CHR_TRN => self.transfer = Some(Transfer::BorderTiles(data[1] & 1 != 0)),
+            PCT_TRN => self.transfer = Some(Transfer::BorderMap),
+            ATTR_TRN => self.transfer = Some(Transfer::AttributeFiles),
+            MASK_EN => self.mask = match data[1] & 3 {
+                1 => Mask::Freeze,
+                2 => Mask::Black,
+                3 => Mask::Color0,
+                _ => Mask::None,
+            },
+            MLT_REQ => {
+                self.players = match data[1] & 3 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            },
+            //Sound, the SNES side and the rest don't show up on the screen
+            _ => (),
+        }
+    }
+    /// PAL01 and friends: colour 0 for every palette, then three colours each for `first` and `second`
+    fn set_palettes(&mut self, first : usize, second : usize, data : &[u8]) {
+        let color : [u16; 7] = colors(&data[1..15]);
+        for palette in &mut self.palettes {
+            palette[0] = color[0];
+        }
+        self.palettes[first][1..].copy_from_slice(&color[1..4]);
+        self.palettes[second][1..].copy_from_slice(&color[4..7]);
+    }
+    /// Use attribute file `control & 0x3F` if bit 7 is set, and lift the mask if bit 6 is
+    fn set_attribute_file(&mut self, control : u8) {
+        let file = (control & 0x3F) as usize;
+        if control & 0x80 != 0 && file < ATTRIBUTE_FILES {
+            let start = file * ATTRIBUTE_FILE_SIZE;
+            let file = &self.attribute_files[start..start + ATTRIBUTE_FILE_SIZE];
+            for (cell, palette) in self.attributes.iter_mut().enumerate() {
+                *palette = (file[cell / 4] >> (6 - cell % 4 * 2)) & 3;
+            }
+        }
+        if control & 0x40 != 0 {
+            self.mask = Mask::None;
+        }
+    }
+    fn attribute_blocks(&mut self, data : &[u8]) {
+        let count = data[1] as usize;
+        for block in data[2..].chunks_exact(6).take(count) {
+            let (control, palettes) = (block[0] & 7, block[1]);
+            let (left, top, right, bottom) = (block[2] as usize, block[3] as usize, block[4] as usize, block[5] as usize);
+            let inside = palettes & 3;
+            let outside = (palettes >> 4) & 3;
+            //With only the inside or only the outside changing, the border goes along with it
+            let border = match control {
+                1 => Some(inside),
+                4 => Some(outside),
+                _ if control & 2 != 0 => Some((palettes >> 2) & 3),
+                _ => None,
+            };
+            for y in 0..CELLS_HIGH {
+                for x in 0..CELLS_WIDE {
+                    let within = (left..=right).contains(&x) && (top..=bottom).contains(&y);
+                    let on_edge = within && (x == left || x == right || y == top || y == bottom);
+                    let palette = if on_edge {
+                        border
+                    } else if within {
+                        (control & 1 != 0).then_some(inside)
+                    } else {
+                        (control & 4 != 0).then_some(outside)
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * CELLS_WIDE + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+    fn attribute_lines(&mut self, data : &[u8]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let (number, palette) = ((line & 0x1F) as usize, (line >> 5) & 3);
+            //Bit 7 set for a row, clear for a column
+            if line & 0x80 != 0 {
+                if number < CELLS_HIGH {
+                    self.attributes[number * CELLS_WIDE..(number + 1) * CELLS_WIDE].fill(palette);
+                }
+            } else if number < CELLS_WIDE {
+                for y in 0..CELLS_HIGH {
+                    self.attributes[y * CELLS_WIDE + number] = palette;
+                }
+            }
+        }
+    }
+    fn attribute_divide(&mut self, data : &[u8]) {
+        let (control, split) = (data[1], data[2] as usize);
+        let (after, before, on) = (control & 3, (control >> 2) & 3, (control >> 4) & 3);
+        //Bit 6 set splits into above and below, clear into left and right
+        let rows = control & 0x40 != 0;
+        for y in 0..CELLS_HIGH {
+            for x in 0..CELLS_WIDE {
+                let position = if rows { y } else { x };
+                self.attributes[y * CELLS_WIDE + x] = match position.cmp(&split) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+    fn attribute_cells(&mut self, data : &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(CELLS_WIDE * CELLS_HIGH);
+        //Bit 0 set goes top to bottom, otherwise left to right
+        let vertical = data[5] & 1 != 0;
+        let palettes = data[6..].iter().flat_map(|&byte| [6, 4, 2, 0].map(|shift| (byte >> shift) & 3));
+        for palette in palettes.take(count) {
+            if x >= CELLS_WIDE || y >= CELLS_HIGH {
+                break;
+            }
+            self.attributes[y * CELLS_WIDE + x] = palette;
+            if vertical {
+                y += 1;
+                if y == CELLS_HIGH {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_WIDE {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    /// Called as each frame finishes with the shades the Game Boy drew. Picks up a pending VRAM transfer and
+    /// redraws the output.
+    pub fn frame(&mut self, shades : &[u8]) {
+        if let Some(transfer) = self.transfer.take() {
+            let data = screen_data(shades);
+            match transfer {
+                Transfer::SystemPalettes => self.system_palettes.copy_from_slice(&data),
+                Transfer::BorderTiles(upper) => {
+                    let start = if upper { TRANSFER_SIZE } else { 0 };
+                    self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(&data);
+                },
+                Transfer::BorderMap => {
+                    self.border_map.copy_from_slice(&data[..0x800]);
+                    for (palette, data) in self.border_palettes.iter_mut().zip(data[0x800..0x880].chunks_exact(32)) {
+                        *palette = colors(data);
+                    }
+                },
+                Transfer::AttributeFiles => self.attribute_files.copy_from_slice(&data),
+            }
+        }
+        if self.mask != Mask::Freeze {
+            self.draw(shades);
+        }
+    }
+    fn draw(&mut self, shades : &[u8]) {
+        let backdrop = self.palettes[0][0];
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let (screen_x, screen_y) = (x.wrapping_sub(SCREEN_X), y.wrapping_sub(SCREEN_Y));
+                let on_screen = screen_x < ppu::SCREEN_WIDTH && screen_y < ppu::SCREEN_HEIGHT;
+                let color = match self.mask {
+                    _ if !on_screen => backdrop,
+                    Mask::Black => 0x0000,
+                    Mask::Color0 => backdrop,
+                    Mask::None | Mask::Freeze => {
+                        //Colour 0 is shared by all the palettes
+                        let shade = shades[screen_y * ppu::SCREEN_WIDTH + screen_x] as usize;
+                        let palette = self.cell_palette(screen_x / 8, screen_y / 8) as usize;
+                        if shade == 0 { backdrop } else { self.palettes[palette][shade] }
+                    },
+                };
+                //The border goes on top, with the screen showing through colour 0
+                self.framebuffer[y * SCREEN_WIDTH + x] = self.border_pixel(x, y).unwrap_or(color);
+            }
+        }
+    }
+    fn border_pixel(&self, x : usize, y : usize) -> Option<u16> {
+        let entry = (y / 8 * 32 + x / 8) * 2;
+        let entry = u16::from_le_bytes([self.border_map[entry], self.border_map[entry + 1]]);
+        let tile = (entry & 0xFF) as usize * 32;
+        let palette = ((entry >> 10) & 3) as usize;
+        let column = if entry & 0x4000 != 0 { 7 - x % 8 } else { x % 8 };
+        let row = if entry & 0x8000 != 0 { 7 - y % 8 } else { y % 8 };
+        //Four bitplanes, the first two interleaved by row and then the second two
+        let planes = [
+            self.border_tiles[tile + row * 2],
+            self.border_tiles[tile + row * 2 + 1],
+            self.border_tiles[tile + 16 + row * 2],
+            self.border_tiles[tile + 16 + row * 2 + 1],
+        ];
+        let color = planes.iter().enumerate()
+            .fold(0, |color, (plane, &bits)| color | ((bits >> (7 - column)) & 1) << plane);
+        (color != 0).then(|| self.border_palettes[palette][color as usize])
+    }
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Sgb::new(true)
+    }
+}
+
+/// Little endian 15-bit colours
+fn colors<const N : usize>(data : &[u8]) -> [u16; N] {
+    std::array::from_fn(|index| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]) & 0x7FFF)
+}
+
+/// Read a VRAM transfer back off the screen, which shows its first 256 tiles in order with the identity palette
+fn screen_data(shades : &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(TRANSFER_SIZE);
+    for tile in 0..TRANSFER_SIZE / 16 {
+        let (tile_x, tile_y) = (tile % CELLS_WIDE * 8, tile / CELLS_WIDE * 8);
+        for row in 0..8 {
+            let pixels = &shades[(tile_y + row) * ppu::SCREEN_WIDTH + tile_x..][..8];
+            let (low, high) = pixels.iter().fold((0, 0), |(low, high), &shade| {
+                (low << 1 | (shade & 1), high << 1 | (shade >> 1))
+            });
+            data.push(low);
+            data.push(high);
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(sgb : &mut Sgb, packet : &[u8; PACKET_SIZE]) {
+        sgb.write_p1(0x00);
+        sgb.write_p1(SELECT_MASK);
+        for index in 0..PACKET_BITS {
+            let one = packet[index / 8] >> (index % 8) & 1 != 0;
+            sgb.write_p1(if one { PULSE_ONE } else { PULSE_ZERO });
+            sgb.write_p1(SELECT_MASK);
+        }
+        sgb.write_p1(PULSE_ZERO);
+        sgb.write_p1(SELECT_MASK);
+    }
+
+    #[test]
+    fn packets_set_palettes_and_attributes() {
+        let mut sgb = Sgb::new(true);
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = PAL01 << 3 | 1;
+        packet[1..15].copy_from_slice(&[0x11, 0x11, 1, 0, 2, 0, 3, 0, 4, 0, 5, 0, 6, 0]);
+        send(&mut sgb, &packet);
+        assert_eq!(sgb.palettes[0], [0x1111, 1, 2, 3]);
+        assert_eq!(sgb.palettes[1], [0x1111, 4, 5, 6]);
+        assert_eq!(sgb.palettes[2][0], 0x1111);
+
+        //Palette 1 inside a block, 2 on its border and 3 outside
+        let mut packet = [0; PACKET_SIZE];
+        packet[..8].copy_from_slice(&[ATTR_BLK << 3 | 1, 1, 0x07, 0b11_10_01, 2, 2, 5, 6]);
+        send(&mut sgb, &packet);
+        assert_eq!(sgb.cell_palette(3, 3), 1);
+        assert_eq!(sgb.cell_palette(2, 4), 2);
+        assert_eq!(sgb.cell_palette(0, 0), 3);
+
+        //Rows below 9 get palette 1
+        let mut packet = [0; PACKET_SIZE];
+        packet[..3].copy_from_slice(&[ATTR_DIV << 3 | 1, 0x40 | 0b00_00_01, 9]);
+        send(&mut sgb, &packet);
+        assert_eq!(sgb.cell_palette(0, 8), 0);
+        assert_eq!(sgb.cell_palette(0, 10), 1);
+
+        //The top left pixel of cell row 10, and the one next to it
+        let mut shades = vec![0; ppu::SCREEN_WIDTH * ppu::SCREEN_HEIGHT];
+        shades[80 * ppu::SCREEN_WIDTH] = 3;
+        sgb.frame(&shades);
+        assert_eq!(sgb.framebuffer()[(SCREEN_Y + 80) * SCREEN_WIDTH + SCREEN_X], 6);
+        assert_eq!(sgb.framebuffer()[(SCREEN_Y + 80) * SCREEN_WIDTH + SCREEN_X + 1], 0x1111);
+        assert_eq!(sgb.framebuffer()[0], 0x1111);
+
+        let mut packet = [0; PACKET_SIZE];
+        packet[..2].copy_from_slice(&[MASK_EN << 3 | 1, 2]);
+        send(&mut sgb, &packet);
+        sgb.frame(&shades);
+        assert_eq!(sgb.mask(), Mask::Black);
+        assert_eq!(sgb.framebuffer()[(SCREEN_Y + 80) * SCREEN_WIDTH + SCREEN_X], 0);
+    }
+
+    /// What the screen shows while a VRAM transfer of `data` is set up
+    fn transfer_screen(data : &[u8]) -> Vec<u8> {
+        let mut shades = vec![0; ppu::SCREEN_WIDTH * ppu::SCREEN_HEIGHT];
+        for (index, pair) in data.chunks_exact(2).enumerate() {
+            let (tile, row) = (index / 8, index % 8);
+            let start = (tile / CELLS_WIDE * 8 + row) * ppu::SCREEN_WIDTH + tile % CELLS_WIDE * 8;
+            for (x, shade) in shades[start..start + 8].iter_mut().enumerate() {
+                *shade = (pair[0] >> (7 - x) & 1) | (pair[1] >> (7 - x) & 1) << 1;
+            }
+        }
+        shades
+    }
+
+    #[test]
+    fn border_comes_from_the_screen() {
+        let mut sgb = Sgb::new(true);
+        //Border tile 0 is colour 1 all over
+        let mut data = [0; TRANSFER_SIZE];
+        for row in 0..8 {
+            data[row * 2] = 0xFF;
+        }
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = CHR_TRN << 3 | 1;
+        send(&mut sgb, &packet);
+        sgb.frame(&transfer_screen(&data));
+
+        //A map of tile 0 in the first border palette, where colour 1 is blue
+        let mut data = [0; TRANSFER_SIZE];
+        data[0x802..0x804].copy_from_slice(&0x7C00_u16.to_le_bytes());
+        packet[0] = PCT_TRN << 3 | 1;
+        send(&mut sgb, &packet);
+        sgb.frame(&transfer_screen(&data));
+        assert_eq!(sgb.framebuffer()[0], 0x7C00);
+        assert_eq!(sgb.framebuffer()[SCREEN_WIDTH * SCREEN_HEIGHT - 1], 0x7C00);
+    }
+
+    #[test]
+    fn multiplayer_reads_the_player() {
+        let mut sgb = Sgb::new(true);
+        let mut packet = [0; PACKET_SIZE];
+        packet[..2].copy_from_slice(&[MLT_REQ << 3 | 1, 1]);
+        send(&mut sgb, &packet);
+        assert_eq!(sgb.read_p1(0xFF) & 0x0F, 0x0F);
+        sgb.write_p1(PULSE_ONE);
+        sgb.write_p1(SELECT_MASK);
+        assert_eq!(sgb.read_p1(0xFF) & 0x0F, 0x0E);
+        //The second player has no controller
+        assert_eq!(sgb.read_p1(0xE0 | PULSE_ZERO) & 0x0F, 0x0F);
+        sgb.write_p1(PULSE_ONE);
+        sgb.write_p1(SELECT_MASK);
+        assert_eq!(sgb.read_p1(0xFF) & 0x0F, 0x0F);
+    }
+
+    #[test]
+    fn carts_without_the_flag_are_ignored() {
+        let mut sgb = Sgb::new(false);
+        let mut packet = [0; PACKET_SIZE];
+        packet[0] = PAL01 << 3 | 1;
+        packet[1..3].copy_from_slice(&[0x11, 0x11]);
+        send(&mut sgb, &packet);
+        assert_eq!(sgb.palettes[0], DEFAULT_PALETTE);
+
+        let mut packet = [0; PACKET_SIZE];
+        packet[..2].copy_from_slice(&[MLT_REQ << 3 | 1, 1]);
+        send(&mut sgb, &packet);
+        sgb.write_p1(PULSE_ONE);
+        sgb.write_p1(SELECT_MASK);
+        assert_eq!(sgb.read_p1(0xFF) & 0x0F, 0x0F);
+    }
+}
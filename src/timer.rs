@@ -0,0 +1,209 @@
+use crate::interrupts::{Interrupt, InterruptController};
+
+pub const DIV_ADDRESS : u16 = 0xFF04;
+pub const TIMA_ADDRESS : u16 = 0xFF05;
+pub const TMA_ADDRESS : u16 = 0xFF06;
+pub const TAC_ADDRESS : u16 = 0xFF07;
+
+const TAC_ENABLE : u8 = 0x04;
+
+/// DIV, TIMA, TMA and TAC, all driven by one 16 bit counter ticking every clock
+#[derive(Default)]
+pub struct Timer {
+    ///DIV is the upper byte
+    counter : u16,
+    tima : u8,
+    tma : u8,
+    tac : u8,
+    ///TIMA overflowed last M-cycle and reads 0 until it's reloaded
+    overflowed : bool,
+    ///TIMA was reloaded from TMA this M-cycle, writes to it are ignored
+    reloading : bool,
+}
+
+impl Timer {
+    /// Counter bit whose falling edge clocks TIMA, for each TAC frequency
+    fn selected_bit(&self) -> u16 {
+        match self.tac & 0b11 {
+            0b00 => 1 << 9,
+            0b01 => 1 << 3,
+            0b10 => 1 << 5,
+            _ => 1 << 7,
+        }
+    }
+    fn signal(&self) -> bool {
+        self.tac & TAC_ENABLE != 0 && self.counter & self.selected_bit() != 0
+    }
+    fn increment(&mut self) {
+        let (tima, overflowed) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        self.overflowed |= overflowed;
+    }
+    /// Update the counter, bumping TIMA if its input signal fell
+    fn set_counter(&mut self, counter : u16) {
+        let before = self.signal();
+        self.counter = counter;
+        if before && !self.signal() {
+            self.increment();
+        }
+    }
+
+    /// Advance by one M-cycle
+    pub fn step(&mut self, interrupts : &mut InterruptController) {
+        self.reloading = false;
+        if self.overflowed {
+            self.overflowed = false;
+            self.reloading = true;
+            self.tima = self.tma;
+            interrupts.request(Interrupt::Timer);
+        }
+        self.set_counter(self.counter.wrapping_add(4));
+    }
+
+    pub fn read(&self, addr : u16) -> u8 {
+        match addr {
+            DIV_ADDRESS => (self.counter >> 8) as u8,
+            TIMA_ADDRESS => self.tima,
+            TMA_ADDRESS => self.tma,
+            TAC_ADDRESS => 0xF8 | self.tac,
+            _ => 0xFF,
+        }
+    }
+    pub fn write(&mut self, addr : u16, data : u8) {
+        match addr {
+            //Any write clears the whole counter, which can look like a falling edge
+            DIV_ADDRESS => self.set_counter(0),
+            //Writing during the cycle after an overflow cancels the reload
+            TIMA_ADDRESS if !self.reloading => {
+                self.tima = data;
+                self.overflowed = false;
+            },
+            TMA_ADDRESS => {
+                self.tma = data;
+                if self.reloading {
+                    self.tima = data;
+                }
+            },
+            TAC_ADDRESS => {
+                //Disabling the timer or switching bits can also drop the signal
+                let before = self.signal();
+                self.tac = data & 0b111;
+                if before && !self.signal() {
+                    self.increment();
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow_reloads_after_a_cycle() {
+        let mut timer = Timer::default();
+        let mut interrupts = InterruptController::default();
+        timer.write(TMA_ADDRESS, 0x80);
+        timer.write(TIMA_ADDRESS, 0xFF);
+        //Fastest rate, every 4 M-cycles
+        timer.write(TAC_ADDRESS, TAC_ENABLE | 0b01);
+
+        for _ in 0..4 {
+            timer.step(&mut interrupts);
+        }
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x00);
+        assert_eq!(interrupts.requested(), 0);
+
+        timer.step(&mut interrupts);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x80);
+        assert_eq!(interrupts.requested(), Interrupt::Timer.mask());
+    }
+
+    #[test]
+    fn div_reset_is_a_falling_edge() {
+        let mut timer = Timer::default();
+        let mut interrupts = InterruptController::default();
+        timer.write(TAC_ADDRESS, TAC_ENABLE | 0b01);
+
+        //Bit 3 is set after two M-cycles, resetting DIV then drops it
+        timer.step(&mut interrupts);
+        timer.step(&mut interrupts);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0);
+        timer.write(DIV_ADDRESS, 0x12);
+        assert_eq!(timer.read(TIMA_ADDRESS), 1);
+        assert_eq!(timer.read(DIV_ADDRESS), 0);
+    }
+
+    /// A timer at the fastest rate with TIMA about to overflow into a reload of 0x80
+    fn overflowing_timer(interrupts : &mut InterruptController) -> Timer {
+        let mut timer = Timer::default();
+        timer.write(TMA_ADDRESS, 0x80);
+        timer.write(TIMA_ADDRESS, 0xFF);
+        timer.write(TAC_ADDRESS, TAC_ENABLE | 0b01);
+        for _ in 0..4 {
+            timer.step(interrupts);
+        }
+        timer
+    }
+
+    #[test]
+    fn tac_writes_can_increment() {
+        let mut interrupts = InterruptController::default();
+        let mut timer = Timer::default();
+        timer.write(TAC_ADDRESS, TAC_ENABLE | 0b01);
+        timer.step(&mut interrupts);
+        timer.step(&mut interrupts);
+
+        //Disabling with the selected bit high is a falling edge
+        timer.write(TAC_ADDRESS, 0b01);
+        assert_eq!(timer.read(TIMA_ADDRESS), 1);
+        //Enabling never is, nor is disabling with the bit low
+        timer.write(TAC_ADDRESS, TAC_ENABLE);
+        timer.write(TAC_ADDRESS, 0b00);
+        assert_eq!(timer.read(TIMA_ADDRESS), 1);
+        //Switching from a high bit to a low one is too
+        timer.write(TAC_ADDRESS, TAC_ENABLE | 0b01);
+        timer.write(TAC_ADDRESS, TAC_ENABLE);
+        assert_eq!(timer.read(TIMA_ADDRESS), 2);
+    }
+
+    #[test]
+    fn tima_writes_around_the_reload() {
+        //Writing in the cycle TIMA reads 0 replaces the reload and its interrupt
+        let mut interrupts = InterruptController::default();
+        let mut timer = overflowing_timer(&mut interrupts);
+        timer.write(TIMA_ADDRESS, 0x12);
+        timer.step(&mut interrupts);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x12);
+        assert_eq!(interrupts.requested(), 0);
+
+        //Writing in the reload cycle itself is ignored
+        let mut interrupts = InterruptController::default();
+        let mut timer = overflowing_timer(&mut interrupts);
+        timer.step(&mut interrupts);
+        timer.write(TIMA_ADDRESS, 0x12);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x80);
+        assert_eq!(interrupts.requested(), Interrupt::Timer.mask());
+        //But sticks a cycle later
+        timer.step(&mut interrupts);
+        timer.write(TIMA_ADDRESS, 0x12);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x12);
+    }
+
+    #[test]
+    fn tma_writes_during_the_reload() {
+        //A TMA write in the reload cycle reaches TIMA too
+        let mut interrupts = InterruptController::default();
+        let mut timer = overflowing_timer(&mut interrupts);
+        timer.step(&mut interrupts);
+        timer.write(TMA_ADDRESS, 0x55);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x55);
+
+        //Any other time it only changes the next reload
+        timer.step(&mut interrupts);
+        timer.write(TMA_ADDRESS, 0x66);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x55);
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const CHANNELS : u16 = 2;
+const BITS_PER_SAMPLE : u16 = 16;
+/// Size of everything after the RIFF chunk header, excluding the sample data
+const HEADER_SIZE : u32 = 36;
+
+/// Write interleaved stereo samples as a 16 bit PCM WAV file
+pub fn write(path : &Path, sample_rate : u32, samples : &[i16]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let data_size = (samples.len() * 2) as u32;
+
+    file.write_all(b"RIFF")?;
+    file.write_all(&(HEADER_SIZE + data_size).to_le_bytes())?;
+    file.write_all(b"WAVE")?;
+
+    file.write_all(b"fmt ")?;
+    file.write_all(&16_u32.to_le_bytes())?;
+    //Uncompressed PCM
+    file.write_all(&1_u16.to_le_bytes())?;
+    file.write_all(&CHANNELS.to_le_bytes())?;
+    file.write_all(&sample_rate.to_le_bytes())?;
+    file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    file.write_all(&block_align.to_le_bytes())?;
+    file.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+    file.write_all(b"data")?;
+    file.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        file.write_all(&sample.to_le_bytes())?;
+    }
+    file.flush()
+}
+
+/// Path of a stem next to `path`, `music.wav` becoming `music_noise.wav`
+pub fn stem_path(path : &Path, name : &str) -> PathBuf {
+    let stem = path.file_stem().map_or_else(Default::default, |stem| stem.to_string_lossy());
+    path.with_file_name(format!("{}_{}.wav", stem, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_pcm_headers() {
+        let path = std::env::temp_dir().join(format!("fuzz_gb_wav_{}.wav", std::process::id()));
+        write(&path, 48_000, &[1, -1, 0x1234, -0x1234]).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let u16_at = |offset : usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at = |offset : usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32_at(4), 36 + 8);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!((u32_at(16), u16_at(20), u16_at(22)), (16, 1, 2));
+        //Sample rate, bytes per second, block align and bits per sample
+        assert_eq!((u32_at(24), u32_at(28), u16_at(32), u16_at(34)), (48_000, 192_000, 4, 16));
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(u32_at(40), 8);
+        assert_eq!(&data[44..], [0x01, 0x00, 0xFF, 0xFF, 0x34, 0x12, 0xCC, 0xED]);
+    }
+
+    #[test]
+    fn stem_paths_sit_next_to_the_mix() {
+        assert_eq!(stem_path(Path::new("out.wav"), "noise"), Path::new("out_noise.wav"));
+        assert_eq!(stem_path(Path::new("music/song.wav"), "wave"), Path::new("music/song_wave.wav"));
+    }
+}